@@ -0,0 +1,67 @@
+//! The code page of the CC: Tweaked font, shared by the server and everything that shows what it
+//! sends to monitors so they all draw the same characters.
+
+/// Characters 0x00 to 0x1f of the CC font. Tab, newline and carriage return are blank.
+const CCTWEAKED_CONTROL_CHARS: [char; 32] = [
+    '\0', '☺', '☻', '♥', '♦', '♣', '♠', '●', '○', '\t', '\n', '♂', '♀', '\r', '♪', '♬',
+    // 0x14 is the same pilcrow as 0xb6, which ¶ maps to as that's where Latin-1 has it
+    '▶', '◀', '↕', '‼', '⁋', '░', '▬', '↨', '⬆', '⬇', '➡', '⬅', '∟', '⧺', '▲', '▼',
+];
+
+/// The character CC shows for `byte`. No two bytes share a character, so the server can turn text
+/// back into bytes with the same table.
+///
+///  |0 1 2 3 4 5 6 7 8 9 A B C D E F
+/// -+--------------------------------
+/// 0|  ☺ ☻ ♥ ♦ ♣ ♠ ● ○     ♂ ♀   ♪ ♬
+/// 1|▶ ◀ ↕ ‼ ⁋ ░ ▬ ↨ ⬆ ⬇ ➡ ⬅ ∟ ⧺ ▲ ▼
+/// 2-7 ascii, with ▒ at 0x7f
+/// 8|  🬀 🬁 🬂 🬃 🬄 🬅 🬆 🬇 🬈 🬉 🬊 🬋 🬌 🬍 🬎
+/// 9|🬏 🬐 🬑 🬒 🬓 ▌ 🬔 🬕 🬖 🬗 🬘 🬙 🬚 🬛 🬜 🬝
+/// A-F Latin-1
+pub fn cctweaked_char(byte: u8) -> char {
+    match byte {
+        0x00..=0x1f => CCTWEAKED_CONTROL_CHARS[byte as usize],
+        0x7f => '▒',
+        // Unicode has no empty sextant, the blank braille cell looks the same
+        0x80 => '\u{2800}',
+        0x81..=0x9f => sextant(byte - 0x80),
+        // the rest is ascii and Latin-1
+        _ => char::from(byte),
+    }
+}
+
+/// The teletext character with the sextants in `bits` set, top left being the lowest bit. CC
+/// never sets the bottom right one, it swaps the colors instead.
+fn sextant(bits: u8) -> char {
+    // Unicode leaves out the sextants that already exist as half blocks
+    const LEFT_HALF: u8 = 0b10101;
+    match bits {
+        LEFT_HALF => '▌',
+        _ => {
+            let skipped = if bits > LEFT_HALF { 2 } else { 1 };
+            char::from_u32(0x1fb00 + bits as u32 - skipped).unwrap_or('?')
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cctweaked_char() {
+        let chars: std::collections::HashSet<char> = (0..=u8::MAX).map(cctweaked_char).collect();
+        assert_eq!(chars.len(), 256);
+        assert_eq!(cctweaked_char(b'A'), 'A');
+        assert_eq!(cctweaked_char(0x03), '♥');
+        assert_eq!(cctweaked_char(0x14), '⁋');
+        assert_eq!(cctweaked_char(0xb6), '¶');
+        assert_eq!(cctweaked_char(0x80), '\u{2800}');
+        assert_eq!(cctweaked_char(0x81), '🬀');
+        assert_eq!(cctweaked_char(0x95), '▌');
+        assert_eq!(cctweaked_char(0x96), '🬔');
+        assert_eq!(cctweaked_char(0x9f), '🬝');
+        assert_eq!(cctweaked_char(0xe9), 'é');
+    }
+}
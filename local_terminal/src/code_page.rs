@@ -0,0 +1,52 @@
+use cc_code_page::cctweaked_char;
+use crossterm::style::Color;
+
+/// Turns a byte written to a CC monitor back into the character it shows
+pub fn cc_byte_to_char(byte: u8) -> char {
+    match cctweaked_char(byte) {
+        // tab, newline and carriage return are blank, and would move the cursor if printed
+        c if c.is_control() => ' ',
+        c => c,
+    }
+}
+
+/// CC's default palette, by the color names the server sends
+pub fn cc_color(name: &str) -> Option<Color> {
+    let (r, g, b) = match name {
+        "White" => (0xf0, 0xf0, 0xf0),
+        "Orange" => (0xf2, 0xb2, 0x33),
+        "Magenta" => (0xe5, 0x7f, 0xd8),
+        "LightBlue" => (0x99, 0xb2, 0xf2),
+        "Yellow" => (0xde, 0xde, 0x6c),
+        "Lime" => (0x7f, 0xcc, 0x19),
+        "Pink" => (0xf2, 0xb2, 0xcc),
+        "Gray" => (0x4c, 0x4c, 0x4c),
+        "LightGray" => (0x99, 0x99, 0x99),
+        "Cyan" => (0x4c, 0x99, 0xb2),
+        "Purple" => (0xb2, 0x66, 0xe5),
+        "Blue" => (0x33, 0x66, 0xcc),
+        "Brown" => (0x7f, 0x66, 0x4c),
+        "Green" => (0x57, 0xa6, 0x4e),
+        "Red" => (0xcc, 0x4c, 0x4c),
+        "Black" => (0x11, 0x11, 0x11),
+        _ => return None,
+    };
+    Some(Color::Rgb { r, g, b })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cc_byte_to_char() {
+        assert_eq!(cc_byte_to_char(b'A'), 'A');
+        assert_eq!(cc_byte_to_char(0x0a), ' ');
+        assert_eq!(cc_byte_to_char(0x14), '⁋');
+        assert_eq!(cc_byte_to_char(0x80), '\u{2800}');
+        // the same table the server translates text with
+        assert!((0x20..=u8::MAX).all(|byte| cc_byte_to_char(byte) == cctweaked_char(byte)));
+        assert_eq!(cc_color("LightBlue"), Some(Color::Rgb { r: 0x99, g: 0xb2, b: 0xf2 }));
+        assert_eq!(cc_color("Beige"), None);
+    }
+}
@@ -1,29 +1,226 @@
-use std::thread::sleep;
-use std::time::Duration;
-use ratatui::{DefaultTerminal, Frame, Terminal};
-use ratatui::backend::CrosstermBackend;
-
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let terminal = Terminal::new(CrosstermBackend::new(std::io::stdout()))?;
-    let result = run(terminal);
-    ratatui::restore();
-    result
+mod code_page;
+
+use std::io::Write;
+use crossterm::cursor::{Hide, MoveTo, Show};
+use crossterm::event::{Event, KeyCode, KeyEventKind, KeyModifiers};
+use crossterm::style::{Print, SetBackgroundColor, SetForegroundColor};
+use crossterm::terminal::{Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen};
+use crossterm::{execute, queue, terminal};
+use futures::{SinkExt, StreamExt};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio_tungstenite::tungstenite::Message;
+use crate::code_page::{cc_byte_to_char, cc_color};
+
+const DEFAULT_URL: &str = "ws://127.0.0.1:3000/ws/monitor";
+/// matches PROTOCOL_VERSION in scan_items.lua
+const PROTOCOL_VERSION: u32 = 1;
+const USAGE: &str = "usage: local_terminal [--url ws://host:port/ws/monitor] [--id computer_id] [--name common_name] [--view view] [--token token]";
+
+/// Who we pretend to be when registering with the server
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Settings {
+    url: String,
+    computer_id: i64,
+    common_name: String,
+    view: Option<String>,
+    /// issued by the server's /api/admin/tokens, only needed once tokens are in use
+    token: Option<String>,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Settings, String> {
+    let mut settings = Settings {
+        url: DEFAULT_URL.to_string(),
+        computer_id: 9001,
+        common_name: String::from("LocalTerminal"),
+        view: None,
+        token: None,
+    };
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--url" => settings.url = value()?,
+            "--id" => settings.computer_id = value()?.parse().map_err(|e| format!("bad computer id: {}", e))?,
+            "--name" => settings.common_name = value()?,
+            "--view" => settings.view = Some(value()?),
+            "--token" => settings.token = Some(value()?),
+            other => return Err(format!("unknown argument {}", other)),
+        }
+    }
+    Ok(settings)
+}
+
+/// The parts of the server's `CCTweakedMonitorBackendEvent` a terminal can act on
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+enum BackendEvent {
+    HideCursor,
+    ShowCursor,
+    ClearLine,
+    ClearScreen,
+    SetCursorPosition {
+        x: u16,
+        y: u16,
+    },
+    SetTextColor(String),
+    SetBackgroundColor(String),
+    ServerCommand(Value),
+    PeripheralCall(Value),
+}
+
+/// Draws the event, returns the message to send back to the server if it needs an answer
+fn apply(out: &mut impl Write, event: BackendEvent) -> std::io::Result<Option<Value>> {
+    match event {
+        BackendEvent::HideCursor => queue!(out, Hide)?,
+        BackendEvent::ShowCursor => queue!(out, Show)?,
+        BackendEvent::ClearLine => queue!(out, Clear(ClearType::CurrentLine))?,
+        BackendEvent::ClearScreen => queue!(out, Clear(ClearType::All))?,
+        BackendEvent::SetCursorPosition { x, y } => queue!(out, MoveTo(x, y))?,
+        BackendEvent::SetTextColor(color) => {
+            if let Some(color) = cc_color(&color) {
+                queue!(out, SetForegroundColor(color))?;
+            }
+        }
+        BackendEvent::SetBackgroundColor(color) => {
+            if let Some(color) = cc_color(&color) {
+                queue!(out, SetBackgroundColor(color))?;
+            }
+        }
+        BackendEvent::ServerCommand(command) => {
+            return Ok(Some(json!({"command_ack": {
+                "id": command["id"],
+                "success": false,
+                "error": "the local terminal has no redstone",
+            }})));
+        }
+        BackendEvent::PeripheralCall(call) => {
+            return Ok(Some(json!({"rpc_response": {
+                "id": call["id"],
+                "results": [],
+                "error": {"no_such_peripheral": call["peripheral"]},
+            }})));
+        }
+    }
+    Ok(None)
+}
+
+/// Reads terminal events on their own thread since crossterm's reads block
+fn spawn_input_reader(sender: UnboundedSender<Event>) {
+    std::thread::spawn(move || {
+        while let Ok(event) = crossterm::event::read() {
+            if sender.send(event).is_err() {
+                return;
+            }
+        }
+    });
+}
+
+fn is_quit(event: &Event) -> bool {
+    let Event::Key(key) = event else {
+        return false;
+    };
+    key.kind == KeyEventKind::Press
+        && (key.code == KeyCode::Char('q') || (key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL)))
 }
 
-fn run(mut terminal: DefaultTerminal) -> Result<(), Box<dyn std::error::Error>> {
-    let mut i = 0;
-    loop {
-        sleep(Duration::from_millis(1000));
-        terminal.draw(|frame| render(frame, i))?;
-        i += 1;
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let settings = parse_args(std::env::args().skip(1)).map_err(|e| format!("{}\n{}", e, USAGE))?;
+    let (socket, _) = tokio_tungstenite::connect_async(settings.url.as_str()).await?;
+    let (mut socket_sender, mut socket_receiver) = socket.split();
+
+    let (width, height) = terminal::size()?;
+    let mut register = json!({
+        "size": {"width": width, "height": height},
+        "computer_id": settings.computer_id,
+        "common_name": settings.common_name,
+        "protocol_version": PROTOCOL_VERSION,
+    });
+    if let Some(view) = &settings.view {
+        register["view"] = json!(view);
     }
+    if let Some(token) = &settings.token {
+        register["token"] = json!(token);
+    }
+    socket_sender.send(Message::text(json!({"inventory_register": register}).to_string())).await?;
+
+    terminal::enable_raw_mode()?;
+    let mut stdout = std::io::stdout();
+    execute!(stdout, EnterAlternateScreen, Hide)?;
+    let (input_sender, mut input_receiver) = tokio::sync::mpsc::unbounded_channel();
+    spawn_input_reader(input_sender);
+
+    let result: Result<(), Box<dyn std::error::Error>> = async {
+        loop {
+            tokio::select! {
+                message = socket_receiver.next() => {
+                    let Some(message) = message else {
+                        return Ok(());
+                    };
+                    match message? {
+                        Message::Binary(bytes) => {
+                            queue!(stdout, Print(bytes.iter().map(|b| cc_byte_to_char(*b)).collect::<String>()))?;
+                        }
+                        Message::Text(text) => {
+                            // events we don't know about are skipped, the server may be newer than us
+                            let Ok(event) = serde_json::from_str::<BackendEvent>(&text) else {
+                                continue;
+                            };
+                            if let Some(reply) = apply(&mut stdout, event)? {
+                                socket_sender.send(Message::text(reply.to_string())).await?;
+                            }
+                        }
+                        Message::Close(_) => return Ok(()),
+                        _ => continue,
+                    }
+                    stdout.flush()?;
+                }
+                Some(event) = input_receiver.recv() => {
+                    if is_quit(&event) {
+                        socket_sender.send(Message::Close(None)).await.ok();
+                        return Ok(());
+                    }
+                    if let Event::Resize(width, height) = event {
+                        let resize = json!({"monitor_resize": {"width": width, "height": height}});
+                        socket_sender.send(Message::text(resize.to_string())).await?;
+                    }
+                }
+            }
+        }
+    }.await;
+
+    execute!(stdout, LeaveAlternateScreen, Show)?;
+    terminal::disable_raw_mode()?;
+    result
 }
 
-fn render(frame: &mut Frame, i: i32) {
-    if i % 5 == 0 {
-        frame.render_widget(format!("Woo hoo {i}"), frame.area());
-    } else {
-        frame.render_widget(format!("Hello world {i}"), frame.area());
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_args() {
+        let args = ["--id", "7", "--view", "fleet"].into_iter().map(String::from);
+        let settings = parse_args(args).unwrap();
+        assert_eq!(settings.computer_id, 7);
+        assert_eq!(settings.view.as_deref(), Some("fleet"));
+        assert_eq!(settings.url, DEFAULT_URL);
+        assert!(parse_args(["--id"].into_iter().map(String::from)).is_err());
+        assert!(parse_args(["--colour"].into_iter().map(String::from)).is_err());
+    }
+
+    #[test]
+    fn test_apply() {
+        let event = serde_json::from_str::<BackendEvent>(r#"{"SetCursorPosition":{"x":2,"y":3}}"#).unwrap();
+        let mut out = Vec::new();
+        assert_eq!(apply(&mut out, event).unwrap(), None);
+        assert_eq!(out, b"\x1b[4;3H");
+
+        let event = serde_json::from_str::<BackendEvent>(
+            r#"{"PeripheralCall":{"id":4,"peripheral":"left","method":"list","args":[]}}"#
+        ).unwrap();
+        let reply = apply(&mut Vec::new(), event).unwrap().unwrap();
+        assert_eq!(reply["rpc_response"]["id"], 4);
+        assert_eq!(reply["rpc_response"]["error"]["no_such_peripheral"], "left");
     }
-    
 }
@@ -0,0 +1,71 @@
+require "server_auth"
+
+local expect = require "cc.expect"
+
+-- for computers without a monitor, posts inventory reports straight to the server
+REPORT_URL = "http://127.0.0.1:3000/api/reports"
+-- has to match seconds_per_report in the server config, rates assume reports come this often
+PUBLISH_DATA_TIME = 5
+
+function Main(storage)
+    expect(1, storage, "table")
+    while true do
+        local ok, err = SendReport(storage)
+        if not ok then
+            print("report failed:", err)
+        end
+        os.sleep(PUBLISH_DATA_TIME)
+    end
+end
+
+--[[
+    Posts one report of the storage, returns false and the server's message if it was rejected
+]]--
+function SendReport(storage)
+    expect(1, storage, "table")
+    local inventory_type = "storage"
+    if storage.inventory_type == "input" then
+        inventory_type = {input = {destination = storage.destination}}
+    elseif storage.inventory_type == "output" then
+        inventory_type = {output = {source = storage.source}}
+    end
+    local inventory = {}
+    for slot, item in pairs(storage.list()) do
+        table.insert(inventory, {slot = slot, name = item.name, count = item.count})
+    end
+    local report = textutils.serializeJSON({
+        common_name = storage.common_name,
+        peripheral_name = storage.peripheral_name,
+        computer_id = os.getComputerID(),
+        inventory_type = inventory_type,
+        inventory = inventory,
+    })
+    -- serializeJSON writes an empty table as {}, the server wants a list
+    report = string.gsub(report, "\"inventory\":{}", "\"inventory\":[]")
+    local response, err, error_response = http.post(REPORT_URL, report, ServerHeaders({["Content-Type"] = "application/json"}))
+    if response then
+        response.close()
+        return true
+    end
+    if error_response then
+        local body = textutils.unserializeJSON(error_response.readAll())
+        error_response.close()
+        if body and body.message then
+            return false, body.message
+        end
+    end
+    return false, err
+end
+
+function GetStoragePeripheral(common_name, peripheral_name)
+    expect(1, common_name, "string")
+    expect(2, peripheral_name, "string")
+
+    local storage = peripheral.wrap(peripheral_name)
+    storage.peripheral_name = peripheral_name
+    storage.common_name = common_name
+    storage.inventory_type = "storage"
+    return storage
+end
+
+Main(GetStoragePeripheral("MainStorage", "left"))
@@ -1,6 +1,11 @@
+require "server_auth"
+
 local expect = require "cc.expect"
 
+-- has to match seconds_per_report in the server config, rates assume reports come this often
 PUBLISH_DATA_TIME = 5
+-- bumped whenever the messages sent to or understood from the server change
+PROTOCOL_VERSION = 1
 WEBSOCKET_RECONNECT_TIME = 5
 
 function Main(input_storage, monitor)
@@ -12,7 +17,7 @@ function Main(input_storage, monitor)
     local publish_data_timer_id
     local websocket_reconnect_timer_id
     local ws_handle
-    http.websocketAsync("ws://127.0.0.1:3000/ws/monitor", {}, 2)
+    http.websocketAsync("ws://127.0.0.1:3000/ws/monitor", ServerHeaders(), 2)
     while true do
         local eventData = {os.pullEventRaw()}
         local event = eventData[1]
@@ -21,7 +26,7 @@ function Main(input_storage, monitor)
             SendInventory(ws_handle, input_storage)
             publish_data_timer_id = os.startTimer(PUBLISH_DATA_TIME)
         elseif event == "timer" and eventData[2] == websocket_reconnect_timer_id then
-            http.websocketAsync("ws://127.0.0.1:3000/ws/monitor", {}, 2)
+            http.websocketAsync("ws://127.0.0.1:3000/ws/monitor", ServerHeaders(), 2)
         elseif event == "websocket_failure" then
             print("FAIL websocket", eventData[2], eventData[3])
             if publish_data_timer_id then
@@ -39,7 +44,7 @@ function Main(input_storage, monitor)
             if eventData[4] then
                 HandleBinaryMessage(monitor, eventData[3])
             else
-                HandleTextMessage(monitor, eventData[3])
+                HandleTextMessage(ws_handle, monitor, eventData[3])
             end
         elseif event == "websocket_success" then
             monitor.clear()
@@ -69,7 +74,7 @@ function Main(input_storage, monitor)
     end
 end
 
-function HandleTextMessage(monitor, message)
+function HandleTextMessage(ws_handle, monitor, message)
     local json = textutils.unserializeJSON(message)
     if json == nil then
         print("Bad JSON", message)
@@ -81,10 +86,75 @@ function HandleTextMessage(monitor, message)
         monitor.setCursorPos(x, y)
     elseif json == "HideCursor" then
         monitor.setCursorBlink(false)
+    elseif json["ServerCommand"] then
+        HandleServerCommand(ws_handle, json["ServerCommand"])
+    elseif json["PeripheralCall"] then
+        HandlePeripheralCall(ws_handle, json["PeripheralCall"])
     else
         print("Bad message", message)
     end
 end
+
+--[[
+    Runs a command sent by the server and acknowledges it so the server knows it happened
+    @param ws_handle: The websocket handle
+    @param request: {id = number, command = {command_name = {args}}}
+]]--
+function HandleServerCommand(ws_handle, request)
+    local ok, err = pcall(RunServerCommand, request.command)
+    local ack = {id = request.id, success = ok}
+    if not ok then
+        ack.error = tostring(err)
+    end
+    ws_handle.send(textutils.serializeJSON({command_ack = ack}))
+end
+
+--[[
+    Runs peripheral.call for the server and sends back the results under the same id
+    @param ws_handle: The websocket handle
+    @param request: {id = number, peripheral = string, method = string, args = {...}}
+]]--
+function HandlePeripheralCall(ws_handle, request)
+    local response = {id = request.id}
+    if not peripheral.isPresent(request.peripheral) then
+        response.error = {no_such_peripheral = request.peripheral}
+    else
+        local results = table.pack(pcall(peripheral.call, request.peripheral, request.method, table.unpack(request.args or {})))
+        if results[1] then
+            response.results = {table.unpack(results, 2, results.n)}
+        else
+            response.error = {call_failed = tostring(results[2])}
+        end
+    end
+    local ok, data = pcall(textutils.serializeJSON, {rpc_response = response})
+    if not ok then
+        data = textutils.serializeJSON({rpc_response = {id = request.id, error = {call_failed = "could not serialize results: " .. tostring(data)}}})
+    end
+    ws_handle.send(data)
+end
+
+function RunServerCommand(command)
+    if command["set_redstone_output"] then
+        local args = command["set_redstone_output"]
+        redstone.setOutput(args.side, args.on)
+    elseif command["set_analog_output"] then
+        local args = command["set_analog_output"]
+        redstone.setAnalogOutput(args.side, args.strength)
+    elseif command["set_bundled_output"] then
+        local args = command["set_bundled_output"]
+        redstone.setBundledOutput(args.side, args.colors)
+    elseif command["set_bundled_colors"] then
+        local args = command["set_bundled_colors"]
+        local current = redstone.getBundledOutput(args.side)
+        if args.on then
+            redstone.setBundledOutput(args.side, colors.combine(current, args.colors))
+        else
+            redstone.setBundledOutput(args.side, colors.subtract(current, args.colors))
+        end
+    else
+        error("unknown command " .. textutils.serializeJSON(command))
+    end
+end
 -- binary messages are WRITE messages, since we need to support non-utf8 characters
 function HandleBinaryMessage(monitor, message)
     monitor.write(message)
@@ -114,7 +184,13 @@ function RegisterMonitor(ws_handle, monitor, input_storage)
     local width, height = monitor.getSize()
     local data = "{\"inventory_register\":{\"size\":{\"width\":" .. width .. ",\"height\":" .. height .. "},"
     data = data .. "\"computer_id\":" .. input_storage.computer_id .. ","
-    data = data .. "\"common_name\":\"" .. input_storage.common_name .. "\"}}"
+    data = data .. "\"common_name\":\"" .. input_storage.common_name .. "\","
+    data = data .. "\"protocol_version\":" .. PROTOCOL_VERSION
+    -- optional, the server shows the inventory view if it is left out
+    if input_storage.view then
+        data = data .. ",\"view\":\"" .. input_storage.view .. "\""
+    end
+    data = data .. "}}"
     ws_handle.send(data)
 end
 
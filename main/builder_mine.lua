@@ -16,6 +16,7 @@ IDLE_POSITION = {
 function Main()
     WriteStartupFile()
     LoadPositionFromLine()
+    ConnectTelemetry()
     GotoPoint(IDLE_POSITION, {"y", "x", "z"})
     Orient(1)
     PickUpMachines()
@@ -23,9 +24,11 @@ function Main()
     while true do
         GotoPoint(IDLE_POSITION, {"y", "x", "z"})
         Orient(1)
+        SetTurtleState("building")
         PlaceMachines()
         os.sleep(12 * 60)
         PickUpMachines()
+        SetTurtleState("moving")
         GotoPoint(IDLE_POSITION, {"y", "x", "z"})
         Orient(1)
         GoToNextChunk()
@@ -28,15 +28,19 @@ MiningInfo = {
 
 
 function Main()
+    ConnectTelemetry()
     turtle.digDown()
     while true do
+        SetTurtleState("mining")
         Mine()
         if not HaveInventorySpace() then
             print("emptying inventory")
+            SetTurtleState("unloading")
             EmptyInventory()
         end
         if not HaveEnoughFuel() then
             print("refueling")
+            SetTurtleState("refueling")
             Refuel()
         end
     end
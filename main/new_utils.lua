@@ -1,3 +1,5 @@
+require "telemetry"
+
 DATA_FILE = "data.txt"
 
 REFUEL_SLOT = 16
@@ -31,6 +33,7 @@ function SavePositionAsLine()
     else
         print("err:", err)
     end
+    SendTelemetry()
 end
 
 --[[
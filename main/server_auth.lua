@@ -0,0 +1,20 @@
+--[[
+    Token handling shared by every program that talks to the server, the base computers included.
+    Copy it next to whichever program requires it.
+]]--
+
+TOKEN_FILE = "token.txt"
+
+--[[
+    Headers for talking to the server, with this computer's token if one was issued. The token
+    goes in TOKEN_FILE, get it from POST /api/admin/tokens on the server machine.
+]]--
+function ServerHeaders(headers)
+    headers = headers or {}
+    if fs.exists(TOKEN_FILE) then
+        local file = fs.open(TOKEN_FILE, "r")
+        headers["X-Computer-Token"] = file.readAll():gsub("%s+", "")
+        file.close()
+    end
+    return headers
+end
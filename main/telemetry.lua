@@ -0,0 +1,112 @@
+require "server_auth"
+
+local expect = require "cc.expect"
+
+TELEMETRY_URL = "ws://127.0.0.1:3000/ws/turtle"
+-- bumped whenever the messages sent to or understood from the server change
+TURTLE_PROTOCOL_VERSION = 1
+
+-- what the turtle is currently doing, sent along with every telemetry message
+TurtleState = "idle"
+
+local telemetry_ws
+
+--[[
+    Name and x/z offset of the block in front of the turtle for each direction. new_utils counts
+    directions 1-4 from where the turtle was placed, utils uses letters.
+]]--
+local FACINGS = {
+    [1] = {name = "east", dx = 1, dz = 0}, [2] = {name = "south", dx = 0, dz = 1},
+    [3] = {name = "west", dx = -1, dz = 0}, [4] = {name = "north", dx = 0, dz = -1},
+}
+FACINGS.e, FACINGS.s, FACINGS.w, FACINGS.n = FACINGS[1], FACINGS[2], FACINGS[3], FACINGS[4]
+
+--[[
+    Connects to the server and registers this turtle, telemetry is silently skipped if this fails
+]]--
+function ConnectTelemetry()
+    local ws, err = http.websocket(TELEMETRY_URL, ServerHeaders())
+    if not ws then
+        print("telemetry unavailable:", err)
+        return false
+    end
+    telemetry_ws = ws
+    telemetry_ws.send(textutils.serializeJSON({
+        turtle_register = {
+            computer_id = os.getComputerID(),
+            label = os.getComputerLabel(),
+            protocol_version = TURTLE_PROTOCOL_VERSION,
+        }
+    }))
+    return true
+end
+
+function GetTelemetrySocket()
+    return telemetry_ws
+end
+
+function SetTurtleState(state)
+    expect(1, state, "string")
+    TurtleState = state
+    SendTelemetry()
+end
+
+--[[
+    Sends the global Position, fuel and inventory to the server
+]]--
+function SendTelemetry()
+    if not telemetry_ws then
+        return
+    end
+    local fuel = turtle.getFuelLevel()
+    if fuel == "unlimited" then
+        fuel = nil
+    end
+    local inventory = {}
+    for slot = 1, 16, 1 do
+        local item = turtle.getItemDetail(slot)
+        if item then
+            table.insert(inventory, {slot = slot, name = item.name, count = item.count})
+        end
+    end
+    local data = textutils.serializeJSON({turtle_telemetry = {
+        computer_id = os.getComputerID(),
+        position = {x = Position.x, y = Position.y, z = Position.z},
+        facing = FACINGS[Position.direction] and FACINGS[Position.direction].name,
+        fuel = fuel,
+        inventory = inventory,
+        state = TurtleState,
+    }})
+    local ok = pcall(telemetry_ws.send, data)
+    if not ok then
+        -- connection dropped, try again next time
+        telemetry_ws = nil
+        ConnectTelemetry()
+    end
+end
+
+local function observation(x, y, z, found, data)
+    local block = nil
+    if found then
+        block = data.name
+    end
+    return {position = {x = x, y = y, z = z}, block = block}
+end
+
+--[[
+    Inspects the blocks in front, above and below the turtle and sends them to the server's world map
+]]--
+function InspectSurroundings()
+    if not telemetry_ws then
+        return
+    end
+    local observations = {}
+    local x, y, z = Position.x, Position.y, Position.z
+    table.insert(observations, observation(x, y + 1, z, turtle.inspectUp()))
+    table.insert(observations, observation(x, y - 1, z, turtle.inspectDown()))
+    local facing = FACINGS[Position.direction]
+    if facing then
+        table.insert(observations, observation(x + facing.dx, y, z + facing.dz, turtle.inspect()))
+    end
+    pcall(telemetry_ws.send, textutils.serializeJSON({block_observations = observations}))
+end
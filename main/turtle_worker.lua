@@ -0,0 +1,291 @@
+require "new_utils"
+
+local expect = require "cc.expect"
+
+RECONNECT_TIME = 5
+-- how long to wait for the server to plan a path before digging straight there
+PATH_TIMEOUT = 10
+
+-- set when the server cancels the running task, checked between steps
+local cancelled_task_id
+local running_task_id
+local next_path_request_id = 0
+
+function Main()
+    LoadPositionFromLine()
+    while true do
+        if not GetTelemetrySocket() and not ConnectTelemetry() then
+            os.sleep(RECONNECT_TIME)
+        else
+            SendTelemetry()
+            local message = GetTelemetrySocket().receive()
+            if message == nil then
+                -- socket closed, reconnect
+                ConnectTelemetry()
+            else
+                HandleServerMessage(message)
+            end
+        end
+    end
+end
+
+function HandleServerMessage(message)
+    local json = textutils.unserializeJSON(message)
+    if json == nil then
+        print("Bad JSON", message)
+        return
+    end
+    if json["RunTask"] then
+        RunTask(json["RunTask"].id, json["RunTask"].task)
+    elseif json["CancelTask"] then
+        -- nothing is running between tasks, so there is nothing to cancel
+    else
+        print("Bad message", message)
+    end
+end
+
+function SendTaskMessage(data)
+    local ws = GetTelemetrySocket()
+    if ws then
+        pcall(ws.send, textutils.serializeJSON(data))
+    end
+end
+
+function ReportProgress(progress)
+    expect(1, progress, "number")
+    SendTaskMessage({task_progress = {id = running_task_id, progress = progress}})
+end
+
+--[[
+    Called between steps of a task, stops the task with error() if the server cancelled it
+]]--
+function CheckCancelled()
+    local ws = GetTelemetrySocket()
+    if not ws then
+        return
+    end
+    local message = ws.receive(0)
+    while message do
+        NoteCancel(textutils.unserializeJSON(message))
+        message = ws.receive(0)
+    end
+    if cancelled_task_id == running_task_id then
+        error("cancelled", 0)
+    end
+end
+
+function NoteCancel(json)
+    if json and running_task_id and json["CancelTask"] == running_task_id then
+        cancelled_task_id = running_task_id
+    end
+end
+
+--[[
+    Asks the server for a path to the position using its map of blocks seen so far.
+    Returns a list of positions starting where the turtle is, false if the server says there is
+    no way there, or nil if the server couldn't be asked or didn't answer in time.
+]]--
+function RequestPath(position)
+    local ws = GetTelemetrySocket()
+    if not ws then
+        return nil
+    end
+    next_path_request_id = next_path_request_id + 1
+    local id = next_path_request_id
+    SendTaskMessage({path_request = {
+        id = id,
+        from = {x = Position.x, y = Position.y, z = Position.z},
+        to = {x = position.x, y = position.y, z = position.z},
+    }})
+    local deadline = os.clock() + PATH_TIMEOUT
+    while os.clock() < deadline do
+        local message = ws.receive(deadline - os.clock())
+        if message == nil then
+            return nil
+        end
+        local json = textutils.unserializeJSON(message)
+        if json and json["Path"] and json["Path"].id == id then
+            -- serde sends None as null, which unserializeJSON turns into nil
+            return json["Path"].path or false
+        end
+        NoteCancel(json)
+    end
+    return nil
+end
+
+--[[
+    Moves along a path from RequestPath one block at a time, digging where needed
+]]--
+function FollowPath(path)
+    for index = 2, #path, 1 do
+        CheckCancelled()
+        local next = path[index]
+        if next.y > Position.y then
+            turtle.digUp()
+            MoveUp()
+        elseif next.y < Position.y then
+            turtle.digDown()
+            MoveDown()
+        else
+            if next.x > Position.x then
+                Orient(1)
+            elseif next.x < Position.x then
+                Orient(3)
+            elseif next.z > Position.z then
+                Orient(2)
+            else
+                Orient(4)
+            end
+            turtle.dig()
+            MoveForward()
+        end
+        InspectSurroundings()
+        if Position.x ~= next.x or Position.y ~= next.y or Position.z ~= next.z then
+            error("stuck at " .. Position.x .. "," .. Position.y .. "," .. Position.z)
+        end
+    end
+end
+
+--[[
+    Goes to the position along a planned path, or straight there if the server can't be reached.
+    When the server found no path, digging straight there would go through blocks the dig policy
+    protects, so the task fails instead.
+]]--
+function TravelTo(position)
+    local path = RequestPath(position)
+    if path then
+        FollowPath(path)
+    elseif path == false then
+        error("no path to " .. position.x .. "," .. position.y .. "," .. position.z .. " the dig policy allows")
+    else
+        DigToPoint(position)
+    end
+end
+
+function RunTask(id, task)
+    running_task_id = id
+    cancelled_task_id = nil
+    local ok, err = pcall(RunTaskSteps, task)
+    SetTurtleState("idle")
+    if cancelled_task_id == id then
+        -- the server already forgot about this task
+    elseif ok then
+        SendTaskMessage({task_finished = {id = id, success = true}})
+    else
+        SendTaskMessage({task_finished = {id = id, success = false, error = tostring(err)}})
+    end
+    running_task_id = nil
+end
+
+function RunTaskSteps(task)
+    if task == "refuel" then
+        SetTurtleState("refueling")
+        Refuel()
+    elseif task["go_to"] then
+        SetTurtleState("moving")
+        TravelTo(task["go_to"].position)
+    elseif task["unload"] then
+        SetTurtleState("unloading")
+        TravelTo(task["unload"].at)
+        for slot = 1, 16, 1 do
+            if slot ~= REFUEL_SLOT then
+                turtle.select(slot)
+                turtle.dropDown()
+            end
+        end
+        turtle.select(1)
+    elseif task["place"] then
+        SetTurtleState("building")
+        local target = task["place"].position
+        TravelTo({x = target.x, y = target.y + 1, z = target.z})
+        if not SelectItem(task["place"].item) then
+            error("no " .. task["place"].item .. " in inventory")
+        end
+        turtle.digDown()
+        if not turtle.placeDown() then
+            error("could not place " .. task["place"].item)
+        end
+        turtle.select(1)
+    elseif task["dig"] then
+        SetTurtleState("mining")
+        DigArea(task["dig"].from, task["dig"].to)
+    else
+        error("unknown task " .. textutils.serializeJSON(task))
+    end
+end
+
+function SelectItem(name)
+    for slot = 1, 16, 1 do
+        local item = turtle.getItemDetail(slot)
+        if item and item.name == name then
+            turtle.select(slot)
+            return true
+        end
+    end
+    return false
+end
+
+--[[
+    Like GotoPoint, but digs through anything in the way and can be cancelled between blocks
+]]--
+function DigToPoint(position)
+    while Position.y ~= position.y do
+        CheckCancelled()
+        local before = Position.y
+        if Position.y < position.y then
+            turtle.digUp()
+            MoveUp()
+        else
+            turtle.digDown()
+            MoveDown()
+        end
+        InspectSurroundings()
+        if Position.y == before then
+            error("stuck at " .. Position.x .. "," .. Position.y .. "," .. Position.z)
+        end
+    end
+    for _, axis in ipairs({"x", "z"}) do
+        if Position[axis] ~= position[axis] then
+            if axis == "x" then
+                Orient(Position.x < position.x and 1 or 3)
+            else
+                Orient(Position.z < position.z and 2 or 4)
+            end
+            while Position[axis] ~= position[axis] do
+                CheckCancelled()
+                turtle.dig()
+                local before = Position[axis]
+                MoveForward()
+                InspectSurroundings()
+                if Position[axis] == before then
+                    error("stuck at " .. Position.x .. "," .. Position.y .. "," .. Position.z)
+                end
+            end
+        end
+    end
+end
+
+--[[
+    Clears every block between two corners, one layer at a time from the top down
+]]--
+function DigArea(from, to)
+    local min_x, max_x = math.min(from.x, to.x), math.max(from.x, to.x)
+    local min_z, max_z = math.min(from.z, to.z), math.max(from.z, to.z)
+    local top, bottom = math.max(from.y, to.y), math.min(from.y, to.y)
+    local layers = top - bottom + 1
+    for y = top, bottom, -1 do
+        for x = min_x, max_x, 1 do
+            -- snake along z so we never walk back over a finished row
+            local z_start, z_end, z_step = min_z, max_z, 1
+            if (x - min_x) % 2 == 1 then
+                z_start, z_end, z_step = max_z, min_z, -1
+            end
+            for z = z_start, z_end, z_step do
+                DigToPoint({x = x, y = y, z = z})
+            end
+        end
+        ReportProgress((top - y + 1) / layers)
+    end
+end
+
+Main()
@@ -1,3 +1,5 @@
+require "telemetry"
+
 
 --[[
 Goes to a point
@@ -160,6 +162,15 @@ function Orient(direction)
 end
 
 
+--[[
+Tells the server where the turtle is now and what is around it
+]]--
+local function Moved()
+    SendTelemetry()
+    InspectSurroundings()
+end
+
+
 function TurnRight()
     turtle.turnRight()
     if Position.direction == "n" then
@@ -171,6 +182,7 @@ function TurnRight()
     elseif Position.direction == "w" then
         Position.direction = "n"
     end
+    Moved()
 end
 
 
@@ -185,18 +197,21 @@ function TurnLeft()
     elseif Position.direction == "e" then
         Position.direction = "n"
     end
+    Moved()
 end
 
 
 function MoveDown()
     if turtle.down() then
         Position.y = Position.y - 1
+        Moved()
     end
 end
 
 function MoveUp()
     if turtle.up() then
         Position.y = Position.y + 1
+        Moved()
     end
 end
 
@@ -211,6 +226,7 @@ function MoveForward()
         elseif Position.direction == "w" then
             Position.x = Position.x - 1
         end
+        Moved()
     end
 end
 
@@ -225,5 +241,6 @@ function MoveBack()
         elseif Position.direction == "w" then
             Position.x = Position.x + 1
         end
+        Moved()
     end
-end
\ No newline at end of file
+end
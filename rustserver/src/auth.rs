@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::RwLock;
+use crate::turtle_manager::now_seconds;
+
+/// Header computers send their token in, the register message can carry it instead
+pub const TOKEN_HEADER: &str = "x-computer-token";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IssuedToken {
+    pub token: String,
+    pub computer_id: i64,
+    #[serde(default)]
+    pub label: Option<String>,
+    /// seconds since the unix epoch
+    pub issued_at: u64,
+}
+
+#[derive(Debug, Error)]
+pub enum AuthError {
+    MissingToken,
+    UnknownToken,
+    WrongComputer {
+        token_computer_id: i64,
+        computer_id: i64,
+    },
+    Io(#[from] std::io::Error),
+    Parse(#[from] serde_json::Error),
+}
+
+impl Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuthError::MissingToken => write!(f, "No token given"),
+            AuthError::UnknownToken => write!(f, "Unknown or revoked token"),
+            AuthError::WrongComputer { token_computer_id, computer_id } => {
+                write!(f, "Token belongs to computer {} but was used by computer {}", token_computer_id, computer_id)
+            }
+            AuthError::Io(e) => write!(f, "Failed to access tokens file: {}", e),
+            AuthError::Parse(e) => write!(f, "Failed to parse tokens: {}", e),
+        }
+    }
+}
+
+/// TokenStore holds the tokens issued to computers. Until the first token is issued every
+/// computer is let in, so existing setups keep working until tokens are handed out.
+pub struct TokenStore {
+    tokens: RwLock<HashMap<String, IssuedToken>>,
+    path: PathBuf,
+}
+
+impl TokenStore {
+    /// Loads the tokens saved at `path`, none if the file doesn't exist yet
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, AuthError> {
+        let path = path.as_ref().to_path_buf();
+        let tokens: Vec<IssuedToken> = match std::fs::read_to_string(&path) {
+            Ok(data) => serde_json::from_str(&data)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(TokenStore {
+            tokens: RwLock::new(tokens.into_iter().map(|t| (t.token.clone(), t)).collect()),
+            path,
+        })
+    }
+
+    pub async fn is_enforced(&self) -> bool {
+        !self.tokens.read().await.is_empty()
+    }
+
+    /// Every issued token, oldest first
+    pub async fn list(&self) -> Vec<IssuedToken> {
+        let mut tokens: Vec<_> = self.tokens.read().await.values().cloned().collect();
+        tokens.sort_by_key(|t| (t.issued_at, t.computer_id));
+        tokens
+    }
+
+    pub async fn issue(&self, computer_id: i64, label: Option<String>) -> Result<IssuedToken, AuthError> {
+        let bytes: [u8; 16] = rand::rng().random();
+        let issued = IssuedToken {
+            token: bytes.iter().map(|b| format!("{:02x}", b)).collect(),
+            computer_id,
+            label,
+            issued_at: now_seconds(),
+        };
+        let mut tokens = self.tokens.write().await;
+        tokens.insert(issued.token.clone(), issued.clone());
+        self.save(&tokens).await?;
+        Ok(issued)
+    }
+
+    /// Returns false if there was no such token
+    pub async fn revoke(&self, token: &str) -> Result<bool, AuthError> {
+        let mut tokens = self.tokens.write().await;
+        if tokens.remove(token).is_none() {
+            return Ok(false);
+        }
+        self.save(&tokens).await?;
+        Ok(true)
+    }
+
+    /// Checks a token is known, without knowing yet which computer it is for
+    pub async fn check_token(&self, token: &str) -> Result<(), AuthError> {
+        if !self.is_enforced().await || self.tokens.read().await.contains_key(token) {
+            return Ok(());
+        }
+        Err(AuthError::UnknownToken)
+    }
+
+    /// Checks `computer_id` may connect with `token`
+    pub async fn check(&self, token: Option<&str>, computer_id: i64) -> Result<(), AuthError> {
+        let tokens = self.tokens.read().await;
+        if tokens.is_empty() {
+            return Ok(());
+        }
+        let token = token.ok_or(AuthError::MissingToken)?;
+        let issued = tokens.get(token).ok_or(AuthError::UnknownToken)?;
+        if issued.computer_id != computer_id {
+            return Err(AuthError::WrongComputer { token_computer_id: issued.computer_id, computer_id });
+        }
+        Ok(())
+    }
+
+    async fn save(&self, tokens: &HashMap<String, IssuedToken>) -> Result<(), AuthError> {
+        let tokens: Vec<_> = tokens.values().collect();
+        let data = serde_json::to_string_pretty(&tokens)?;
+        let temp = self.path.with_extension("tmp");
+        tokio::fs::write(&temp, data).await?;
+        tokio::fs::rename(&temp, &self.path).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_issue_check_and_revoke() {
+        let path = std::env::temp_dir().join(format!("tokens_test_{}.json", std::process::id()));
+        let store = TokenStore::load(&path).unwrap();
+        // nothing issued yet, so anyone gets in
+        assert!(store.check(None, 1).await.is_ok());
+
+        let issued = store.issue(1, Some("storage".to_string())).await.unwrap();
+        assert_eq!(issued.token.len(), 32);
+        assert!(store.check(Some(&issued.token), 1).await.is_ok());
+        assert!(matches!(store.check(None, 1).await, Err(AuthError::MissingToken)));
+        assert!(matches!(store.check(Some("guess"), 1).await, Err(AuthError::UnknownToken)));
+        assert!(matches!(store.check(Some(&issued.token), 2).await, Err(AuthError::WrongComputer { token_computer_id: 1, computer_id: 2 })));
+
+        let other = store.issue(2, None).await.unwrap();
+        let reloaded = TokenStore::load(&path).unwrap();
+        assert_eq!(reloaded.list().await.len(), 2);
+        assert!(reloaded.revoke(&issued.token).await.unwrap());
+        assert!(!reloaded.revoke(&issued.token).await.unwrap());
+        std::fs::remove_file(&path).ok();
+        assert!(matches!(reloaded.check_token(&issued.token).await, Err(AuthError::UnknownToken)));
+        assert!(reloaded.check_token(&other.token).await.is_ok());
+    }
+}
@@ -1,53 +1,142 @@
+use std::collections::HashMap;
+use cc_code_page::cctweaked_char;
 use std::fmt::Display;
 use std::io::BufWriter;
 use ratatui::backend::{Backend, ClearType, WindowSize};
-use ratatui::buffer::Cell;
-use ratatui::layout::{Position, Size};
+use ratatui::buffer::{Buffer, Cell};
+use ratatui::layout::{Position, Rect, Size};
 use ratatui::prelude::Color;
-use tracing::{debug, error, info, trace};
+use tracing::{debug, error};
 use std::io::Write;
-use std::sync::Arc;
-use axum::extract::ws::{Message, Utf8Bytes, WebSocket};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use axum::body::Bytes;
+use axum::extract::ws::{close_code, CloseFrame, Message, Utf8Bytes, WebSocket};
 use futures::stream::{SplitSink, SplitStream};
-use tokio::sync::mpsc::{UnboundedSender, UnboundedReceiver};
+use tokio::sync::mpsc::{Receiver, Sender, UnboundedReceiver};
+use tokio::sync::mpsc::error::TrySendError;
 use futures::{SinkExt, StreamExt};
 use ratatui::Terminal;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
-use tokio::sync::{oneshot, Mutex};
-use crate::inventory_manager::{InventoryManager, InventoryReport};
+use tokio::sync::Mutex;
+use tokio_util::sync::CancellationToken;
+use crate::connections::{Connection, ConnectionRole, QueueStats};
+use crate::commands::{CommandAck, ComputerHandle, ComputerRequest, RpcRequest, RpcResponse, ServerCommandRequest};
+use crate::inventory_manager::InventoryReport;
+use crate::metrics::{Direction, Metrics};
+use crate::recording::Recorder;
+use crate::session::SessionError;
+use crate::views::MonitorView;
+
+/// How many drawn frames can wait for a monitor before new ones are dropped
+pub const MONITOR_FRAME_QUEUE: usize = 4;
+
+/// Everything drawn to a monitor by one `Terminal::draw`, sent as a whole so a monitor never
+/// shows half a frame
+pub type MonitorFrame = Vec<CCTweakedMonitorBackendEvent>;
 
 pub struct CCTweakedMonitorBackend {
-    event_writer: UnboundedSender<CCTweakedMonitorBackendEvent>,
+    frame_writer: Sender<MonitorFrame>,
+    queue: Arc<QueueStats>,
+    metrics: Arc<Metrics>,
     size: Size,
-    current_word: Option<BufWriter<Vec<u8>>>
+    current_word: Option<BufWriter<Vec<u8>>>,
+    // events of the frame being drawn, sent on flush
+    pending: MonitorFrame,
+    // what the monitor shows once every frame so far has been sent
+    screen: Buffer,
+    // a frame was dropped, so the next one has to redraw the whole screen instead of just what changed
+    resync: bool,
 }
 
-pub struct WebSocketCloseEvent;
+/// The monitor's session has ended, so there is nothing left to draw to
+#[derive(Debug, Error)]
+pub struct MonitorDisconnected;
+
+impl Display for MonitorDisconnected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Monitor disconnected")
+    }
+}
+
+/// Whether a drawing error only means the monitor went away
+pub fn is_disconnect(e: &std::io::Error) -> bool {
+    e.get_ref().is_some_and(|e| e.is::<MonitorDisconnected>())
+}
 
 impl CCTweakedMonitorBackend {
-    pub fn new(event_writer: UnboundedSender<CCTweakedMonitorBackendEvent>, size: Size) -> Self {
+    pub fn new(frame_writer: Sender<MonitorFrame>, queue: Arc<QueueStats>, metrics: Arc<Metrics>, size: Size) -> Self {
         CCTweakedMonitorBackend {
-            event_writer,
+            frame_writer,
+            queue,
+            metrics,
             size,
-            current_word: None
+            current_word: None,
+            pending: Vec::new(),
+            screen: Buffer::empty(Rect::new(0, 0, size.width, size.height)),
+            resync: false,
         }
     }
     
     pub fn set_size(&mut self, size: Size) {
         self.size = size;
+        self.screen.resize(Rect::new(0, 0, size.width, size.height));
     }
     
+    /// Records how long a whole `Terminal::draw` took, rendering included
+    pub fn draw_took(&self, took: Duration) {
+        self.metrics.draw_took(took);
+    }
+
+    fn send(&mut self, event: CCTweakedMonitorBackendEvent) -> std::io::Result<()> {
+        self.pending.push(event);
+        Ok(())
+    }
+
+    /// Queues the pending frame. A congested monitor gets no frames until it catches up, then
+    /// the latest full screen instead of everything it missed.
+    fn send_frame(&mut self) -> std::io::Result<()> {
+        if self.resync {
+            self.pending.clear();
+            let screen = std::mem::take(&mut self.screen);
+            let redraw = self.draw(screen.content.iter().enumerate().map(|(i, cell)| {
+                let (x, y) = screen.pos_of(i);
+                (x, y, cell)
+            }));
+            self.screen = screen;
+            redraw?;
+            self.flush_word()?;
+        }
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        match self.frame_writer.try_send(std::mem::take(&mut self.pending)) {
+            Ok(()) => {
+                self.queue.pushed();
+                self.resync = false;
+                Ok(())
+            }
+            Err(TrySendError::Full(_)) => {
+                if !self.resync {
+                    debug!("Monitor is falling behind, dropping frames until it catches up");
+                }
+                self.queue.dropped();
+                self.resync = true;
+                Ok(())
+            }
+            Err(TrySendError::Closed(_)) => Err(std::io::Error::new(std::io::ErrorKind::BrokenPipe, MonitorDisconnected)),
+        }
+    }
+
     fn flush_word(&mut self) -> std::io::Result<()> {
         if let Some(word) = self.current_word.take() {
             let bytes = word.into_inner()?;
             let word = String::from_utf8(bytes).map_err(|e| {
-                std::io::Error::new(std::io::ErrorKind::Other, format!("Failed to convert bytes to string: {}", e))
+                std::io::Error::other(format!("Failed to convert bytes to string: {}", e))
             })?;
             debug!("Flushing word: \"{}\"", word);
-            self.event_writer.send(CCTweakedMonitorBackendEvent::WriteText(word)).map_err(|e| {
-                std::io::Error::new(std::io::ErrorKind::Other, format!("Failed to send event: {}", e))
-            })?;
+            self.send(CCTweakedMonitorBackendEvent::WriteText(word))?;
         }
         Ok(())
     }
@@ -59,265 +148,194 @@ impl CCTweakedMonitorBackend {
 pub struct MonitorOutputHandler {
     // sends events to the terminal via websocket
     socket_writer: SplitSink<WebSocket, Message>,
-    event_receiver: UnboundedReceiver<CCTweakedMonitorBackendEvent>,
-    hangup: oneshot::Sender<WebSocketCloseEvent>
+    frame_receiver: Receiver<MonitorFrame>,
+    // the connection's queue stats are kept up to date as frames are taken off the queue
+    connection: Connection,
+    metrics: Arc<Metrics>,
+    recorder: Option<Arc<Recorder>>,
+    // commands and peripheral calls issued through the computer's ComputerHandle
+    command_receiver: UnboundedReceiver<ComputerRequest>,
+    // how often the computer is pinged so a dead connection is noticed by the input handler
+    heartbeat_interval: Duration,
 }
 
 
 impl MonitorOutputHandler {
     pub fn new(
-        event_receiver: UnboundedReceiver<CCTweakedMonitorBackendEvent>, 
+        frame_receiver: Receiver<MonitorFrame>,
+        command_receiver: UnboundedReceiver<ComputerRequest>,
         socket_writer: SplitSink<WebSocket, Message>,
-        hangup: oneshot::Sender<WebSocketCloseEvent>
+        connection: Connection,
+        metrics: Arc<Metrics>,
+        recorder: Option<Arc<Recorder>>,
+        heartbeat_interval: Duration,
     ) -> Self {
         MonitorOutputHandler {
             socket_writer,
-            event_receiver,
-            hangup
+            frame_receiver,
+            connection,
+            metrics,
+            recorder,
+            command_receiver,
+            heartbeat_interval,
         }
     }
     
-    pub async fn handle_outbound(mut self) {
+    /// Sends events until the terminal is dropped or sending fails. When `shutdown` is cancelled the
+    /// computer is told the server is going away.
+    pub async fn handle_outbound(mut self, shutdown: CancellationToken) -> Result<(), SessionError> {
+        let mut heartbeat = tokio::time::interval(self.heartbeat_interval);
+        heartbeat.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
         loop {
-            let Some(event) = self.event_receiver.recv().await else {
-                info!("Monitor Backend Connection closed");
-                self.hangup.send(WebSocketCloseEvent).ok();
-                return;
-            };
-            let message = match event {
-                CCTweakedMonitorBackendEvent::WriteText(word) => {
-                    let Ok(word) = translate_to_cctweaked(&word).map_err(|e| {
-                        error!("Failed to translate word: {}", e);
-                    }) else {
-                        continue;
+            let frame = tokio::select! {
+                frame = self.frame_receiver.recv() => {
+                    let Some(frame) = frame else {
+                        debug!("Monitor Backend Connection closed");
+                        return Ok(());
                     };
-                    Message::Binary(word.into())
+                    self.connection.queue().popped();
+                    frame
                 }
-                e => {
-                    let Ok(data) = serde_json::to_string(&e).map_err(|e| {
-                        error!("Failed to serialize event: {}", e);
-                    }) else {
-                        continue;
-                    };
-                    Message::Text(Utf8Bytes::from(data))
+                Some(request) = self.command_receiver.recv() => vec![match request {
+                    ComputerRequest::Command(command) => CCTweakedMonitorBackendEvent::ServerCommand(command),
+                    ComputerRequest::Rpc(call) => CCTweakedMonitorBackendEvent::PeripheralCall(call),
+                }],
+                _ = heartbeat.tick() => {
+                    // CC answers pings on its own, the pong counts as a message for the input handler
+                    self.socket_writer.send(Message::Ping(Bytes::new())).await.map_err(SessionError::Send)?;
+                    continue;
                 }
-            };
-            
-            let Ok(()) = self.socket_writer.send(message).await.map_err(|e| {
-                let message = format!("{}", e);
-                if message.contains("closed connection") || message.contains("channel closed") {
-                    // The connection is closed, so we can just return
-                    return
+                _ = shutdown.cancelled() => {
+                    let close = CloseFrame { code: close_code::AWAY, reason: Utf8Bytes::from_static("server shutting down") };
+                    self.socket_writer.send(Message::Close(Some(close))).await.map_err(SessionError::Send)?;
+                    return Err(SessionError::Shutdown);
                 }
-                error!("Failed to send event: {}", e);
-            }) else {
-                self.hangup.send(WebSocketCloseEvent).ok();
-                return;
             };
+            for event in frame {
+                self.metrics.message(ConnectionRole::Monitor, Direction::Out, event.kind());
+                if let Some(recorder) = &self.recorder {
+                    recorder.outbound(&event);
+                }
+                let message = match event {
+                    CCTweakedMonitorBackendEvent::WriteText(word) => Message::Binary(translate_to_cctweaked(&word).into()),
+                    e => {
+                        let Ok(data) = serde_json::to_string(&e).map_err(|e| {
+                            error!("Failed to serialize event: {}", e);
+                        }) else {
+                            continue;
+                        };
+                        Message::Text(Utf8Bytes::from(data))
+                    }
+                };
+                let bytes = match &message {
+                    Message::Text(text) => text.len(),
+                    Message::Binary(data) => data.len(),
+                    _ => 0,
+                };
+                self.metrics.bytes_sent(self.connection.computer_id(), bytes);
+                self.socket_writer.feed(message).await.map_err(SessionError::Send)?;
+            }
+            self.socket_writer.flush().await.map_err(SessionError::Send)?;
         }
     }
 
 }
 
 
-fn translate_to_cctweaked(word: &str) -> Result<Vec<u8>, CharTranslationError> {
-    let mut result = Vec::new();
+/// Translates text to the CC code page, one byte per cell it takes up. Characters the font doesn't
+/// have are shown as one it does where there's an obvious stand-in, and as `?` otherwise.
+fn translate_to_cctweaked(word: &str) -> Vec<u8> {
+    let mut result = Vec::with_capacity(word.len());
     for c in word.chars() {
         if let Some(byte) = translate_char_to_cctweaked_byte(c) {
             result.push(byte);
+        } else if let Some(text) = transliterate(c) {
+            result.extend(text.chars().map(|c| translate_char_to_cctweaked_byte(c).unwrap_or(b'?')));
         } else {
-            return Err(CharTranslationError(c));
+            debug!("No CC character for {:?}", c);
+            result.push(b'?');
         }
     }
-    Ok(result)
+    result
 }
 
 fn translate_char_to_cctweaked_byte(c: char) -> Option<u8> {
+    static BYTES: OnceLock<HashMap<char, u8>> = OnceLock::new();
     if c.is_ascii() {
         return Some(c as u8);
     }
-    // Handle special characters
-    match c {
-        //  |0 1 2 3 4 5 6 7 8 9 A B C D E F
-        // -+--------------------------------
-        // 0|  ☺ ☻ ♥ ♦ ♣ ♠ ● ○     ♂ ♀   ♪ ♬
-        '☺' => Some(0x01),
-        '☻' => Some(0x02),
-        '♥' => Some(0x03),
-        '♦' => Some(0x04),
-        '♣' => Some(0x05),
-        '♠' => Some(0x06),
-        '●' => Some(0x07),
-        '○' => Some(0x08),
-        '♂' => Some(0x0b),
-        '♀' => Some(0x0c),
-        '♪' => Some(0x0e),
-        '♬' => Some(0x0f),
-        // 1|▶ ◀ ↕ ‼ ¶ ░ ▬ ↨ ⬆ ⬇ ➡ ⬅ ∟ ⧺ ▲ ▼
-        '▶' => Some(0x10),
-        '◀' => Some(0x11),
-        '↕' => Some(0x12),
-        '‼' => Some(0x13),
-        '¶' => Some(0x14),
-        '░' => Some(0x15),
-        '▬' => Some(0x16),
-        '↨' => Some(0x17),
-        '⬆' => Some(0x18),
-        '⬇' => Some(0x19),
-        '➡' => Some(0x1a),
-        '⬅' => Some(0x1b),
-        '∟' => Some(0x1c),
-        '⧺' => Some(0x1d),
-        '▲' => Some(0x1e),
-        '▼' => Some(0x1f),
-        // ascii
-        '▒' => Some(0x7f),
-        // 8|⠀ ⠁ ⠈ ⠉ ⠂ ⠃ ⠊ ⠋ ⠐ ⠑ ⠘ ⠙ ⠒ ⠓ ⠚ ⠛
-        '🬀' => Some(0x81),
-        '🬁' => Some(0x82),
-        '🬂' => Some(0x83),
-        '🬃' => Some(0x84),
-        '🬄' => Some(0x85),
-        '🬅' => Some(0x86),
-        '🬆' => Some(0x87),
-        '🬇' => Some(0x88),
-        '🬈' => Some(0x89),
-        '🬉' => Some(0x8a),
-        '🬊' => Some(0x8b),
-        '🬋' => Some(0x8c),
-        '🬌' => Some(0x8d),
-        '🬍' => Some(0x8e),
-        '🬎' => Some(0x8f),
-        // 9|⠄ ⠅ ⠌ ⠍ ⠆ ⠇ ⠎ ⠏ ⠔ ⠕ ⠜ ⠝ ⠖ ⠗ ⠞ ⠟
-        '🬏' => Some(0x90),
-        '🬐' => Some(0x91),
-        '🬑' => Some(0x92),
-        '🬒' => Some(0x93),
-        '🬓' => Some(0x94),
-        '▌' => Some(0x95),
-        '🬔' => Some(0x96),
-        '🬕' => Some(0x97),
-        '🬖' => Some(0x98),
-        '🬗' => Some(0x99),
-        '🬘' => Some(0x9a),
-        '🬙' => Some(0x9b),
-        '🬚' => Some(0x9c),
-        '🬛' => Some(0x9d),
-        '🬜' => Some(0x9e),
-        '🬝' => Some(0x9f),
-        // A|▓ ¡ ¢ £ ¤ ¥ ¦ █ ¨ © ª « ¬ ­ ® ¯
-        '▓' => Some(0xa0),
-        '¡' => Some(0xa1),
-        '¢' => Some(0xa2),
-        '£' => Some(0xa3),
-        '¤' => Some(0xa4),
-        '¥' => Some(0xa5),
-        '¦' => Some(0xa6),
-        '¨' => Some(0xa8),
-        '©' => Some(0xa9),
-        'ª' => Some(0xaa),
-        '«' => Some(0xab),
-        '¬' => Some(0xac),
-        '\u{AD}' => Some(0xad),
-        '®' => Some(0xae),
-        '¯' => Some(0xaf),
-        // B|° ± ² ³ ´ µ ¶ · ¸ ¹ º » ¼ ½ ¾ ¿
-        '°' => Some(0xb0),
-        '±' => Some(0xb1),
-        '²' => Some(0xb2),
-        '³' => Some(0xb3),
-        '´' => Some(0xb4),
-        'µ' => Some(0xb5),
-        //'¶' => Some(0xb6),
-        '·' => Some(0xb7),
-        '¸' => Some(0xb8),
-        '¹' => Some(0xb9),
-        'º' => Some(0xba),
-        '»' => Some(0xbb),
-        '¼' => Some(0xbc),
-        '½' => Some(0xbd),
-        '¾' => Some(0xbe),
-        '¿' => Some(0xbf),
-        // C|À Á Â Ã Ä Å Æ Ç È É Ê Ë Ì Í Î Ï
-        'À' => Some(0xc0),
-        'Á' => Some(0xc1),
-        'Â' => Some(0xc2),
-        'Ã' => Some(0xc3),
-        'Ä' => Some(0xc4),
-        'Å' => Some(0xc5),
-        'Æ' => Some(0xc6),
-        'Ç' => Some(0xc7),
-        'È' => Some(0xc8),
-        'É' => Some(0xc9),
-        'Ê' => Some(0xca),
-        'Ë' => Some(0xcb),
-        'Ì' => Some(0xcc),
-        'Í' => Some(0xcd),
-        'Î' => Some(0xce),
-        'Ï' => Some(0xcf),
-        // D|Ð Ñ Ò Ó Ô Õ Ö × Ø Ù Ú Û Ü Ý Þ ß
-        'Ð' => Some(0xd0),
-        'Ñ' => Some(0xd1),
-        'Ò' => Some(0xd2),
-        'Ó' => Some(0xd3),
-        'Ô' => Some(0xd4),
-        'Õ' => Some(0xd5),
-        'Ö' => Some(0xd6),
-        '×' => Some(0xd7),
-        'Ø' => Some(0xd8),
-        'Ù' => Some(0xd9),
-        'Ú' => Some(0xda),
-        'Û' => Some(0xdb),
-        'Ü' => Some(0xdc),
-        'Ý' => Some(0xdd),
-        'Þ' => Some(0xde),
-        'ß' => Some(0xdf),
-        // E|à á â ã ä å æ ç è é ê ë ì í î ï
-        'à' => Some(0xe0),
-        'á' => Some(0xe1),
-        'â' => Some(0xe2),
-        'ã' => Some(0xe3),
-        'ä' => Some(0xe4),
-        'å' => Some(0xe5),
-        'æ' => Some(0xe6),
-        'ç' => Some(0xe7),
-        'è' => Some(0xe8),
-        'é' => Some(0xe9),
-        'ê' => Some(0xea),
-        'ë' => Some(0xeb),
-        'ì' => Some(0xec),
-        'í' => Some(0xed),
-        'î' => Some(0xee),
-        'ï' => Some(0xef),
-        // F|ð ñ ò ó ô õ ö ÷ ø ù ú û ü ý þ ÿ
-        'ð' => Some(0xf0),
-        'ñ' => Some(0xf1),
-        'ò' => Some(0xf2),
-        'ó' => Some(0xf3),
-        'ô' => Some(0xf4),
-        'õ' => Some(0xf5),
-        'ö' => Some(0xf6),
-        '÷' => Some(0xf7),
-        'ø' => Some(0xf8),
-        'ù' => Some(0xf9),
-        'ú' => Some(0xfa),
-        'û' => Some(0xfb),
-        'ü' => Some(0xfc),
-        'ý' => Some(0xfd),
-        'þ' => Some(0xfe),
-        'ÿ' => Some(0xff),
-        _ => None,
-
-    }
+    BYTES.get_or_init(|| (0..=u8::MAX).map(|byte| (cctweaked_char(byte), byte)).collect()).get(&c).copied()
 }
 
-#[derive(Debug, Clone, Copy, Error)]
-struct CharTranslationError(char);
-
-impl Display for CharTranslationError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Failed to translate character: {:?}", self.0)
-    }
+/// Stand-ins for characters the CC font doesn't have. Each is a single character, or nothing for
+/// characters ratatui doesn't give a cell of their own, so the rest of the row stays in place.
+fn transliterate(c: char) -> Option<&'static str> {
+    let text = match c {
+        // accented letters outside Latin-1
+        'Ā' | 'Ă' | 'Ą' => "A",
+        'ā' | 'ă' | 'ą' => "a",
+        'Ć' | 'Ĉ' | 'Ċ' | 'Č' => "C",
+        'ć' | 'ĉ' | 'ċ' | 'č' => "c",
+        'Ď' | 'Đ' => "D",
+        'ď' | 'đ' => "d",
+        'Ē' | 'Ĕ' | 'Ė' | 'Ę' | 'Ě' => "E",
+        'ē' | 'ĕ' | 'ė' | 'ę' | 'ě' => "e",
+        'Ĝ' | 'Ğ' | 'Ġ' | 'Ģ' => "G",
+        'ĝ' | 'ğ' | 'ġ' | 'ģ' => "g",
+        'Ĥ' | 'Ħ' => "H",
+        'ĥ' | 'ħ' => "h",
+        'Ĩ' | 'Ī' | 'Ĭ' | 'Į' | 'İ' => "I",
+        'ĩ' | 'ī' | 'ĭ' | 'į' | 'ı' => "i",
+        'Ĳ' => "I",
+        'ĳ' => "i",
+        'Ĵ' => "J",
+        'ĵ' => "j",
+        'Ķ' => "K",
+        'ķ' | 'ĸ' => "k",
+        'Ĺ' | 'Ļ' | 'Ľ' | 'Ŀ' | 'Ł' => "L",
+        'ĺ' | 'ļ' | 'ľ' | 'ŀ' | 'ł' => "l",
+        'Ń' | 'Ņ' | 'Ň' | 'Ŋ' => "N",
+        'ń' | 'ņ' | 'ň' | 'ŉ' | 'ŋ' => "n",
+        'Ō' | 'Ŏ' | 'Ő' => "O",
+        'ō' | 'ŏ' | 'ő' => "o",
+        'Œ' => "O",
+        'œ' => "o",
+        'Ŕ' | 'Ŗ' | 'Ř' => "R",
+        'ŕ' | 'ŗ' | 'ř' => "r",
+        'Ś' | 'Ŝ' | 'Ş' | 'Š' => "S",
+        'ś' | 'ŝ' | 'ş' | 'š' | 'ſ' => "s",
+        'Ţ' | 'Ť' | 'Ŧ' => "T",
+        'ţ' | 'ť' | 'ŧ' => "t",
+        'Ũ' | 'Ū' | 'Ŭ' | 'Ů' | 'Ű' | 'Ų' => "U",
+        'ũ' | 'ū' | 'ŭ' | 'ů' | 'ű' | 'ų' => "u",
+        'Ŵ' => "W",
+        'ŵ' => "w",
+        'Ŷ' | 'Ÿ' => "Y",
+        'ŷ' => "y",
+        'Ź' | 'Ż' | 'Ž' => "Z",
+        'ź' | 'ż' | 'ž' => "z",
+        // punctuation word processors like to put in
+        '‘' | '’' | '‚' | '‛' | '′' => "'",
+        '“' | '”' | '„' | '‟' | '″' => "\"",
+        '‐' | '‑' | '‒' | '–' | '—' | '―' | '−' => "-",
+        '…' => ".",
+        '•' => "·",
+        '\u{2002}'..='\u{200a}' | '\u{202f}' => " ",
+        '\u{200b}'..='\u{200d}' | '\u{feff}' => "",
+        // combining marks, which ratatui puts in the cell of the letter they go on
+        '\u{300}'..='\u{36f}' => "",
+        // box drawing, as ratatui's default borders use
+        '─' | '━' | '┄' | '┅' | '┈' | '┉' | '╌' | '╍' | '═' | '╴' | '╶' | '╸' | '╺' | '╼' | '╾' => "-",
+        '│' | '┃' | '┆' | '┇' | '┊' | '┋' | '╎' | '╏' | '║' | '╵' | '╷' | '╹' | '╻' | '╽' | '╿' => "|",
+        '╱' => "/",
+        '╲' => "\\",
+        '╳' => "X",
+        '\u{2500}'..='\u{257f}' => "+",
+        '▀' => "🬎",
+        '▓' => "▒",
+        _ => return None,
+    };
+    Some(text)
 }
 
 
@@ -334,11 +352,12 @@ impl Backend for CCTweakedMonitorBackend {
             // Move the cursor if the previous location was not (x - 1, y)
             if !matches!(last_pos, Some(p) if x == p.x + 1 && y == p.y) {
                 self.flush_word()?;
-                self.event_writer.send(CCTweakedMonitorBackendEvent::SetCursorPosition(Position { x, y })).map_err(|e| {
-                    std::io::Error::new(std::io::ErrorKind::Other, format!("Failed to send event: {}", e))
-                })?;
+                self.send(CCTweakedMonitorBackendEvent::SetCursorPosition(Position { x, y }))?;
             }
             last_pos = Some(Position { x, y });
+            if let Some(shown) = self.screen.cell_mut((x, y)) {
+                *shown = cell.clone();
+            }
             let cell_fg = if cell.fg != Color::Reset {
                 cell.fg
             } else {
@@ -353,22 +372,18 @@ impl Backend for CCTweakedMonitorBackend {
 
             if cell_fg != fg || cell_bg != bg {
                 self.flush_word()?;
-                self.event_writer.send(CCTweakedMonitorBackendEvent::SetTextColor(
+                self.send(CCTweakedMonitorBackendEvent::SetTextColor(
                     CCTweakedColor::try_from(cell.fg).unwrap_or_else(|e|{
                         error!("Failed to convert color: {}", e);
                         CCTweakedColor::White
                     })
-                )).map_err(|e| {
-                    std::io::Error::new(std::io::ErrorKind::Other, format!("Failed to send event: {}", e))
-                })?;
-                self.event_writer.send(CCTweakedMonitorBackendEvent::SetBackgroundColor(
+                ))?;
+                self.send(CCTweakedMonitorBackendEvent::SetBackgroundColor(
                     CCTweakedColor::try_from(cell.bg).unwrap_or_else(|e|{
                         error!("Failed to convert color: {}", e);
                         CCTweakedColor::Black
                     })
-                )).map_err(|e| {
-                    std::io::Error::new(std::io::ErrorKind::Other, format!("Failed to send event: {}", e))
-                })?;
+                ))?;
                 fg = cell.fg;
                 bg = cell.bg;
             }
@@ -380,7 +395,7 @@ impl Backend for CCTweakedMonitorBackend {
             match self.current_word {
                 Some(ref mut writer) => {
                     if let Err(e) = write!(writer, "{}", cell.symbol()) {
-                        return Err(std::io::Error::new(std::io::ErrorKind::Other, format!("Failed to write to word: {}", e)));
+                        return Err(std::io::Error::other(format!("Failed to write to word: {}", e)));
                     }
                 }
                 None => {
@@ -389,25 +404,16 @@ impl Backend for CCTweakedMonitorBackend {
             }
         }
 
-        Ok(())
+        // ratatui hides or moves the cursor after the diff, which has to come after the last word
+        self.flush_word()
     }
 
     fn hide_cursor(&mut self) -> std::io::Result<()> {
-        self.event_writer.send(CCTweakedMonitorBackendEvent::HideCursor).map_err(|e| {
-            std::io::Error::new(std::io::ErrorKind::Other, format!("Failed to send event: {}", e))
-        })
+        self.send(CCTweakedMonitorBackendEvent::HideCursor)
     }
 
     fn show_cursor(&mut self) -> std::io::Result<()> {
-        let result = self.event_writer.send(CCTweakedMonitorBackendEvent::ShowCursor);
-        if let Err(e) = result {
-            if e.to_string().contains("channel closed") {
-                // The connection is closed, so we can just return
-                return Ok(());
-            }
-            return Err(std::io::Error::new(std::io::ErrorKind::Other, format!("Failed to send event: {}", e)));
-        }
-        Ok(())
+        self.send(CCTweakedMonitorBackendEvent::ShowCursor)
     }
 
     fn get_cursor_position(&mut self) -> std::io::Result<Position> {
@@ -415,23 +421,18 @@ impl Backend for CCTweakedMonitorBackend {
     }
 
     fn set_cursor_position<P: Into<Position>>(&mut self, position: P) -> std::io::Result<()> {
-        self.event_writer.send(CCTweakedMonitorBackendEvent::SetCursorPosition(position.into())).map_err(|e| {
-            std::io::Error::new(std::io::ErrorKind::Other, format!("Failed to send event: {}", e))
-        })
+        self.send(CCTweakedMonitorBackendEvent::SetCursorPosition(position.into()))
     }
 
     fn clear(&mut self) -> std::io::Result<()> {
-        self.event_writer.send(CCTweakedMonitorBackendEvent::ClearScreen).map_err(|e| {
-            std::io::Error::new(std::io::ErrorKind::Other, format!("Failed to send event: {}", e))
-        })
+        self.screen.reset();
+        self.send(CCTweakedMonitorBackendEvent::ClearScreen)
     }
 
     fn clear_region(&mut self, clear_type: ClearType) -> std::io::Result<()> {
         match clear_type {
             ClearType::All => self.clear(),
-            ClearType::CurrentLine => self.event_writer.send(CCTweakedMonitorBackendEvent::ClearLine).map_err(|e| {
-                std::io::Error::new(std::io::ErrorKind::Other, format!("Failed to send event: {}", e))
-            }),
+            ClearType::CurrentLine => self.send(CCTweakedMonitorBackendEvent::ClearLine),
             ClearType::AfterCursor => unimplemented!("Not supported by cctweaked"),
             ClearType::UntilNewLine => unimplemented!("Not supported by cctweaked"),
             ClearType::BeforeCursor => unimplemented!("Not supported by cctweaked")
@@ -443,41 +444,50 @@ impl Backend for CCTweakedMonitorBackend {
     }
 
     fn window_size(&mut self) -> std::io::Result<WindowSize> {
-        Err(std::io::Error::new(std::io::ErrorKind::Other, "Not supported by computer craft, use size() instead"))
+        Err(std::io::Error::other("Not supported by computer craft, use size() instead"))
     }
 
+    /// Called once at the end of every `Terminal::draw`
     fn flush(&mut self) -> std::io::Result<()> {
-        self.flush_word()
+        self.flush_word()?;
+        self.send_frame()
     }
 }
 
 /// MonitorInputHandler is responsible for receiving Monitor events from the websocket and sending them to the terminal (like monitor_resize or click events).
 pub struct MonitorInputHandler {
     socket_reader: SplitStream<WebSocket>,
-    terminal: Arc<Mutex<Terminal<CCTweakedMonitorBackend>>>
+    terminal: Arc<Mutex<Terminal<CCTweakedMonitorBackend>>>,
+    computer: ComputerHandle,
+    connection: Connection,
+    metrics: Arc<Metrics>,
+    recorder: Option<Arc<Recorder>>,
+    // the connection is given up on if nothing, not even a pong, comes in for this long
+    heartbeat_timeout: Duration,
 }
 
 impl MonitorInputHandler {
     
-    pub fn new(socket_reader: SplitStream<WebSocket>, terminal: Arc<Mutex<Terminal<CCTweakedMonitorBackend>>>) -> Self {
+    pub fn new(socket_reader: SplitStream<WebSocket>, terminal: Arc<Mutex<Terminal<CCTweakedMonitorBackend>>>, computer: ComputerHandle, connection: Connection, metrics: Arc<Metrics>, recorder: Option<Arc<Recorder>>, heartbeat_timeout: Duration) -> Self {
         MonitorInputHandler {
             socket_reader,
-            terminal
+            terminal,
+            computer,
+            connection,
+            metrics,
+            recorder,
+            heartbeat_timeout,
         }
     }
 
-    pub async fn handle_inbound(mut self, manager_sender: UnboundedSender<InventoryReport>) {
+    /// Returns once the websocket is closed, fails or goes quiet for longer than the heartbeat timeout
+    pub async fn handle_inbound(mut self, manager_sender: Sender<InventoryReport>) -> Result<(), SessionError> {
         loop {
-            let msg = self.socket_reader.next().await;
-            let Some(msg) = msg else {
-                info!("WebSocket connection closed");
-                return;
-            };
-            let Ok(msg) = msg.map_err(|_e| {
-                info!("WebSocket connection closed (reset)"); //cctweaked isnt nice when closing websockets and just sends a stream reset, causing an error
-            }) else {
-                continue;
-            };
+            let msg = tokio::time::timeout(self.heartbeat_timeout, self.socket_reader.next()).await
+                .map_err(|_| SessionError::Timeout(self.heartbeat_timeout))?;
+            // the stream is done after an error, reading again would just spin
+            let msg = msg.ok_or(SessionError::Closed)?.map_err(SessionError::Read)?;
+            self.connection.touch();
             match msg {
                 Message::Text(text) => {
                     debug!("Received text message: {}", text);
@@ -486,6 +496,10 @@ impl MonitorInputHandler {
                     }) else {
                         continue;
                     };
+                    self.metrics.message(ConnectionRole::Monitor, Direction::In, event.kind());
+                    if let Some(recorder) = &self.recorder {
+                        recorder.inbound(&event);
+                    }
                     match event {
                         CCTweakedMonitorInputEvent::InventoryRegister { .. } => {
                             error!("Received inventory register after already spawned websocket");
@@ -497,10 +511,27 @@ impl MonitorInputHandler {
                         }
                         CCTweakedMonitorInputEvent::InventoryReport(report) => {
                             debug!("Received inventory report: {:?}", report);
-                            if let Err(e) = manager_sender.send(report) {
+                            if let Err(e) = report.validate() {
+                                error!("Dropping inventory report from {}: {}", report.common_name, e);
+                                continue;
+                            }
+                            if report.computer_id != self.connection.computer_id() {
+                                error!("Computer {} sent an inventory report for {}", self.connection.computer_id(), report.computer_id);
+                                continue;
+                            }
+                            // waits while the manager is behind, which stops reading from this computer too
+                            if let Err(e) = manager_sender.send(report).await {
                                 error!("Failed to send inventory report: {}", e);
                             }
                         }
+                        CCTweakedMonitorInputEvent::CommandAck(ack) => {
+                            debug!("Received command ack: {:?}", ack);
+                            self.computer.acknowledge(ack).await;
+                        }
+                        CCTweakedMonitorInputEvent::RpcResponse(response) => {
+                            debug!("Received rpc response: {:?}", response);
+                            self.computer.respond(response).await;
+                        }
                     }
                 }
                 Message::Binary(data) => {
@@ -508,8 +539,8 @@ impl MonitorInputHandler {
                     continue
                 }
                 Message::Close(frame) => {
-                    info!("WebSocket connection closed: {:?}", frame);
-                    return;
+                    debug!("WebSocket connection closed: {:?}", frame);
+                    return Err(SessionError::Closed);
                 }
                 _ => {}
             }
@@ -529,11 +560,37 @@ pub enum CCTweakedMonitorInputEvent {
         size: Size,
         computer_id: i64,
         common_name: String,
+        /// which view the monitor should show, the inventory view if left out
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        view: Option<MonitorView>,
+        /// left out by clients from before the protocol was versioned
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        protocol_version: Option<u32>,
+        /// for clients that can't set the token header
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        token: Option<String>,
     },
     #[serde(rename = "monitor_resize")]
     MonitorResize(Size),
     #[serde(rename = "inventory_report")]
-    InventoryReport(InventoryReport)
+    InventoryReport(InventoryReport),
+    #[serde(rename = "command_ack")]
+    CommandAck(CommandAck),
+    #[serde(rename = "rpc_response")]
+    RpcResponse(RpcResponse),
+}
+
+impl CCTweakedMonitorInputEvent {
+    /// Name of the message type on the wire
+    pub fn kind(&self) -> &'static str {
+        match self {
+            CCTweakedMonitorInputEvent::InventoryRegister { .. } => "inventory_register",
+            CCTweakedMonitorInputEvent::MonitorResize(_) => "monitor_resize",
+            CCTweakedMonitorInputEvent::InventoryReport(_) => "inventory_report",
+            CCTweakedMonitorInputEvent::CommandAck(_) => "command_ack",
+            CCTweakedMonitorInputEvent::RpcResponse(_) => "rpc_response",
+        }
+    }
 }
 
 
@@ -548,9 +605,29 @@ pub enum CCTweakedMonitorBackendEvent {
     SetTextColor(CCTweakedColor),
     SetBackgroundColor(CCTweakedColor),
     WriteText(String),
+    ServerCommand(ServerCommandRequest),
+    PeripheralCall(RpcRequest),
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+impl CCTweakedMonitorBackendEvent {
+    /// Name of the message type on the wire, text is sent as a binary message but named anyway
+    pub fn kind(&self) -> &'static str {
+        match self {
+            CCTweakedMonitorBackendEvent::HideCursor => "HideCursor",
+            CCTweakedMonitorBackendEvent::ShowCursor => "ShowCursor",
+            CCTweakedMonitorBackendEvent::ClearLine => "ClearLine",
+            CCTweakedMonitorBackendEvent::ClearScreen => "ClearScreen",
+            CCTweakedMonitorBackendEvent::SetCursorPosition(_) => "SetCursorPosition",
+            CCTweakedMonitorBackendEvent::SetTextColor(_) => "SetTextColor",
+            CCTweakedMonitorBackendEvent::SetBackgroundColor(_) => "SetBackgroundColor",
+            CCTweakedMonitorBackendEvent::WriteText(_) => "WriteText",
+            CCTweakedMonitorBackendEvent::ServerCommand(_) => "ServerCommand",
+            CCTweakedMonitorBackendEvent::PeripheralCall(_) => "PeripheralCall",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum CCTweakedColor {
     White,
     Orange,
@@ -569,6 +646,29 @@ pub enum CCTweakedColor {
     Black
 }
 
+impl CCTweakedColor {
+    /// The hex digit `term.blit` uses for the color
+    pub fn blit(self) -> char {
+        match self {
+            CCTweakedColor::White => '0',
+            CCTweakedColor::Orange => '1',
+            CCTweakedColor::Magenta => '2',
+            CCTweakedColor::LightBlue => '3',
+            CCTweakedColor::Yellow => '4',
+            CCTweakedColor::Lime => '5',
+            CCTweakedColor::Pink => '6',
+            CCTweakedColor::Gray => '7',
+            CCTweakedColor::Cyan => '9',
+            CCTweakedColor::Purple => 'a',
+            CCTweakedColor::Blue => 'b',
+            CCTweakedColor::Brown => 'c',
+            CCTweakedColor::Green => 'd',
+            CCTweakedColor::Red => 'e',
+            CCTweakedColor::Black => 'f',
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, Error)]
 pub struct CCTweakedColorConversionError(Color);
 
@@ -610,38 +710,94 @@ mod tests {
     
     #[tokio::test]
     async fn test_size() {
-        let (writer, _reader) = tokio::sync::mpsc::unbounded_channel();
+        let (writer, _reader) = tokio::sync::mpsc::channel(MONITOR_FRAME_QUEUE);
         let size = Size { width: 80, height: 25 };
-        let backend = CCTweakedMonitorBackend {
-            event_writer: writer,
-            size,
-            current_word: None
-        };
+        let backend = CCTweakedMonitorBackend::new(writer, Arc::default(), Arc::default(), size);
         assert_eq!(backend.size().unwrap(), size);
     }
     
     #[tokio::test]
     async fn test_flush() {
-        let (writer, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let (writer, mut receiver) = tokio::sync::mpsc::channel(MONITOR_FRAME_QUEUE);
         let size = Size { width: 80, height: 25 };
-        let mut current_word = Some(BufWriter::new(vec![]));
-        write!(&mut current_word.as_mut().unwrap(), "Hello").unwrap();
-        let mut backend = CCTweakedMonitorBackend {
-            event_writer: writer,
-            size,
-            current_word
-        };
+        let mut backend = CCTweakedMonitorBackend::new(writer, Arc::default(), Arc::default(), size);
+        let mut current_word = BufWriter::new(vec![]);
+        write!(&mut current_word, "Hello").unwrap();
+        backend.current_word = Some(current_word);
         let result = backend.flush();
         assert!(result.is_ok());
         assert!(backend.current_word.is_none());
-        // Check that the event was sent
-        let event = receiver.recv().await;
-        assert!(event.is_some());
-        match event.unwrap() {
-            CCTweakedMonitorBackendEvent::WriteText(text) => {
+        // Check that the frame was sent
+        let frame = receiver.recv().await;
+        assert!(frame.is_some());
+        match frame.unwrap().as_slice() {
+            [CCTweakedMonitorBackendEvent::WriteText(text)] => {
                 assert_eq!(text, "Hello");
             }
             _ => panic!("Expected WriteText event")
         }
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_congested_monitor_gets_latest_full_frame() {
+        fn text(frame: MonitorFrame) -> String {
+            frame.into_iter().filter_map(|e| match e {
+                CCTweakedMonitorBackendEvent::WriteText(word) => Some(word),
+                _ => None,
+            }).collect()
+        }
+        let (writer, mut receiver) = tokio::sync::mpsc::channel(1);
+        let backend = CCTweakedMonitorBackend::new(writer, Arc::default(), Arc::default(), Size { width: 5, height: 1 });
+        let mut terminal = Terminal::new(backend).unwrap();
+        terminal.draw(|frame| frame.render_widget("one", frame.area())).unwrap();
+        // the monitor hasn't taken the first frame yet
+        terminal.draw(|frame| frame.render_widget("two", frame.area())).unwrap();
+        terminal.draw(|frame| frame.render_widget("three", frame.area())).unwrap();
+        assert_eq!(text(receiver.recv().await.unwrap()), "one");
+        assert!(receiver.try_recv().is_err());
+
+        // nothing changed since the last draw, but the monitor missed two frames
+        terminal.draw(|frame| frame.render_widget("three", frame.area())).unwrap();
+        assert_eq!(text(receiver.recv().await.unwrap()), "three");
+        // caught up, so only what changed is sent again
+        terminal.draw(|frame| frame.render_widget("threw", frame.area())).unwrap();
+        assert_eq!(text(receiver.recv().await.unwrap()), "w");
+    }
+
+    #[tokio::test]
+    async fn test_last_word_is_sent_before_hiding_cursor() {
+        let (writer, mut receiver) = tokio::sync::mpsc::channel(MONITOR_FRAME_QUEUE);
+        let backend = CCTweakedMonitorBackend::new(writer, Arc::default(), Arc::default(), Size { width: 5, height: 1 });
+        let mut terminal = Terminal::new(backend).unwrap();
+        terminal.draw(|frame| frame.render_widget("hi", frame.area())).unwrap();
+        let frame = receiver.recv().await.unwrap();
+        let written = frame.iter().position(|e| matches!(e, CCTweakedMonitorBackendEvent::WriteText(word) if word.starts_with("hi")));
+        let hidden = frame.iter().position(|e| matches!(e, CCTweakedMonitorBackendEvent::HideCursor));
+        assert!(matches!((written, hidden), (Some(w), Some(h)) if w < h), "{:?}", frame);
+    }
+
+    #[test]
+    fn test_code_page_round_trips() {
+        for byte in 0..=u8::MAX {
+            assert_eq!(translate_char_to_cctweaked_byte(cctweaked_char(byte)), Some(byte), "{:#04x}", byte);
+        }
+        assert_eq!(translate_char_to_cctweaked_byte('¶'), Some(0xb6));
+    }
+
+    #[test]
+    fn test_translate_fallbacks() {
+        assert_eq!(translate_to_cctweaked("naïve “Łódź” ☃ ┌─┐…"), b"na\xefve \"L\xf3dz\" ? +-+.");
+        assert_eq!(translate_to_cctweaked("🬕🬂▌"), [0x97, 0x83, 0x95]);
+    }
+
+    #[test]
+    fn test_translate_keeps_one_byte_per_cell() {
+        let text = "Œuvre ĳs… cafe\u{301} ☃ 漢 ┌─┐";
+        let mut buffer = Buffer::empty(Rect::new(0, 0, 30, 1));
+        buffer.set_string(0, 0, text, ratatui::style::Style::default());
+        assert_eq!(buffer[(13, 0)].symbol(), "e\u{301}");
+        let row: String = buffer.content.iter().map(|cell| cell.symbol()).collect();
+        assert_eq!(translate_to_cctweaked(&row).len(), buffer.content.len());
+        assert_eq!(translate_to_cctweaked(&row)[..14], *b"Ouvre is. cafe");
+    }
+}
@@ -0,0 +1,454 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::{oneshot, Mutex, RwLock};
+use tracing::{debug, warn};
+
+/// How long we wait for a computer to acknowledge a command before giving up on it
+pub const COMMAND_ACK_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a peripheral call may take unless the caller asks for something else
+pub const DEFAULT_RPC_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Side of a computer that redstone can be read from or written to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RedstoneSide {
+    Top,
+    Bottom,
+    Left,
+    Right,
+    Front,
+    Back,
+}
+
+/// Commands sent from the server to a computer so it can act on the world
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[allow(clippy::enum_variant_names)] // named after the redstone API calls they perform
+pub enum ServerCommand {
+    /// `redstone.setOutput(side, on)`
+    #[serde(rename = "set_redstone_output")]
+    SetRedstoneOutput {
+        side: RedstoneSide,
+        on: bool,
+    },
+    /// `redstone.setAnalogOutput(side, strength)`, strength is 0-15
+    #[serde(rename = "set_analog_output")]
+    SetAnalogOutput {
+        side: RedstoneSide,
+        strength: u8,
+    },
+    /// `redstone.setBundledOutput(side, colors)`, replaces every color on the cable
+    #[serde(rename = "set_bundled_output")]
+    SetBundledOutput {
+        side: RedstoneSide,
+        colors: u16,
+    },
+    /// Turns the given colors on or off while leaving the rest of the cable untouched
+    #[serde(rename = "set_bundled_colors")]
+    SetBundledColors {
+        side: RedstoneSide,
+        colors: u16,
+        on: bool,
+    },
+}
+
+impl ServerCommand {
+    pub fn validate(&self) -> Result<(), CommandError> {
+        match self {
+            ServerCommand::SetAnalogOutput { strength, .. } if *strength > 15 => {
+                Err(CommandError::Invalid(format!("analog strength must be between 0 and 15, got {strength}")))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A command along with the id the computer will acknowledge it with
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerCommandRequest {
+    pub id: u64,
+    pub command: ServerCommand,
+}
+
+/// Sent by the computer once it has run (or failed to run) a command
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommandAck {
+    pub id: u64,
+    pub success: bool,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+/// Asks the computer to run `peripheral.call(peripheral, method, table.unpack(args))`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RpcRequest {
+    pub id: u64,
+    pub peripheral: String,
+    pub method: String,
+    pub args: Vec<serde_json::Value>,
+}
+
+/// Sent by the computer with the return values of a peripheral call, or why it failed
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RpcResponse {
+    pub id: u64,
+    #[serde(default, deserialize_with = "deserialize_lua_array")]
+    pub results: Vec<serde_json::Value>,
+    #[serde(default)]
+    pub error: Option<RpcFailure>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RpcFailure {
+    #[serde(rename = "no_such_peripheral")]
+    NoSuchPeripheral(String),
+    #[serde(rename = "call_failed")]
+    CallFailed(String),
+}
+
+/// More return values than any peripheral method has, so a bad index can't make a huge list
+const MAX_RPC_RESULTS: usize = 256;
+
+/// textutils.serializeJSON writes empty tables as `{}` and sparse ones as objects keyed by index,
+/// so turn those back into a list with the nils left as nulls
+fn deserialize_lua_array<'de, D>(deserializer: D) -> Result<Vec<serde_json::Value>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let value = serde_json::Value::deserialize(deserializer)?;
+    Ok(match value {
+        serde_json::Value::Array(values) => values,
+        serde_json::Value::Object(map) => {
+            let mut values = Vec::new();
+            for (key, value) in map {
+                let index = key.parse::<usize>().ok().filter(|i| (1..=MAX_RPC_RESULTS).contains(i)).ok_or_else(|| {
+                    serde::de::Error::custom(format!("expected a list, found key {:?}", key))
+                })?;
+                if values.len() < index {
+                    values.resize(index, serde_json::Value::Null);
+                }
+                values[index - 1] = value;
+            }
+            values
+        }
+        serde_json::Value::Null => Vec::new(),
+        other => vec![other],
+    })
+}
+
+/// Everything the server can ask of a computer over its websocket
+#[derive(Debug, Clone, PartialEq)]
+pub enum ComputerRequest {
+    Command(ServerCommandRequest),
+    Rpc(RpcRequest),
+}
+
+#[derive(Debug)]
+enum ComputerResponse {
+    Ack(CommandAck),
+    Rpc(RpcResponse),
+}
+
+#[derive(Debug, Clone, Error, PartialEq, Eq)]
+pub enum RpcError {
+    Disconnected,
+    Timeout,
+    UnknownComputer(i64),
+    NoSuchPeripheral(String),
+    CallFailed(String),
+    InvalidResponse(String),
+}
+
+impl Display for RpcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RpcError::Disconnected => write!(f, "Computer disconnected before answering the call"),
+            RpcError::Timeout => write!(f, "Computer did not answer the call in time"),
+            RpcError::UnknownComputer(id) => write!(f, "No computer with id {} is connected", id),
+            RpcError::NoSuchPeripheral(name) => write!(f, "Computer has no peripheral named {}", name),
+            RpcError::CallFailed(reason) => write!(f, "Peripheral call failed: {}", reason),
+            RpcError::InvalidResponse(reason) => write!(f, "Computer sent an invalid response: {}", reason),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Error, PartialEq, Eq)]
+pub enum CommandError {
+    Invalid(String),
+    Disconnected,
+    Timeout,
+    Rejected(String),
+    UnknownComputer(i64),
+}
+
+impl Display for CommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CommandError::Invalid(reason) => write!(f, "Invalid command: {}", reason),
+            CommandError::Disconnected => write!(f, "Computer disconnected before acknowledging the command"),
+            CommandError::Timeout => write!(f, "Computer did not acknowledge the command in time"),
+            CommandError::Rejected(reason) => write!(f, "Computer failed to run the command: {}", reason),
+            CommandError::UnknownComputer(id) => write!(f, "No computer with id {} is connected", id),
+        }
+    }
+}
+
+/// ComputerHandle lets the rest of the server send commands and peripheral calls to a single
+/// connected computer and wait for their results. Cloning the handle is cheap.
+#[derive(Clone)]
+pub struct ComputerHandle {
+    computer_id: i64,
+    command_writer: UnboundedSender<ComputerRequest>,
+    pending: Arc<Mutex<HashMap<u64, oneshot::Sender<ComputerResponse>>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl ComputerHandle {
+    pub fn new(computer_id: i64, command_writer: UnboundedSender<ComputerRequest>) -> Self {
+        ComputerHandle {
+            computer_id,
+            command_writer,
+            pending: Arc::new(Mutex::new(HashMap::new())),
+            next_id: Arc::new(AtomicU64::new(1)),
+        }
+    }
+
+    pub fn computer_id(&self) -> i64 {
+        self.computer_id
+    }
+
+    /// Sends a command and waits for the computer to acknowledge it
+    pub async fn send_command(&self, command: ServerCommand) -> Result<(), CommandError> {
+        command.validate()?;
+        debug!("Sending command to computer {}: {:?}", self.computer_id, command);
+        let response = self.request(
+            |id| ComputerRequest::Command(ServerCommandRequest { id, command }),
+            COMMAND_ACK_TIMEOUT,
+        ).await.map_err(|e| match e {
+            RequestError::Disconnected => CommandError::Disconnected,
+            RequestError::Timeout => CommandError::Timeout,
+        })?;
+        let ComputerResponse::Ack(ack) = response else {
+            return Err(CommandError::Rejected(String::from("computer answered a command with a call response")));
+        };
+        if ack.success {
+            Ok(())
+        } else {
+            Err(CommandError::Rejected(ack.error.unwrap_or_else(|| String::from("unknown error"))))
+        }
+    }
+
+    /// Runs `peripheral.call(peripheral, method, ...)` on the computer and returns its results
+    pub async fn call(&self, peripheral: &str, method: &str, args: Vec<serde_json::Value>) -> Result<Vec<serde_json::Value>, RpcError> {
+        self.call_with_timeout(peripheral, method, args, DEFAULT_RPC_TIMEOUT).await
+    }
+
+    pub async fn call_with_timeout(
+        &self,
+        peripheral: &str,
+        method: &str,
+        args: Vec<serde_json::Value>,
+        timeout: Duration,
+    ) -> Result<Vec<serde_json::Value>, RpcError> {
+        debug!("Calling {peripheral}.{method} on computer {}", self.computer_id);
+        let response = self.request(
+            |id| ComputerRequest::Rpc(RpcRequest {
+                id,
+                peripheral: peripheral.to_string(),
+                method: method.to_string(),
+                args,
+            }),
+            timeout,
+        ).await.map_err(|e| match e {
+            RequestError::Disconnected => RpcError::Disconnected,
+            RequestError::Timeout => RpcError::Timeout,
+        })?;
+        let ComputerResponse::Rpc(response) = response else {
+            return Err(RpcError::InvalidResponse(String::from("computer answered a call with a command ack")));
+        };
+        match response.error {
+            None => Ok(response.results),
+            Some(RpcFailure::NoSuchPeripheral(name)) => Err(RpcError::NoSuchPeripheral(name)),
+            Some(RpcFailure::CallFailed(reason)) => Err(RpcError::CallFailed(reason)),
+        }
+    }
+
+    /// Sends a request tagged with a fresh correlation id and waits for the matching response
+    async fn request(&self, build: impl FnOnce(u64) -> ComputerRequest, timeout: Duration) -> Result<ComputerResponse, RequestError> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (response_sender, response_receiver) = oneshot::channel();
+        self.pending.lock().await.insert(id, response_sender);
+        if self.command_writer.send(build(id)).is_err() {
+            self.pending.lock().await.remove(&id);
+            return Err(RequestError::Disconnected);
+        }
+        match tokio::time::timeout(timeout, response_receiver).await {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(_)) => Err(RequestError::Disconnected),
+            Err(_) => {
+                self.pending.lock().await.remove(&id);
+                Err(RequestError::Timeout)
+            }
+        }
+    }
+
+    /// Resolves the pending command the ack belongs to
+    pub async fn acknowledge(&self, ack: CommandAck) {
+        let id = ack.id;
+        self.resolve(id, ComputerResponse::Ack(ack)).await;
+    }
+
+    /// Resolves the pending peripheral call the response belongs to
+    pub async fn respond(&self, response: RpcResponse) {
+        let id = response.id;
+        self.resolve(id, ComputerResponse::Rpc(response)).await;
+    }
+
+    async fn resolve(&self, id: u64, response: ComputerResponse) {
+        let Some(sender) = self.pending.lock().await.remove(&id) else {
+            warn!("Computer {} answered unknown request {}", self.computer_id, id);
+            return;
+        };
+        // the caller may have timed out and gone away, that is fine
+        sender.send(response).ok();
+    }
+}
+
+enum RequestError {
+    Disconnected,
+    Timeout,
+}
+
+/// ComputerHandles keeps track of the command handle of every connected computer, keyed by computer id
+#[derive(Default)]
+pub struct ComputerHandles {
+    handles: RwLock<HashMap<i64, ComputerHandle>>,
+}
+
+impl ComputerHandles {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub async fn register(&self, handle: ComputerHandle) {
+        self.handles.write().await.insert(handle.computer_id(), handle);
+    }
+
+    /// Removes the handle, unless the computer has already reconnected with a new one
+    pub async fn unregister(&self, handle: &ComputerHandle) {
+        let mut guard = self.handles.write().await;
+        if guard.get(&handle.computer_id()).is_some_and(|h| Arc::ptr_eq(&h.pending, &handle.pending)) {
+            guard.remove(&handle.computer_id());
+        }
+    }
+
+    pub async fn get(&self, computer_id: i64) -> Option<ComputerHandle> {
+        self.handles.read().await.get(&computer_id).cloned()
+    }
+
+    pub async fn send_command(&self, computer_id: i64, command: ServerCommand) -> Result<(), CommandError> {
+        let Some(handle) = self.get(computer_id).await else {
+            return Err(CommandError::UnknownComputer(computer_id));
+        };
+        handle.send_command(command).await
+    }
+
+    pub async fn call(&self, computer_id: i64, peripheral: &str, method: &str, args: Vec<serde_json::Value>) -> Result<Vec<serde_json::Value>, RpcError> {
+        let Some(handle) = self.get(computer_id).await else {
+            return Err(RpcError::UnknownComputer(computer_id));
+        };
+        handle.call(peripheral, method, args).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_serialize() {
+        let request = ServerCommandRequest {
+            id: 3,
+            command: ServerCommand::SetRedstoneOutput { side: RedstoneSide::Top, on: true },
+        };
+        assert_eq!(
+            serde_json::to_string(&request).unwrap(),
+            r#"{"id":3,"command":{"set_redstone_output":{"side":"top","on":true}}}"#
+        );
+
+        let ack = serde_json::from_str::<CommandAck>(r#"{"id":3,"success":false,"error":"bad side"}"#).unwrap();
+        assert_eq!(ack, CommandAck { id: 3, success: false, error: Some("bad side".to_string()) });
+
+        // textutils.serializeJSON leaves out nil fields
+        let ack = serde_json::from_str::<CommandAck>(r#"{"id":4,"success":true}"#).unwrap();
+        assert_eq!(ack, CommandAck { id: 4, success: true, error: None });
+    }
+
+    #[tokio::test]
+    async fn test_send_command_ack() {
+        let (writer, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let handle = ComputerHandle::new(7, writer);
+        let computer = handle.clone();
+        tokio::spawn(async move {
+            let Some(ComputerRequest::Command(request)) = receiver.recv().await else { panic!("Expected command") };
+            computer.acknowledge(CommandAck { id: request.id, success: true, error: None }).await;
+            let Some(ComputerRequest::Command(request)) = receiver.recv().await else { panic!("Expected command") };
+            computer.acknowledge(CommandAck { id: request.id, success: false, error: Some("nope".to_string()) }).await;
+        });
+        let command = ServerCommand::SetAnalogOutput { side: RedstoneSide::Back, strength: 7 };
+        assert_eq!(handle.send_command(command.clone()).await, Ok(()));
+        assert_eq!(handle.send_command(command).await, Err(CommandError::Rejected("nope".to_string())));
+    }
+
+    #[tokio::test]
+    async fn test_send_command_invalid_and_disconnected() {
+        let (writer, receiver) = tokio::sync::mpsc::unbounded_channel();
+        let handle = ComputerHandle::new(7, writer);
+        let command = ServerCommand::SetAnalogOutput { side: RedstoneSide::Back, strength: 16 };
+        assert!(matches!(handle.send_command(command).await, Err(CommandError::Invalid(_))));
+        drop(receiver);
+        let command = ServerCommand::SetRedstoneOutput { side: RedstoneSide::Top, on: false };
+        assert_eq!(handle.send_command(command).await, Err(CommandError::Disconnected));
+    }
+
+    #[test]
+    fn test_deserialize_rpc_response() {
+        let response = serde_json::from_str::<RpcResponse>(r#"{"id":1,"results":[64]}"#).unwrap();
+        assert_eq!(response.results, vec![serde_json::json!(64)]);
+        let response = serde_json::from_str::<RpcResponse>(r#"{"id":1,"results":{}}"#).unwrap();
+        assert!(response.results.is_empty());
+        let response = serde_json::from_str::<RpcResponse>(r#"{"id":1,"results":{"3":true,"1":"a"}}"#).unwrap();
+        assert_eq!(response.results, vec![serde_json::json!("a"), serde_json::Value::Null, serde_json::json!(true)]);
+        assert!(serde_json::from_str::<RpcResponse>(r#"{"id":1,"results":{"name":"a"}}"#).is_err());
+        let response = serde_json::from_str::<RpcResponse>(r#"{"id":1,"error":{"no_such_peripheral":"left"}}"#).unwrap();
+        assert_eq!(response.error, Some(RpcFailure::NoSuchPeripheral("left".to_string())));
+    }
+
+    #[tokio::test]
+    async fn test_call() {
+        let (writer, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let handle = ComputerHandle::new(7, writer);
+        let computer = handle.clone();
+        tokio::spawn(async move {
+            let Some(ComputerRequest::Rpc(request)) = receiver.recv().await else { panic!("Expected call") };
+            assert_eq!(request.method, "pushItems");
+            computer.respond(RpcResponse { id: request.id, results: vec![serde_json::json!(32)], error: None }).await;
+            let Some(ComputerRequest::Rpc(request)) = receiver.recv().await else { panic!("Expected call") };
+            computer.respond(RpcResponse { id: request.id, results: vec![], error: Some(RpcFailure::CallFailed("no space".to_string())) }).await;
+            // never answer the last one
+            let _request = receiver.recv().await;
+            std::future::pending::<()>().await;
+        });
+        let args = vec![serde_json::json!("minecraft:furnace_3"), serde_json::json!(1)];
+        assert_eq!(handle.call("left", "pushItems", args.clone()).await, Ok(vec![serde_json::json!(32)]));
+        assert_eq!(handle.call("left", "pushItems", args.clone()).await, Err(RpcError::CallFailed("no space".to_string())));
+        let result = handle.call_with_timeout("left", "pushItems", args, Duration::from_millis(10)).await;
+        assert_eq!(result, Err(RpcError::Timeout));
+        assert!(handle.pending.lock().await.is_empty());
+    }
+}
@@ -0,0 +1,410 @@
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::watch;
+use tracing::warn;
+use crate::connections::{HEARTBEAT_INTERVAL, HEARTBEAT_TIMEOUT};
+use crate::inventory_manager::{ReportSettings, REPORT_RETENTION, REPORT_WINDOW, SECONDS_PER_REPORT, STALE_AFTER};
+use crate::views::{MonitorView, REDRAW_INTERVAL};
+
+/// Where the config is read from unless --config says otherwise
+pub const CONFIG_PATH: &str = "rustserver.toml";
+/// How often the config file is checked for changes
+pub const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// Environment variables overriding the config start with this, e.g. RUSTSERVER_BIND
+const ENV_PREFIX: &str = "RUSTSERVER_";
+pub const USAGE: &str = "usage: rustserver [--config path] [--bind addr] [--retention-seconds n] [--seconds-per-report n] \
+[--report-window-seconds n] [--redraw-interval-ms n] [--heartbeat-interval-seconds n] [--heartbeat-timeout-seconds n] \
+[--stale-after-seconds n] [--record-dir path] [--view computer_id=view]...
+       rustserver replay <recording.jsonl> [--colors]";
+
+/// Settings that can be given in the config file, by environment variables or on the command line,
+/// later ones winning
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// address the server listens on
+    pub bind: SocketAddr,
+    /// how long inventory reports are kept
+    pub retention_seconds: u64,
+    /// how often computers send reports, rates assume reports come in this often. The computers
+    /// don't get told, so PUBLISH_DATA_TIME in scan_items.lua and report_items.lua has to be
+    /// changed to match or every rate is off by the difference.
+    pub seconds_per_report: u64,
+    /// how far back rates are averaged over by views and the rate endpoints
+    pub report_window_seconds: u64,
+    /// how often monitor views redraw
+    pub redraw_interval_ms: u64,
+    /// how often connected computers are pinged
+    pub heartbeat_interval_seconds: u64,
+    /// how long a computer can stay silent, pongs included, before its connection is dropped
+    pub heartbeat_timeout_seconds: u64,
+    /// how long after its last report a computer's data is shown as stale
+    pub stale_after_seconds: u64,
+    /// monitor sessions are recorded to a file in here if set, see `rustserver replay`
+    pub record_dir: Option<PathBuf>,
+    /// views picked for monitors, these win over the view a computer asks for when registering
+    pub monitors: Vec<MonitorAssignment>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MonitorAssignment {
+    pub computer_id: i64,
+    pub view: MonitorView,
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    /// a command line flag or environment variable that couldn't be used
+    BadOverride {
+        name: String,
+        reason: String,
+    },
+    /// every problem found by `Config::validate`
+    Invalid(Vec<String>),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Io { path, source } => write!(f, "Failed to read config {}: {}", path.display(), source),
+            ConfigError::Parse { path, source } => write!(f, "Failed to parse config {}: {}", path.display(), source),
+            ConfigError::BadOverride { name, reason } => write!(f, "Bad value for {}: {}", name, reason),
+            ConfigError::Invalid(problems) => write!(f, "Invalid config:\n  {}", problems.join("\n  ")),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind: SocketAddr::from(([127, 0, 0, 1], 3000)),
+            retention_seconds: REPORT_RETENTION.as_secs(),
+            seconds_per_report: SECONDS_PER_REPORT,
+            report_window_seconds: REPORT_WINDOW.as_secs(),
+            redraw_interval_ms: REDRAW_INTERVAL.as_millis() as u64,
+            heartbeat_interval_seconds: HEARTBEAT_INTERVAL.as_secs(),
+            heartbeat_timeout_seconds: HEARTBEAT_TIMEOUT.as_secs(),
+            stale_after_seconds: STALE_AFTER.as_secs(),
+            record_dir: None,
+            monitors: Vec::new(),
+        }
+    }
+}
+
+/// Names of the settings that can be overridden, as used in the config file
+const OVERRIDABLE: [&str; 10] = [
+    "bind", "retention_seconds", "seconds_per_report", "report_window_seconds", "redraw_interval_ms",
+    "heartbeat_interval_seconds", "heartbeat_timeout_seconds", "stale_after_seconds", "record_dir", "views",
+];
+
+impl Config {
+    /// Reads the config file, then applies environment variables from `env` and then `args`.
+    /// A missing config file is fine unless it was asked for with --config.
+    pub fn load(mut args: impl Iterator<Item = String>, env: impl Fn(&str) -> Option<String>) -> Result<Self, ConfigError> {
+        let mut path = None;
+        let mut flags = Vec::new();
+        while let Some(arg) = args.next() {
+            let Some(name) = arg.strip_prefix("--") else {
+                return Err(ConfigError::BadOverride { name: arg, reason: String::from("expected a flag") });
+            };
+            let name = name.replace('-', "_");
+            let value = args.next().ok_or_else(|| ConfigError::BadOverride { name: arg.clone(), reason: String::from("missing value") })?;
+            match name.as_str() {
+                "config" => path = Some(PathBuf::from(value)),
+                // repeatable, so `--view 1=fleet --view 2=stock_jobs` works
+                "view" => flags.push((arg, String::from("views"), value)),
+                _ if OVERRIDABLE.contains(&name.as_str()) && name != "views" => flags.push((arg, name, value)),
+                _ => return Err(ConfigError::BadOverride { name: arg, reason: String::from("unknown flag") }),
+            }
+        }
+
+        let mut config = match &path {
+            Some(path) => Self::read(path.clone())?,
+            None => match Self::read(PathBuf::from(CONFIG_PATH)) {
+                Err(ConfigError::Io { source, .. }) if source.kind() == std::io::ErrorKind::NotFound => Config::default(),
+                result => result?,
+            },
+        };
+        for key in OVERRIDABLE {
+            let name = format!("{}{}", ENV_PREFIX, key.to_uppercase());
+            if let Some(value) = env(&name) {
+                config.set(key, &value).map_err(|reason| ConfigError::BadOverride { name, reason })?;
+            }
+        }
+        for (flag, key, value) in flags {
+            config.set(&key, &value).map_err(|reason| ConfigError::BadOverride { name: flag, reason })?;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn read(path: PathBuf) -> Result<Self, ConfigError> {
+        let data = match std::fs::read_to_string(&path) {
+            Ok(data) => data,
+            Err(source) => return Err(ConfigError::Io { path, source }),
+        };
+        toml::from_str(&data).map_err(|source| ConfigError::Parse { path, source })
+    }
+
+    /// Sets one setting from its text form. `views` takes `computer_id=view` pairs separated by commas.
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let number = || value.parse::<u64>().map_err(|e| format!("{:?} is not a whole number: {}", value, e));
+        match key {
+            "bind" => self.bind = value.parse().map_err(|e| format!("{:?} is not an address: {}", value, e))?,
+            "retention_seconds" => self.retention_seconds = number()?,
+            "seconds_per_report" => self.seconds_per_report = number()?,
+            "report_window_seconds" => self.report_window_seconds = number()?,
+            "redraw_interval_ms" => self.redraw_interval_ms = number()?,
+            "heartbeat_interval_seconds" => self.heartbeat_interval_seconds = number()?,
+            "heartbeat_timeout_seconds" => self.heartbeat_timeout_seconds = number()?,
+            "stale_after_seconds" => self.stale_after_seconds = number()?,
+            // empty turns recording off again
+            "record_dir" => self.record_dir = Some(PathBuf::from(value)).filter(|_| !value.is_empty()),
+            "views" => {
+                for pair in value.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+                    let assignment = parse_assignment(pair)?;
+                    self.monitors.retain(|m| m.computer_id != assignment.computer_id);
+                    self.monitors.push(assignment);
+                }
+            }
+            _ => return Err(format!("unknown setting {}", key)),
+        }
+        Ok(())
+    }
+
+    /// Checks the settings make sense together, collecting every problem rather than stopping at the first
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+        if self.seconds_per_report == 0 {
+            problems.push(String::from("seconds_per_report must be at least 1"));
+        }
+        if self.redraw_interval_ms == 0 {
+            problems.push(String::from("redraw_interval_ms must be at least 1"));
+        }
+        if self.heartbeat_interval_seconds == 0 {
+            problems.push(String::from("heartbeat_interval_seconds must be at least 1"));
+        }
+        if self.heartbeat_timeout_seconds <= self.heartbeat_interval_seconds {
+            problems.push(format!(
+                "heartbeat_timeout_seconds ({}) must be longer than heartbeat_interval_seconds ({}), or live computers would be dropped",
+                self.heartbeat_timeout_seconds, self.heartbeat_interval_seconds
+            ));
+        }
+        if self.stale_after_seconds < self.seconds_per_report {
+            problems.push(format!(
+                "stale_after_seconds ({}) is shorter than seconds_per_report ({}), every computer would look stale",
+                self.stale_after_seconds, self.seconds_per_report
+            ));
+        }
+        if self.report_window_seconds < self.seconds_per_report {
+            problems.push(format!(
+                "report_window_seconds ({}) is shorter than seconds_per_report ({}), no report would ever be in the window",
+                self.report_window_seconds, self.seconds_per_report
+            ));
+        }
+        if self.retention_seconds < self.report_window_seconds {
+            problems.push(format!(
+                "retention_seconds ({}) is shorter than report_window_seconds ({}), reports would be dropped before the window ends",
+                self.retention_seconds, self.report_window_seconds
+            ));
+        }
+        for (i, monitor) in self.monitors.iter().enumerate() {
+            if self.monitors[..i].iter().any(|m| m.computer_id == monitor.computer_id) {
+                problems.push(format!("computer {} is assigned a view more than once", monitor.computer_id));
+            }
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(problems))
+        }
+    }
+
+    pub fn report_settings(&self) -> ReportSettings {
+        ReportSettings {
+            seconds_per_report: self.seconds_per_report,
+            retention: Duration::from_secs(self.retention_seconds),
+        }
+    }
+
+    pub fn report_window(&self) -> Duration {
+        Duration::from_secs(self.report_window_seconds)
+    }
+
+    pub fn redraw_interval(&self) -> Duration {
+        Duration::from_millis(self.redraw_interval_ms)
+    }
+
+    pub fn heartbeat_interval(&self) -> Duration {
+        Duration::from_secs(self.heartbeat_interval_seconds)
+    }
+
+    pub fn heartbeat_timeout(&self) -> Duration {
+        Duration::from_secs(self.heartbeat_timeout_seconds)
+    }
+
+    pub fn stale_after(&self) -> Duration {
+        Duration::from_secs(self.stale_after_seconds)
+    }
+
+    /// The view assigned to a computer in the config, if any
+    pub fn view_for(&self, computer_id: i64) -> Option<MonitorView> {
+        self.monitors.iter().find(|m| m.computer_id == computer_id).map(|m| m.view)
+    }
+}
+
+/// The config currently in use. It is reloaded from the same file, environment and flags it was
+/// first loaded from, and every change is sent to subscribers.
+pub struct LiveConfig {
+    args: Vec<String>,
+    sender: watch::Sender<Arc<Config>>,
+}
+
+impl LiveConfig {
+    pub fn load(args: Vec<String>) -> Result<Self, ConfigError> {
+        let config = Config::load(args.iter().cloned(), |name| std::env::var(name).ok())?;
+        let (sender, _) = watch::channel(Arc::new(config));
+        Ok(LiveConfig { args, sender })
+    }
+
+    pub fn current(&self) -> Arc<Config> {
+        self.sender.borrow().clone()
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<Arc<Config>> {
+        self.sender.subscribe()
+    }
+
+    /// The file the config is read from
+    pub fn path(&self) -> PathBuf {
+        self.args.iter()
+            .position(|arg| arg == "--config")
+            .and_then(|i| self.args.get(i + 1))
+            .map_or_else(|| PathBuf::from(CONFIG_PATH), PathBuf::from)
+    }
+
+    /// Loads the config again, keeping the current one if the new one doesn't load or validate.
+    /// Returns whether anything changed.
+    pub fn reload(&self) -> Result<bool, ConfigError> {
+        let config = Config::load(self.args.iter().cloned(), |name| std::env::var(name).ok())?;
+        Ok(self.sender.send_if_modified(|current| {
+            if **current == config {
+                return false;
+            }
+            if current.bind != config.bind {
+                warn!("The server keeps listening on {} until it is restarted", current.bind);
+            }
+            *current = Arc::new(config);
+            true
+        }))
+    }
+}
+
+fn parse_assignment(pair: &str) -> Result<MonitorAssignment, String> {
+    let (computer_id, view) = pair.split_once('=').ok_or_else(|| format!("expected computer_id=view, got {:?}", pair))?;
+    let computer_id = computer_id.trim().parse().map_err(|e| format!("{:?} is not a computer id: {}", computer_id, e))?;
+    let view = toml::Value::String(view.trim().to_string()).try_into()
+        .map_err(|_| format!("{:?} is not a view", view.trim()))?;
+    Ok(MonitorAssignment { computer_id, view })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_with_overrides() {
+        let path = std::env::temp_dir().join(format!("config_test_{}.toml", std::process::id()));
+        std::fs::write(&path, r#"
+bind = "0.0.0.0:4000"
+seconds_per_report = 10
+
+[[monitors]]
+computer_id = 3
+view = "fleet"
+"#).unwrap();
+        let args = ["--config", path.to_str().unwrap(), "--redraw-interval-ms", "500", "--view", "4=stock_jobs", "--record-dir", "recordings"];
+        let env = |name: &str| match name {
+            "RUSTSERVER_SECONDS_PER_REPORT" => Some(String::from("20")),
+            "RUSTSERVER_REDRAW_INTERVAL_MS" => Some(String::from("250")),
+            "RUSTSERVER_VIEWS" => Some(String::from("3=inventory")),
+            _ => None,
+        };
+        let config = Config::load(args.into_iter().map(String::from), env).unwrap();
+        std::fs::remove_file(&path).ok();
+        assert_eq!(config.bind, "0.0.0.0:4000".parse().unwrap());
+        assert_eq!(config.seconds_per_report, 20);
+        assert_eq!(config.redraw_interval(), Duration::from_millis(500));
+        assert_eq!(config.report_window(), REPORT_WINDOW);
+        assert_eq!(config.view_for(3), Some(MonitorView::Inventory));
+        assert_eq!(config.view_for(4), Some(MonitorView::StockJobs));
+        assert_eq!(config.view_for(5), None);
+        assert_eq!(config.record_dir, Some(PathBuf::from("recordings")));
+
+        let path = std::env::temp_dir().join(format!("config_test_empty_{}.toml", std::process::id()));
+        std::fs::write(&path, "").unwrap();
+        let args = ["--config", path.to_str().unwrap(), "--bind", "nowhere"];
+        let result = Config::load(args.into_iter().map(String::from), |_| None);
+        std::fs::remove_file(&path).ok();
+        assert!(matches!(result, Err(ConfigError::BadOverride { .. })));
+        let missing = std::env::temp_dir().join(format!("config_test_missing_{}.toml", std::process::id()));
+        assert!(matches!(Config::load(["--config", missing.to_str().unwrap()].into_iter().map(String::from), |_| None), Err(ConfigError::Io { .. })));
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(Config::default().validate().is_ok());
+        let config = Config {
+            seconds_per_report: 0,
+            report_window_seconds: 60,
+            retention_seconds: 30,
+            monitors: vec![
+                MonitorAssignment { computer_id: 1, view: MonitorView::Fleet },
+                MonitorAssignment { computer_id: 1, view: MonitorView::Inventory },
+            ],
+            ..Config::default()
+        };
+        let Err(ConfigError::Invalid(problems)) = config.validate() else {
+            panic!("expected the config to be invalid");
+        };
+        assert_eq!(problems.len(), 3);
+        assert!(problems[2].contains("computer 1"));
+    }
+
+    #[test]
+    fn test_reload() {
+        let path = std::env::temp_dir().join(format!("live_config_test_{}.toml", std::process::id()));
+        std::fs::write(&path, "redraw_interval_ms = 500\n").unwrap();
+        let config = LiveConfig::load(vec![String::from("--config"), path.to_str().unwrap().to_string()]).unwrap();
+        assert_eq!(config.path(), path);
+        let mut receiver = config.subscribe();
+        assert!(!config.reload().unwrap());
+        assert!(!receiver.has_changed().unwrap());
+
+        std::fs::write(&path, "redraw_interval_ms = 250\n").unwrap();
+        assert!(config.reload().unwrap());
+        assert!(receiver.has_changed().unwrap());
+        assert_eq!(receiver.borrow_and_update().redraw_interval_ms, 250);
+
+        // a broken config leaves the running one alone
+        std::fs::write(&path, "redraw_interval_ms = 0\n").unwrap();
+        assert!(matches!(config.reload(), Err(ConfigError::Invalid(_))));
+        std::fs::remove_file(&path).ok();
+        assert_eq!(config.current().redraw_interval_ms, 250);
+        assert!(!receiver.has_changed().unwrap());
+    }
+}
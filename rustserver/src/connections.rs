@@ -0,0 +1,227 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+use crate::events::{EventBus, ServerEvent};
+use crate::turtle_manager::now_seconds;
+
+/// How often connected computers are pinged unless configured otherwise
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+/// How long a computer can stay silent before its connection is dropped unless configured otherwise
+pub const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// What a computer connected to the server as
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectionRole {
+    Monitor,
+    Turtle,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConnectionInfo {
+    /// unique per connection, a computer reconnecting gets a new one
+    pub connection_id: u64,
+    pub computer_id: i64,
+    /// common name of a monitor or label of a turtle
+    pub name: Option<String>,
+    pub role: ConnectionRole,
+    pub peer_addr: SocketAddr,
+    pub user_agent: Option<String>,
+    /// seconds since the unix epoch
+    pub connected_at: u64,
+    /// seconds since the unix epoch
+    pub last_message_at: u64,
+    /// None for clients from before the protocol was versioned
+    pub protocol_version: Option<u32>,
+    /// frames drawn but not yet sent, only monitors queue frames
+    pub queue_depth: usize,
+    /// frames skipped because the monitor couldn't keep up
+    pub dropped_frames: u64,
+}
+
+/// Counters for the frames queued up for a monitor, shared by the session and the registry
+#[derive(Debug, Default)]
+pub struct QueueStats {
+    depth: AtomicUsize,
+    dropped: AtomicU64,
+}
+
+impl QueueStats {
+    pub fn pushed(&self) {
+        self.depth.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn popped(&self) {
+        self.depth.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn dropped(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+struct Entry {
+    info: ConnectionInfo,
+    last_message_at: Arc<AtomicU64>,
+    queue: Arc<QueueStats>,
+}
+
+/// ConnectionRegistry keeps track of every computer currently connected over a websocket
+pub struct ConnectionRegistry {
+    connections: RwLock<HashMap<u64, Entry>>,
+    next_id: AtomicU64,
+    events: Arc<EventBus>,
+}
+
+/// Handle to a registered connection, held by the connection's tasks
+#[derive(Clone)]
+pub struct Connection {
+    id: u64,
+    computer_id: i64,
+    last_message_at: Arc<AtomicU64>,
+    queue: Arc<QueueStats>,
+    registry: Arc<ConnectionRegistry>,
+}
+
+impl Connection {
+    /// Records that a message just came in
+    pub fn touch(&self) {
+        self.last_message_at.store(now_seconds(), Ordering::Relaxed);
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn computer_id(&self) -> i64 {
+        self.computer_id
+    }
+
+    /// Counters the session keeps up to date as it queues frames
+    pub fn queue(&self) -> Arc<QueueStats> {
+        self.queue.clone()
+    }
+
+    pub async fn unregister(&self) {
+        let Some(entry) = self.registry.connections.write().await.remove(&self.id) else {
+            return;
+        };
+        self.registry.events.publish(ServerEvent::Disconnected {
+            connection_id: self.id,
+            computer_id: entry.info.computer_id,
+        });
+    }
+}
+
+impl ConnectionRegistry {
+    pub fn new(events: Arc<EventBus>) -> Self {
+        ConnectionRegistry {
+            connections: RwLock::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+            events,
+        }
+    }
+
+    /// `connection_id`, `connected_at`, `last_message_at` and the queue counters of `info` are filled in here
+    pub async fn register(self: &Arc<Self>, mut info: ConnectionInfo) -> Connection {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let now = now_seconds();
+        info.connection_id = id;
+        info.connected_at = now;
+        info.last_message_at = now;
+        info.queue_depth = 0;
+        info.dropped_frames = 0;
+        let last_message_at = Arc::new(AtomicU64::new(now));
+        let queue = Arc::new(QueueStats::default());
+        let computer_id = info.computer_id;
+        self.events.publish(ServerEvent::Connected(info.clone()));
+        self.connections.write().await.insert(id, Entry { info, last_message_at: last_message_at.clone(), queue: queue.clone() });
+        Connection { id, computer_id, last_message_at, queue, registry: self.clone() }
+    }
+
+    /// Every open connection, sorted by computer id
+    pub async fn list(&self) -> Vec<ConnectionInfo> {
+        let mut connections: Vec<_> = self.connections.read().await.values().map(Entry::snapshot).collect();
+        connections.sort_by_key(|c| (c.computer_id, c.connection_id));
+        connections
+    }
+
+    /// Open connections of one computer, a turtle with a monitor attached can have more than one
+    pub async fn get(&self, computer_id: i64) -> Vec<ConnectionInfo> {
+        let mut connections: Vec<_> = self.connections.read().await.values()
+            .filter(|e| e.info.computer_id == computer_id)
+            .map(Entry::snapshot)
+            .collect();
+        connections.sort_by_key(|c| c.connection_id);
+        connections
+    }
+}
+
+impl Entry {
+    fn snapshot(&self) -> ConnectionInfo {
+        ConnectionInfo {
+            last_message_at: self.last_message_at.load(Ordering::Relaxed),
+            queue_depth: self.queue.depth.load(Ordering::Relaxed),
+            dropped_frames: self.queue.dropped.load(Ordering::Relaxed),
+            ..self.info.clone()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(computer_id: i64, role: ConnectionRole) -> ConnectionInfo {
+        ConnectionInfo {
+            connection_id: 0,
+            computer_id,
+            name: None,
+            role,
+            peer_addr: "127.0.0.1:1234".parse().unwrap(),
+            user_agent: Some("computercraft/1.110.0".to_string()),
+            connected_at: 0,
+            last_message_at: 0,
+            protocol_version: Some(1),
+            queue_depth: 0,
+            dropped_frames: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_register_and_unregister() {
+        let events = Arc::new(EventBus::new());
+        let mut receiver = events.subscribe();
+        let registry = Arc::new(ConnectionRegistry::new(events));
+        let monitor = registry.register(info(3, ConnectionRole::Monitor)).await;
+        let turtle = registry.register(info(3, ConnectionRole::Turtle)).await;
+        registry.register(info(1, ConnectionRole::Monitor)).await;
+
+        assert_eq!(registry.list().await.iter().map(|c| c.computer_id).collect::<Vec<_>>(), vec![1, 3, 3]);
+        let connections = registry.get(3).await;
+        assert_eq!(connections.iter().map(|c| c.role).collect::<Vec<_>>(), vec![ConnectionRole::Monitor, ConnectionRole::Turtle]);
+        assert!(connections[0].connected_at > 0);
+
+        monitor.touch();
+        monitor.queue().pushed();
+        monitor.queue().pushed();
+        monitor.queue().popped();
+        monitor.queue().dropped();
+        let connections = registry.get(3).await;
+        assert_eq!((connections[0].queue_depth, connections[0].dropped_frames), (1, 1));
+        assert_eq!((connections[1].queue_depth, connections[1].dropped_frames), (0, 0));
+        monitor.unregister().await;
+        turtle.unregister().await;
+        assert!(registry.get(3).await.is_empty());
+        assert_eq!(registry.list().await.len(), 1);
+
+        assert!(matches!(receiver.recv().await.unwrap(), ServerEvent::Connected(ConnectionInfo { computer_id: 3, .. })));
+        let events: Vec<_> = std::iter::from_fn(|| receiver.try_recv().ok()).collect();
+        assert_eq!(events.len(), 4);
+        assert_eq!(events[3], ServerEvent::Disconnected { connection_id: turtle.id, computer_id: 3 });
+    }
+}
@@ -0,0 +1,317 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use crate::inventory_manager::InventoryItemCount;
+
+/// A way of making `count` of `output` out of `ingredients`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Recipe {
+    pub output: String,
+    /// how many items one craft produces
+    #[serde(default = "default_recipe_count")]
+    pub count: i64,
+    /// item name -> how many of it one craft uses
+    pub ingredients: HashMap<String, i64>,
+    /// what has to do the craft, i.e `crafting_table` or `furnace`
+    #[serde(default = "default_recipe_machine")]
+    pub machine: String,
+}
+
+fn default_recipe_count() -> i64 {
+    1
+}
+
+fn default_recipe_machine() -> String {
+    String::from("crafting_table")
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RecipeBook {
+    recipes: HashMap<String, Recipe>,
+}
+
+#[derive(Debug, Error)]
+pub enum RecipeError {
+    Io(#[from] std::io::Error),
+    Parse(#[from] serde_json::Error),
+    Invalid(String),
+}
+
+impl Display for RecipeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RecipeError::Io(e) => write!(f, "Failed to read recipes: {}", e),
+            RecipeError::Parse(e) => write!(f, "Failed to parse recipes: {}", e),
+            RecipeError::Invalid(reason) => write!(f, "Invalid recipe: {}", reason),
+        }
+    }
+}
+
+impl RecipeBook {
+    pub fn new(recipes: Vec<Recipe>) -> Result<Self, RecipeError> {
+        let mut book = RecipeBook::default();
+        for recipe in recipes {
+            if recipe.count <= 0 {
+                return Err(RecipeError::Invalid(format!("{} must produce at least one item", recipe.output)));
+            }
+            if let Some((name, count)) = recipe.ingredients.iter().find(|(_, count)| **count <= 0) {
+                return Err(RecipeError::Invalid(format!("{} uses {} of {}", recipe.output, count, name)));
+            }
+            if book.recipes.contains_key(&recipe.output) {
+                return Err(RecipeError::Invalid(format!("{} has more than one recipe", recipe.output)));
+            }
+            book.recipes.insert(recipe.output.clone(), recipe);
+        }
+        Ok(book)
+    }
+
+    /// Loads a json list of recipes
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, RecipeError> {
+        let data = std::fs::read_to_string(path)?;
+        Self::new(serde_json::from_str(&data)?)
+    }
+
+    pub fn get(&self, item: &str) -> Option<&Recipe> {
+        self.recipes.get(item)
+    }
+
+    pub fn len(&self) -> usize {
+        self.recipes.len()
+    }
+}
+
+/// One batch of crafts to hand to a crafting turtle
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CraftStep {
+    pub item: String,
+    pub machine: String,
+    /// how many times the recipe has to be run
+    pub crafts: i64,
+    /// how many items those crafts produce, may be more than needed
+    pub produces: i64,
+    /// ingredients used by all the crafts together
+    pub ingredients: Vec<InventoryItemCount>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CraftingPlan {
+    pub item: String,
+    pub count: i64,
+    /// in the order they have to be run, every step only needs items from stock or earlier steps
+    pub steps: Vec<CraftStep>,
+    /// items taken out of storage
+    pub from_stock: Vec<InventoryItemCount>,
+    /// raw materials we have no recipe for and not enough of
+    pub missing: Vec<InventoryItemCount>,
+}
+
+impl CraftingPlan {
+    pub fn is_craftable(&self) -> bool {
+        self.missing.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Error, PartialEq, Eq)]
+pub enum PlanError {
+    InvalidCount(i64),
+    RecipeCycle(Vec<String>),
+    /// planning needs more of an item than can be counted
+    TooMany(String),
+}
+
+impl Display for PlanError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PlanError::InvalidCount(count) => write!(f, "Can't craft {} items", count),
+            PlanError::RecipeCycle(chain) => write!(f, "Recipes loop back on themselves: {}", chain.join(" -> ")),
+            PlanError::TooMany(item) => write!(f, "Too many {} needed to plan for", item),
+        }
+    }
+}
+
+/// Works out how to get `count` of `item` given what is in `stock`. Items in stock are used
+/// before anything gets crafted, and leftovers from earlier crafts are reused by later ones.
+pub fn plan(book: &RecipeBook, stock: &HashMap<String, i64>, item: &str, count: i64) -> Result<CraftingPlan, PlanError> {
+    if count <= 0 {
+        return Err(PlanError::InvalidCount(count));
+    }
+    let mut planner = Planner {
+        book,
+        stock: stock.clone(),
+        from_stock: HashMap::new(),
+        missing: HashMap::new(),
+        steps: Vec::new(),
+        chain: Vec::new(),
+    };
+    planner.require(item, count, true)?;
+
+    Ok(CraftingPlan {
+        item: item.to_string(),
+        count,
+        steps: planner.steps,
+        from_stock: sorted_counts(planner.from_stock),
+        missing: sorted_counts(planner.missing),
+    })
+}
+
+struct Planner<'a> {
+    book: &'a RecipeBook,
+    /// what is left in storage (plus leftovers of crafts) as the plan gets built
+    stock: HashMap<String, i64>,
+    from_stock: HashMap<String, i64>,
+    missing: HashMap<String, i64>,
+    steps: Vec<CraftStep>,
+    /// items currently being expanded, used to spot recipe cycles
+    chain: Vec<String>,
+}
+
+impl Planner<'_> {
+    fn require(&mut self, item: &str, count: i64, is_target: bool) -> Result<(), PlanError> {
+        let mut needed = count;
+        if !is_target {
+            let available = self.stock.get(item).copied().unwrap_or(0).max(0);
+            let used = available.min(needed);
+            if used > 0 {
+                *self.stock.entry(item.to_string()).or_insert(0) -= used;
+                *self.from_stock.entry(item.to_string()).or_insert(0) += used;
+                needed -= used;
+            }
+        }
+        if needed == 0 {
+            return Ok(());
+        }
+        let too_many = || PlanError::TooMany(item.to_string());
+        let Some(recipe) = self.book.get(item) else {
+            let missing = self.missing.entry(item.to_string()).or_insert(0);
+            *missing = missing.checked_add(needed).ok_or_else(too_many)?;
+            return Ok(());
+        };
+        if self.chain.iter().any(|i| i == item) {
+            let mut chain = self.chain.clone();
+            chain.push(item.to_string());
+            return Err(PlanError::RecipeCycle(chain));
+        }
+
+        self.chain.push(item.to_string());
+        // rounded up without `needed + count - 1`, which could overflow
+        let crafts = needed / recipe.count + i64::from(needed % recipe.count != 0);
+        let mut ingredients = Vec::new();
+        for (name, per_craft) in &recipe.ingredients {
+            let count = per_craft.checked_mul(crafts).ok_or_else(|| PlanError::TooMany(name.clone()))?;
+            ingredients.push(InventoryItemCount { name: name.clone(), count });
+        }
+        ingredients.sort_by(|a, b| a.name.cmp(&b.name));
+        for ingredient in &ingredients {
+            self.require(&ingredient.name, ingredient.count, false)?;
+        }
+        self.chain.pop();
+
+        let produces = crafts.checked_mul(recipe.count).ok_or_else(too_many)?;
+        if produces > needed {
+            *self.stock.entry(item.to_string()).or_insert(0) += produces - needed;
+        }
+        self.steps.push(CraftStep {
+            item: item.to_string(),
+            machine: recipe.machine.clone(),
+            crafts,
+            produces,
+            ingredients,
+        });
+        Ok(())
+    }
+}
+
+fn sorted_counts(counts: HashMap<String, i64>) -> Vec<InventoryItemCount> {
+    let mut counts: Vec<_> = counts.into_iter()
+        .map(|(name, count)| InventoryItemCount { name, count })
+        .collect();
+    counts.sort_by(|a, b| a.name.cmp(&b.name));
+    counts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recipe(output: &str, count: i64, ingredients: &[(&str, i64)]) -> Recipe {
+        Recipe {
+            output: output.to_string(),
+            count,
+            ingredients: ingredients.iter().map(|(name, count)| (name.to_string(), *count)).collect(),
+            machine: default_recipe_machine(),
+        }
+    }
+
+    fn book() -> RecipeBook {
+        RecipeBook::new(vec![
+            recipe("planks", 4, &[("log", 1)]),
+            recipe("stick", 4, &[("planks", 2)]),
+            recipe("chest", 1, &[("planks", 8)]),
+            recipe("piston", 1, &[("planks", 3), ("cobblestone", 4), ("iron_ingot", 1), ("redstone", 1)]),
+        ]).unwrap()
+    }
+
+    fn stock(items: &[(&str, i64)]) -> HashMap<String, i64> {
+        items.iter().map(|(name, count)| (name.to_string(), *count)).collect()
+    }
+
+    fn count(name: &str, count: i64) -> InventoryItemCount {
+        InventoryItemCount { name: name.to_string(), count }
+    }
+
+    #[test]
+    fn test_plan_expands_sub_recipes() {
+        let plan = super::plan(&book(), &stock(&[("log", 10), ("cobblestone", 64), ("iron_ingot", 2), ("redstone", 1)]), "piston", 2).unwrap();
+        assert_eq!(plan.steps.iter().map(|s| (s.item.as_str(), s.crafts)).collect::<Vec<_>>(), vec![("planks", 2), ("piston", 2)]);
+        assert_eq!(plan.from_stock, vec![count("cobblestone", 8), count("iron_ingot", 2), count("log", 2), count("redstone", 1)]);
+        assert_eq!(plan.missing, vec![count("redstone", 1)]);
+        assert!(!plan.is_craftable());
+    }
+
+    #[test]
+    fn test_plan_reuses_stock_and_leftovers() {
+        // 2 chests need 16 planks, 6 are in stock, so only 10 have to be crafted (3 crafts -> 12)
+        let plan = super::plan(&book(), &stock(&[("planks", 6), ("log", 5)]), "chest", 2).unwrap();
+        assert_eq!(plan.steps[0], CraftStep {
+            item: "planks".to_string(),
+            machine: "crafting_table".to_string(),
+            crafts: 3,
+            produces: 12,
+            ingredients: vec![count("log", 3)],
+        });
+        assert!(plan.is_craftable());
+
+        // the target itself is always crafted, even if some are already in stock
+        let plan = super::plan(&book(), &stock(&[("stick", 64), ("log", 1)]), "stick", 4).unwrap();
+        assert_eq!(plan.steps.len(), 2);
+    }
+
+    #[test]
+    fn test_plan_errors() {
+        let cyclic = RecipeBook::new(vec![
+            recipe("iron_block", 1, &[("iron_ingot", 9)]),
+            recipe("iron_ingot", 9, &[("iron_block", 1)]),
+        ]).unwrap();
+        assert_eq!(
+            super::plan(&cyclic, &HashMap::new(), "iron_block", 1),
+            Err(PlanError::RecipeCycle(vec!["iron_block".to_string(), "iron_ingot".to_string(), "iron_block".to_string()]))
+        );
+        assert_eq!(super::plan(&book(), &HashMap::new(), "chest", 0), Err(PlanError::InvalidCount(0)));
+        assert!(matches!(super::plan(&book(), &HashMap::new(), "chest", i64::MAX), Err(PlanError::TooMany(_))));
+        assert!(RecipeBook::new(vec![recipe("chest", 0, &[])]).is_err());
+    }
+
+    #[test]
+    fn test_deserialize_recipes() {
+        let recipes: Vec<Recipe> = serde_json::from_str(r#"[
+            {"output": "minecraft:iron_ingot", "ingredients": {"minecraft:raw_iron": 1}, "machine": "furnace"},
+            {"output": "minecraft:stick", "count": 4, "ingredients": {"minecraft:oak_planks": 2}}
+        ]"#).unwrap();
+        let book = RecipeBook::new(recipes).unwrap();
+        assert_eq!(book.len(), 2);
+        assert_eq!(book.get("minecraft:iron_ingot").unwrap().count, 1);
+        assert_eq!(book.get("minecraft:stick").unwrap().machine, "crafting_table");
+    }
+}
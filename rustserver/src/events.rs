@@ -0,0 +1,140 @@
+use std::fmt::Display;
+use std::str::FromStr;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use crate::connections::ConnectionInfo;
+use crate::inventory_manager::{ComputerRates, InventoryReport};
+
+/// How many events a slow subscriber can fall behind before it starts missing some
+pub const EVENT_BUFFER: usize = 256;
+
+/// What a subscriber can filter events on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventTopic {
+    Reports,
+    Connections,
+    Alerts,
+    Rates,
+}
+
+impl EventTopic {
+    pub const ALL: [EventTopic; 4] = [EventTopic::Reports, EventTopic::Connections, EventTopic::Alerts, EventTopic::Rates];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventTopic::Reports => "reports",
+            EventTopic::Connections => "connections",
+            EventTopic::Alerts => "alerts",
+            EventTopic::Rates => "rates",
+        }
+    }
+}
+
+impl FromStr for EventTopic {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        EventTopic::ALL.into_iter()
+            .find(|topic| topic.name() == s)
+            .ok_or_else(|| format!("Unknown topic `{}`", s))
+    }
+}
+
+impl Display for EventTopic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Something that happened on the server that outside tools may want to hear about
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ServerEvent {
+    Report(InventoryReport),
+    Connected(ConnectionInfo),
+    Disconnected {
+        connection_id: u64,
+        computer_id: i64,
+    },
+    Alert {
+        /// what raised the alert, i.e. `stock_keeper`
+        source: String,
+        message: String,
+    },
+    Rates(Vec<ComputerRates>),
+}
+
+impl ServerEvent {
+    pub fn topic(&self) -> EventTopic {
+        match self {
+            ServerEvent::Report(_) => EventTopic::Reports,
+            ServerEvent::Connected(_) | ServerEvent::Disconnected { .. } => EventTopic::Connections,
+            ServerEvent::Alert { .. } => EventTopic::Alerts,
+            ServerEvent::Rates(_) => EventTopic::Rates,
+        }
+    }
+
+    pub fn alert(source: &str, message: impl Into<String>) -> Self {
+        ServerEvent::Alert { source: source.to_string(), message: message.into() }
+    }
+}
+
+/// EventBus fans [ServerEvent]s out to every subscriber
+pub struct EventBus {
+    sender: broadcast::Sender<ServerEvent>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        EventBus { sender: broadcast::channel(EVENT_BUFFER).0 }
+    }
+
+    /// Events published while nobody is subscribed are dropped
+    pub fn publish(&self, event: ServerEvent) {
+        self.sender.send(event).ok();
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<ServerEvent> {
+        self.sender.subscribe()
+    }
+
+    /// Lets publishers skip building events nobody will see
+    pub fn has_subscribers(&self) -> bool {
+        self.sender.receiver_count() > 0
+    }
+}
+
+/// Parses a comma separated list of topics, all topics if `topics` is None or empty
+pub fn parse_topics(topics: Option<&str>) -> Result<Vec<EventTopic>, String> {
+    let Some(topics) = topics.filter(|t| !t.is_empty()) else {
+        return Ok(EventTopic::ALL.to_vec());
+    };
+    topics.split(',').map(|topic| topic.trim().parse()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_publish_and_filter() {
+        assert_eq!(parse_topics(None).unwrap().len(), 4);
+        assert_eq!(parse_topics(Some("alerts, rates")).unwrap(), vec![EventTopic::Alerts, EventTopic::Rates]);
+        assert!(parse_topics(Some("alerts,weather")).is_err());
+
+        let bus = EventBus::new();
+        assert!(!bus.has_subscribers());
+        bus.publish(ServerEvent::alert("test", "nobody hears this"));
+        let mut receiver = bus.subscribe();
+        bus.publish(ServerEvent::Disconnected { connection_id: 1, computer_id: 2 });
+        let event = receiver.recv().await.unwrap();
+        assert_eq!(event.topic(), EventTopic::Connections);
+        assert_eq!(serde_json::to_string(&event).unwrap(), r#"{"Disconnected":{"connection_id":1,"computer_id":2}}"#);
+    }
+}
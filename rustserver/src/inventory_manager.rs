@@ -1,11 +1,41 @@
 use std::cmp::Ordering;
 use std::collections::{HashMap, VecDeque};
+use std::fmt::Display;
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+use std::sync::Arc;
 use std::time::Duration;
-use tokio::sync::mpsc::UnboundedSender;
+use thiserror::Error;
+use tokio::sync::mpsc::{Receiver, Sender};
 use tokio::sync::RwLock;
 use tokio::time::Instant;
+use crate::events::{EventBus, ServerEvent};
 
+/// How often computers send reports unless configured otherwise
 pub const SECONDS_PER_REPORT: u64 = 5;
+/// How far back rates are averaged over unless asked otherwise
+pub const REPORT_WINDOW: Duration = Duration::from_secs(5 * 60);
+/// How long reports are kept unless configured otherwise
+pub const REPORT_RETENTION: Duration = Duration::from_secs(30 * 60);
+/// How long after its last report a computer is considered stale unless configured otherwise
+pub const STALE_AFTER: Duration = Duration::from_secs(60);
+/// How many reports can wait for the manager before senders have to wait
+pub const REPORT_QUEUE: usize = 256;
+
+/// How the manager turns reports into rates and how long it keeps them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReportSettings {
+    pub seconds_per_report: u64,
+    pub retention: Duration,
+}
+
+impl Default for ReportSettings {
+    fn default() -> Self {
+        ReportSettings {
+            seconds_per_report: SECONDS_PER_REPORT,
+            retention: REPORT_RETENTION,
+        }
+    }
+}
 
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone, PartialEq, Eq)]
 pub struct InventoryReport {
@@ -16,6 +46,91 @@ pub struct InventoryReport {
     pub inventory_type: InventoryType,
 }
 
+/// Why a report was turned away
+#[derive(Debug, Clone, Error, PartialEq, Eq, serde::Serialize)]
+#[serde(tag = "error", rename_all = "snake_case")]
+pub enum ReportError {
+    /// the body wasn't a valid report
+    Malformed {
+        reason: String,
+    },
+    EmptyField {
+        field: String,
+    },
+    InvalidComputerId {
+        computer_id: i64,
+    },
+    InvalidSlot {
+        slot: i64,
+    },
+    DuplicateSlot {
+        slot: i64,
+    },
+    InvalidCount {
+        slot: i64,
+        count: i64,
+    },
+    /// the manager isn't taking reports anymore, the server is shutting down
+    Unavailable,
+    /// the computer's token is missing or doesn't match
+    Unauthorized {
+        reason: String,
+    },
+}
+
+impl Display for ReportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReportError::Malformed { reason } => write!(f, "Malformed report: {}", reason),
+            ReportError::EmptyField { field } => write!(f, "{} must not be empty", field),
+            ReportError::InvalidComputerId { computer_id } => write!(f, "Invalid computer id {}", computer_id),
+            ReportError::InvalidSlot { slot } => write!(f, "Invalid slot {}", slot),
+            ReportError::DuplicateSlot { slot } => write!(f, "Slot {} is reported more than once", slot),
+            ReportError::InvalidCount { slot, count } => write!(f, "Slot {} has an invalid count of {}", slot, count),
+            ReportError::Unavailable => write!(f, "Inventory manager is not running"),
+            ReportError::Unauthorized { reason } => write!(f, "Not allowed to report: {}", reason),
+        }
+    }
+}
+
+impl InventoryReport {
+    /// Checks the report makes sense before it gets near the manager
+    pub fn validate(&self) -> Result<(), ReportError> {
+        let empty = |field: &str| Err(ReportError::EmptyField { field: field.to_string() });
+        if self.common_name.is_empty() {
+            return empty("common_name");
+        }
+        if self.peripheral_name.is_empty() {
+            return empty("peripheral_name");
+        }
+        match &self.inventory_type {
+            InventoryType::Input { destination } if destination.is_empty() => return empty("destination"),
+            InventoryType::Output { source } if source.is_empty() => return empty("source"),
+            _ => {}
+        }
+        if self.computer_id < 0 {
+            return Err(ReportError::InvalidComputerId { computer_id: self.computer_id });
+        }
+        let mut slots = Vec::with_capacity(self.inventory.len());
+        for item in &self.inventory {
+            if item.slot < 1 {
+                return Err(ReportError::InvalidSlot { slot: item.slot });
+            }
+            if slots.contains(&item.slot) {
+                return Err(ReportError::DuplicateSlot { slot: item.slot });
+            }
+            slots.push(item.slot);
+            if item.name.is_empty() {
+                return empty("name");
+            }
+            if item.count <= 0 {
+                return Err(ReportError::InvalidCount { slot: item.slot, count: item.count });
+            }
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone, PartialEq, Eq)]
 pub struct InventoryItem {
     pub slot: i64,
@@ -28,7 +143,7 @@ pub struct InventoryRate {
     pub rate_per_second: f64,
 }
 
-#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, PartialEq)]
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, PartialEq, Eq)]
 pub struct InventoryItemCount {
     pub name: String,
     pub count: i64,
@@ -49,61 +164,149 @@ pub enum InventoryType {
     Storage
 }
 
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, PartialEq)]
 pub enum InventoryManagerReport{
     Input(Vec<InventoryRate>),
     Output(Vec<InventoryRate>),
     Storage(Vec<InventoryItemCount>),
 }
 
+impl InventoryManagerReport {
+    /// Sorts the items biggest rate or count first
+    pub fn sorted(mut self) -> Self {
+        match &mut self {
+            InventoryManagerReport::Input(r) | InventoryManagerReport::Output(r) => {
+                r.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal).reverse());
+            }
+            InventoryManagerReport::Storage(r) => {
+                r.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal).reverse());
+            }
+        }
+        self
+    }
+}
+
+/// Rates of one input or output computer
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, PartialEq)]
+pub struct ComputerRates {
+    pub computer_id: i64,
+    pub common_name: String,
+    pub report: InventoryManagerReport,
+}
+
+/// Latest counts of every item in one storage computer, across all of its peripherals
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, PartialEq, Eq)]
+pub struct ComputerStock {
+    pub computer_id: i64,
+    pub common_name: String,
+    pub items: Vec<InventoryItemCount>,
+}
+
+/// How many of an item one storage peripheral holds
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, PartialEq, Eq)]
+pub struct ItemLocation {
+    pub common_name: String,
+    pub computer_id: i64,
+    pub peripheral_name: String,
+    pub count: i64,
+}
+
 type ComputerIds = Vec<i64>;
 type InventoryReports = VecDeque<(Instant, InventoryReport)>;
 
 pub struct InventoryManager {
     inventory_reports: RwLock<(ComputerIds,InventoryReports)>,
     // used so that we can clone the sender
-    sender: UnboundedSender<InventoryReport>,
+    sender: Sender<InventoryReport>,
+    events: Arc<EventBus>,
+    settings: RwLock<ReportSettings>,
+    reports_ingested: AtomicU64,
 }
 
 
 impl InventoryManager {
-    pub fn new(sender: UnboundedSender<InventoryReport>) -> Self {
+    pub fn new(sender: Sender<InventoryReport>, events: Arc<EventBus>, settings: ReportSettings) -> Self {
         Self {
             inventory_reports: RwLock::new((Vec::new(), VecDeque::new())),
             sender,
+            events,
+            settings: RwLock::new(settings),
+            reports_ingested: AtomicU64::new(0),
         }
     }
 
-    pub fn get_sender(&self) -> UnboundedSender<InventoryReport> {
+    /// Applies new settings, reports already past the new retention go with the next report
+    pub async fn set_settings(&self, settings: ReportSettings) {
+        *self.settings.write().await = settings;
+    }
+
+    pub fn get_sender(&self) -> Sender<InventoryReport> {
         self.sender.clone()
     }
 
-    pub async fn run(&self, mut event_receiver: tokio::sync::mpsc::UnboundedReceiver<InventoryReport>) {
+    pub async fn run(&self, mut event_receiver: Receiver<InventoryReport>) {
         loop {
             let report = event_receiver.recv().await;
+            let settings = *self.settings.read().await;
             let now = Instant::now();
             let mut guard = self.inventory_reports.write().await;
-            loop {
-                // drop the oldest reports if they are older than 30 minutes
-                let Some(back) = guard.1.back() else {
-                    break;
-                };
-                if back.0 + std::time::Duration::from_secs(60 * 30) > now {
+            // drop the oldest reports once they are past retention
+            while let Some(back) = guard.1.back() {
+                if back.0 + settings.retention > now {
                     break
                 }
                 guard.1.pop_back();
             }
             if let Some(report) = report {
+                self.reports_ingested.fetch_add(1, AtomicOrdering::Relaxed);
+                if self.events.has_subscribers() {
+                    self.events.publish(ServerEvent::Report(report.clone()));
+                }
                 if !guard.0.contains(&report.computer_id) {
                     // new computer id, reserve enough report capacity
-                    guard.1.reserve(30 * (60 / SECONDS_PER_REPORT) as usize);
+                    guard.1.reserve((settings.retention.as_secs() / settings.seconds_per_report) as usize);
                 }
                 guard.1.push_front((now, report));
             }
         }
     }
 
+    /// Reports taken in since the server started
+    pub fn reports_ingested(&self) -> u64 {
+        self.reports_ingested.load(AtomicOrdering::Relaxed)
+    }
+
+    /// Rough number of bytes the kept reports take up, strings and vectors included
+    pub async fn memory_usage(&self) -> usize {
+        fn heap_size(report: &InventoryReport) -> usize {
+            let inventory_type = match &report.inventory_type {
+                InventoryType::Input { destination } => destination.capacity(),
+                InventoryType::Output { source } => source.capacity(),
+                InventoryType::Storage => 0,
+            };
+            report.common_name.capacity()
+                + report.peripheral_name.capacity()
+                + inventory_type
+                + report.inventory.capacity() * std::mem::size_of::<InventoryItem>()
+                + report.inventory.iter().map(|item| item.name.capacity()).sum::<usize>()
+        }
+        let guard = self.inventory_reports.read().await;
+        guard.0.capacity() * std::mem::size_of::<i64>()
+            + guard.1.capacity() * std::mem::size_of::<(Instant, InventoryReport)>()
+            + guard.1.iter().map(|(_, report)| heap_size(report)).sum::<usize>()
+    }
+
+    /// How long ago the computer last reported, None if it hasn't within the retention
+    pub async fn last_report_age(&self, computer_id: i64) -> Option<Duration> {
+        let guard = self.inventory_reports.read().await;
+        // newest reports are at the front
+        guard.1.iter()
+            .find(|(_, report)| report.computer_id == computer_id)
+            .map(|(time_reported, _)| time_reported.elapsed())
+    }
 
     pub async fn get_report(&self, computer_id: i64, over_past: Duration) -> Option<InventoryManagerReport> {
+        let seconds_per_report = self.settings.read().await.seconds_per_report;
         let guard = self.inventory_reports.read().await;
         let mut inventory_rate_map: HashMap<String, f64> = HashMap::new();
         let mut inventory_type = None;
@@ -136,7 +339,7 @@ impl InventoryManager {
             Some(InventoryType::Input { .. }) => {
                 let mut inventory_rate = Vec::new();
                 for (name, count) in inventory_rate_map {
-                    let rate = count / number_of_reports as f64 / SECONDS_PER_REPORT as f64;
+                    let rate = count / number_of_reports as f64 / seconds_per_report as f64;
                     inventory_rate.push(InventoryRate {
                         name,
                         rate_per_second: rate,
@@ -147,7 +350,7 @@ impl InventoryManager {
             Some(InventoryType::Output { .. }) => {
                 let mut inventory_rate = Vec::new();
                 for (name, count) in inventory_rate_map {
-                    let rate = count / number_of_reports as f64 / SECONDS_PER_REPORT as f64;
+                    let rate = count / number_of_reports as f64 / seconds_per_report as f64;
                     inventory_rate.push(InventoryRate {
                         name,
                         rate_per_second: rate,
@@ -174,6 +377,136 @@ impl InventoryManager {
     }
 }
 
+impl InventoryManager {
+    /// Most recent report of the storage registered under `common_name`
+    pub async fn get_latest_storage_report(&self, common_name: &str) -> Option<InventoryReport> {
+        let guard = self.inventory_reports.read().await;
+        guard.1.iter()
+            .find(|(_, report)| report.common_name == common_name && report.inventory_type == InventoryType::Storage)
+            .map(|(_, report)| report.clone())
+    }
+
+    /// Total count of every item across the latest report of every storage peripheral
+    pub async fn get_storage_totals(&self) -> HashMap<String, i64> {
+        let guard = self.inventory_reports.read().await;
+        let mut seen = Vec::new();
+        let mut totals = HashMap::new();
+        for (_, report) in guard.1.iter().filter(|(_, report)| report.inventory_type == InventoryType::Storage) {
+            let key = (report.computer_id, report.peripheral_name.as_str());
+            if seen.contains(&key) {
+                continue; // only the most recent report of a storage counts
+            }
+            seen.push(key);
+            for item in &report.inventory {
+                *totals.entry(item.name.clone()).or_insert(0) += item.count;
+            }
+        }
+        totals
+    }
+
+    /// Item counts of every storage computer, from the latest report of each of its peripherals,
+    /// sorted by computer id
+    pub async fn get_storage_stock(&self) -> Vec<ComputerStock> {
+        let guard = self.inventory_reports.read().await;
+        let mut seen = Vec::new();
+        let mut stock: Vec<(ComputerStock, HashMap<String, i64>)> = Vec::new();
+        for (_, report) in guard.1.iter().filter(|(_, report)| report.inventory_type == InventoryType::Storage) {
+            let key = (report.computer_id, report.peripheral_name.as_str());
+            if seen.contains(&key) {
+                continue;
+            }
+            seen.push(key);
+            let index = match stock.iter().position(|(s, _)| s.computer_id == report.computer_id) {
+                Some(index) => index,
+                None => {
+                    stock.push((ComputerStock {
+                        computer_id: report.computer_id,
+                        common_name: report.common_name.clone(),
+                        items: Vec::new(),
+                    }, HashMap::new()));
+                    stock.len() - 1
+                }
+            };
+            for item in &report.inventory {
+                *stock[index].1.entry(item.name.clone()).or_insert(0) += item.count;
+            }
+        }
+        let mut stock: Vec<_> = stock.into_iter().map(|(mut computer, counts)| {
+            computer.items = counts.into_iter().map(|(name, count)| InventoryItemCount { name, count }).collect();
+            computer.items.sort_by(|a, b| a.name.cmp(&b.name));
+            computer
+        }).collect();
+        stock.sort_by_key(|s| s.computer_id);
+        stock
+    }
+
+    /// Where `name` is stored, according to the latest report of every storage peripheral
+    pub async fn find_item(&self, name: &str) -> Vec<ItemLocation> {
+        let guard = self.inventory_reports.read().await;
+        let mut seen = Vec::new();
+        let mut locations = Vec::new();
+        for (_, report) in guard.1.iter().filter(|(_, report)| report.inventory_type == InventoryType::Storage) {
+            let key = (report.computer_id, report.peripheral_name.as_str());
+            if seen.contains(&key) {
+                continue;
+            }
+            seen.push(key);
+            let count: i64 = report.inventory.iter().filter(|item| item.name == name).map(|item| item.count).sum();
+            if count > 0 {
+                locations.push(ItemLocation {
+                    common_name: report.common_name.clone(),
+                    computer_id: report.computer_id,
+                    peripheral_name: report.peripheral_name.clone(),
+                    count,
+                });
+            }
+        }
+        locations
+    }
+
+    /// Id and latest common name of every computer that sent an input or output report in the
+    /// window, sorted by id
+    pub async fn get_rate_computers(&self, over_past: Duration) -> Vec<(i64, String)> {
+        let guard = self.inventory_reports.read().await;
+        let now = Instant::now();
+        let mut computers: Vec<(i64, String)> = Vec::new();
+        for (_, report) in guard.1.iter().filter(|(time_reported, report)| {
+            now.duration_since(*time_reported) <= over_past && report.inventory_type != InventoryType::Storage
+        }) {
+            if !computers.iter().any(|(id, _)| *id == report.computer_id) {
+                computers.push((report.computer_id, report.common_name.clone()));
+            }
+        }
+        computers.sort_by_key(|(id, _)| *id);
+        computers
+    }
+
+    /// Rates of every input and output computer over the window
+    pub async fn get_rates(&self, over_past: Duration) -> Vec<ComputerRates> {
+        let mut rates = Vec::new();
+        for (computer_id, common_name) in self.get_rate_computers(over_past).await {
+            if let Some(report) = self.get_report(computer_id, over_past).await {
+                rates.push(ComputerRates { computer_id, common_name, report: report.sorted() });
+            }
+        }
+        rates
+    }
+
+    /// Optimistically takes `count` items out of a slot in the latest report of a peripheral, so
+    /// that we don't try to move the same items twice before the next report comes in
+    pub async fn remove_items(&self, computer_id: i64, peripheral_name: &str, slot: i64, count: i64) {
+        let mut guard = self.inventory_reports.write().await;
+        let Some((_, report)) = guard.1.iter_mut()
+            .find(|(_, report)| report.computer_id == computer_id && report.peripheral_name == peripheral_name) else {
+            return;
+        };
+        if let Some(item) = report.inventory.iter_mut().find(|item| item.slot == slot) {
+            item.count -= count;
+        }
+        report.inventory.retain(|item| item.count > 0);
+    }
+}
+
 impl PartialOrd for InventoryRate {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         if self.rate_per_second == other.rate_per_second {
@@ -255,15 +588,117 @@ mod tests {
             r#"{"monitor_resize":{"width":10,"height":20}}"#
         );
 
-        let inventory_Register = CCTweakedMonitorInputEvent::InventoryRegister {
+        let inventory_register = CCTweakedMonitorInputEvent::InventoryRegister {
             size: Size { width: 10, height: 20 },
             computer_id: 0,
             common_name: "123".to_string(),
+            view: None,
+            protocol_version: None,
+            token: None,
         };
-        let serialized = serde_json::to_string(&inventory_Register).unwrap();
+        let serialized = serde_json::to_string(&inventory_register).unwrap();
         assert_eq!(
             serialized,
             r#"{"inventory_register":{"size":{"width":10,"height":20},"computer_id":0,"common_name":"123"}}"#
         );
     }
-}
\ No newline at end of file
+
+    /// A running manager with `reports` taken in, waiting until it has seen every one of them
+    async fn manager_with(reports: Vec<InventoryReport>) -> Arc<InventoryManager> {
+        let (sender, receiver) = tokio::sync::mpsc::channel(REPORT_QUEUE);
+        let manager = Arc::new(InventoryManager::new(sender.clone(), Arc::new(EventBus::new()), ReportSettings::default()));
+        let runner = manager.clone();
+        tokio::spawn(async move { runner.run(receiver).await });
+        let count = reports.len() as u64;
+        for report in reports {
+            sender.send(report).await.unwrap();
+        }
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while manager.reports_ingested() < count {
+                tokio::task::yield_now().await;
+            }
+        }).await.expect("manager didn't take in the reports");
+        manager
+    }
+
+    #[tokio::test]
+    async fn test_remove_items() {
+        let manager = manager_with(vec![InventoryReport {
+            common_name: "MainStorage".to_string(),
+            computer_id: 1,
+            inventory: vec![
+                InventoryItem { slot: 1, name: "iron_ingot".to_string(), count: 64 },
+                InventoryItem { slot: 2, name: "iron_ingot".to_string(), count: 10 },
+            ],
+            peripheral_name: "left".to_string(),
+            inventory_type: InventoryType::Storage,
+        }]).await;
+
+        manager.remove_items(1, "left", 1, 60).await;
+        manager.remove_items(1, "left", 2, 10).await;
+        let report = manager.get_latest_storage_report("MainStorage").await.unwrap();
+        assert_eq!(report.inventory, vec![InventoryItem { slot: 1, name: "iron_ingot".to_string(), count: 4 }]);
+        assert!(manager.get_latest_storage_report("OtherStorage").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_find_item_and_rate_computers() {
+        let report = |computer_id: i64, peripheral_name: &str, count: i64, inventory_type: InventoryType| InventoryReport {
+            common_name: format!("Computer{computer_id}"),
+            computer_id,
+            inventory: vec![InventoryItem { slot: 1, name: "coal".to_string(), count }],
+            peripheral_name: peripheral_name.to_string(),
+            inventory_type,
+        };
+        let manager = manager_with(vec![
+            report(1, "left", 10, InventoryType::Storage),
+            report(1, "left", 20, InventoryType::Storage),
+            report(1, "right", 5, InventoryType::Storage),
+            report(3, "top", 1, InventoryType::Output { source: "furnace".to_string() }),
+            report(2, "top", 1, InventoryType::Input { destination: "Computer1".to_string() }),
+        ]).await;
+
+        let mut locations = manager.find_item("coal").await;
+        locations.sort_by_key(|l| l.count);
+        assert_eq!(locations.iter().map(|l| (l.peripheral_name.as_str(), l.count)).collect::<Vec<_>>(), vec![("right", 5), ("left", 20)]);
+        assert!(manager.find_item("diamond").await.is_empty());
+        assert!(manager.last_report_age(1).await.unwrap() < Duration::from_secs(1));
+        assert_eq!(manager.last_report_age(9).await, None);
+        assert_eq!(manager.get_rate_computers(REPORT_WINDOW).await, vec![(2, "Computer2".to_string()), (3, "Computer3".to_string())]);
+        assert_eq!(manager.get_storage_stock().await, vec![ComputerStock {
+            computer_id: 1,
+            common_name: "Computer1".to_string(),
+            items: vec![InventoryItemCount { name: "coal".to_string(), count: 25 }],
+        }]);
+        assert_eq!(manager.reports_ingested(), 5);
+        assert!(manager.memory_usage().await >= 5 * std::mem::size_of::<InventoryReport>());
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut report = InventoryReport {
+            common_name: "Main".to_string(),
+            computer_id: 1,
+            inventory: vec![
+                InventoryItem { slot: 1, name: "coal".to_string(), count: 3 },
+                InventoryItem { slot: 2, name: "coal".to_string(), count: 64 },
+            ],
+            peripheral_name: "left".to_string(),
+            inventory_type: InventoryType::Storage,
+        };
+        assert_eq!(report.validate(), Ok(()));
+
+        report.inventory[1].slot = 1;
+        assert_eq!(report.validate(), Err(ReportError::DuplicateSlot { slot: 1 }));
+        report.inventory[1].slot = 2;
+        report.inventory[1].count = 0;
+        assert_eq!(report.validate(), Err(ReportError::InvalidCount { slot: 2, count: 0 }));
+        report.inventory_type = InventoryType::Input { destination: String::new() };
+        assert_eq!(report.validate(), Err(ReportError::EmptyField { field: "destination".to_string() }));
+
+        assert_eq!(
+            serde_json::to_string(&ReportError::InvalidCount { slot: 2, count: 0 }).unwrap(),
+            r#"{"error":"invalid_count","slot":2,"count":0}"#
+        );
+    }
+}
@@ -0,0 +1,183 @@
+use std::fmt::Display;
+use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::{info, warn};
+use crate::commands::{ComputerHandles, RpcError};
+use crate::inventory_manager::{InventoryManager, InventoryReport};
+
+/// Move `count` of `item` out of the storage registered as `from` into the peripheral `to`.
+/// `to` has to be on the same wired network as the storage's peripheral.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransferRequest {
+    pub item: String,
+    pub count: i64,
+    pub from: String,
+    pub to: String,
+}
+
+/// A single `pushItems` call
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SlotTransfer {
+    pub slot: i64,
+    pub count: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransferOutcome {
+    pub requested: i64,
+    pub moved: i64,
+    /// the transfers the computer confirmed, with how many items each actually moved
+    pub transfers: Vec<SlotTransfer>,
+}
+
+#[derive(Debug, Clone, Error, PartialEq, Eq)]
+pub enum TransferError {
+    InvalidCount(i64),
+    UnknownStorage(String),
+    NotEnoughItems {
+        item: String,
+        available: i64,
+        requested: i64,
+    },
+    Rpc(RpcError),
+}
+
+impl Display for TransferError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransferError::InvalidCount(count) => write!(f, "Can't transfer {} items", count),
+            TransferError::UnknownStorage(name) => write!(f, "No storage report for {}", name),
+            TransferError::NotEnoughItems { item, available, requested } => {
+                write!(f, "Only {} of {} available, {} requested", available, item, requested)
+            }
+            TransferError::Rpc(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Picks the slots to take `count` of `item` from, emptying the smallest stacks first so the
+/// storage ends up with fewer partial stacks
+pub fn plan_transfer(report: &InventoryReport, item: &str, count: i64) -> Result<Vec<SlotTransfer>, TransferError> {
+    if count <= 0 {
+        return Err(TransferError::InvalidCount(count));
+    }
+    let mut slots: Vec<_> = report.inventory.iter().filter(|i| i.name == item && i.count > 0).collect();
+    let available: i64 = slots.iter().map(|i| i.count).sum();
+    if available < count {
+        return Err(TransferError::NotEnoughItems {
+            item: item.to_string(),
+            available,
+            requested: count,
+        });
+    }
+    slots.sort_by_key(|i| (i.count, i.slot));
+    let mut remaining = count;
+    let mut plan = Vec::new();
+    for slot in slots {
+        if remaining == 0 {
+            break;
+        }
+        let take = slot.count.min(remaining);
+        plan.push(SlotTransfer { slot: slot.slot, count: take });
+        remaining -= take;
+    }
+    Ok(plan)
+}
+
+/// ItemRouter moves items between inventories by having the computer that reports a storage
+/// call `pushItems` on it
+pub struct ItemRouter {
+    manager: Arc<InventoryManager>,
+    computers: Arc<ComputerHandles>,
+}
+
+impl ItemRouter {
+    pub fn new(manager: Arc<InventoryManager>, computers: Arc<ComputerHandles>) -> Self {
+        ItemRouter {
+            manager,
+            computers,
+        }
+    }
+
+    pub async fn transfer(&self, request: &TransferRequest) -> Result<TransferOutcome, TransferError> {
+        let Some(report) = self.manager.get_latest_storage_report(&request.from).await else {
+            return Err(TransferError::UnknownStorage(request.from.clone()));
+        };
+        let plan = plan_transfer(&report, &request.item, request.count)?;
+        let Some(computer) = self.computers.get(report.computer_id).await else {
+            return Err(TransferError::Rpc(RpcError::UnknownComputer(report.computer_id)));
+        };
+        info!("Moving {} {} from {} to {}", request.count, request.item, request.from, request.to);
+
+        let mut outcome = TransferOutcome {
+            requested: request.count,
+            moved: 0,
+            transfers: Vec::new(),
+        };
+        for step in plan {
+            let args = vec![
+                serde_json::Value::from(request.to.clone()),
+                serde_json::Value::from(step.slot),
+                serde_json::Value::from(step.count),
+            ];
+            let results = match computer.call(&report.peripheral_name, "pushItems", args).await {
+                Ok(results) => results,
+                // report what already happened rather than pretending nothing moved
+                Err(e) if outcome.moved > 0 => {
+                    warn!("Transfer of {} stopped after {} items: {}", request.item, outcome.moved, e);
+                    break;
+                }
+                Err(e) => return Err(TransferError::Rpc(e)),
+            };
+            let moved = results.first().and_then(|v| v.as_f64()).unwrap_or(0.0) as i64;
+            if moved > 0 {
+                self.manager.remove_items(report.computer_id, &report.peripheral_name, step.slot, moved).await;
+                outcome.moved += moved;
+                outcome.transfers.push(SlotTransfer { slot: step.slot, count: moved });
+            }
+            if moved < step.count {
+                // destination is full or the slot changed under us, no point in trying the rest
+                warn!("Only moved {} of {} from slot {} to {}", moved, step.count, step.slot, request.to);
+                break;
+            }
+        }
+        Ok(outcome)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inventory_manager::{InventoryItem, InventoryType};
+
+    fn storage(items: &[(i64, &str, i64)]) -> InventoryReport {
+        InventoryReport {
+            common_name: "MainStorage".to_string(),
+            computer_id: 1,
+            inventory: items.iter().map(|(slot, name, count)| InventoryItem {
+                slot: *slot,
+                name: name.to_string(),
+                count: *count,
+            }).collect(),
+            peripheral_name: "left".to_string(),
+            inventory_type: InventoryType::Storage,
+        }
+    }
+
+    #[test]
+    fn test_plan_transfer() {
+        let report = storage(&[(1, "iron_ingot", 64), (2, "coal", 64), (3, "iron_ingot", 10), (4, "iron_ingot", 64)]);
+        assert_eq!(plan_transfer(&report, "iron_ingot", 64).unwrap(), vec![
+            SlotTransfer { slot: 3, count: 10 },
+            SlotTransfer { slot: 1, count: 54 },
+        ]);
+        assert_eq!(plan_transfer(&report, "iron_ingot", 5).unwrap(), vec![SlotTransfer { slot: 3, count: 5 }]);
+        assert_eq!(plan_transfer(&report, "iron_ingot", 200), Err(TransferError::NotEnoughItems {
+            item: "iron_ingot".to_string(),
+            available: 138,
+            requested: 200,
+        }));
+        assert_eq!(plan_transfer(&report, "coal", 0), Err(TransferError::InvalidCount(0)));
+    }
+}
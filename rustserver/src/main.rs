@@ -1,91 +1,1033 @@
+mod auth;
 mod cctweaked;
+mod commands;
+mod config;
+mod connections;
+mod crafting;
+mod events;
+mod item_router;
+mod metrics;
+mod mining_planner;
+mod recording;
+mod session;
+#[cfg(test)]
+mod simulator;
+mod stock_keeper;
+mod turtle_manager;
+mod turtle_tasks;
+mod views;
+mod world_map;
 pub mod inventory_manager;
 
-use std::fmt::{Debug, Display};
-use axum::extract::{ConnectInfo, State, WebSocketUpgrade};
-use axum::http::StatusCode;
-use axum::response::IntoResponse;
-use axum::{Error, Router, ServiceExt};
-use axum::routing::{any, get, post};
+use axum::extract::rejection::JsonRejection;
+use axum::extract::{ConnectInfo, Path, Query, State, WebSocketUpgrade};
+use axum::http::{HeaderMap, StatusCode};
+use axum::http::header::CONTENT_TYPE;
+use axum::response::{Html, IntoResponse, Response};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::{Json, Router};
+use axum::routing::{any, delete, get, post};
 use axum_extra::TypedHeader;
-use ratatui::backend::{Backend, ClearType, CrosstermBackend, WindowSize};
-use ratatui::buffer::Cell;
-use ratatui::{DefaultTerminal, Frame, Terminal, TerminalOptions};
-use ratatui::layout::{Position, Size};
+use ratatui::{Frame, Terminal};
 use core::net::SocketAddr;
-use std::sync::{Arc};
-use axum::extract::ws::{CloseFrame, Message, Utf8Bytes, WebSocket};
-use futures::{StreamExt, SinkExt};
-use futures::stream::{SplitSink, SplitStream};
-use ratatui::crossterm::queue;
-use ratatui::prelude::{Color, Modifier, Widget};
+use std::convert::Infallible;
+use std::sync::{Arc, OnceLock};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+use axum::extract::ws::{close_code, CloseFrame, Message, WebSocket};
+use futures::{SinkExt, Stream, StreamExt};
 use serde::{Deserialize, Serialize};
-use thiserror::Error;
 use tokio::select;
 use tokio::sync::Mutex;
-use tracing::{error, info};
-use tracing::instrument::WithSubscriber;
-use std::io::{stdout, BufWriter, Stdout, Write};
-use std::time::Duration;
-use ratatui::crossterm::terminal::enable_raw_mode;
+use tokio::sync::broadcast::error::RecvError;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{error, info, warn};
 use ratatui::symbols::border;
 use ratatui::text::Text;
 use ratatui::widgets::{Block, List};
+use cc_code_page::cctweaked_char;
 use cctweaked::CCTweakedMonitorBackend;
-use crate::cctweaked::{CCTweakedMonitorBackendEvent, CCTweakedMonitorInputEvent, MonitorInputHandler, MonitorOutputHandler};
-use crate::inventory_manager::{InventoryManager, InventoryManagerReport, InventoryReport};
+use crate::auth::{AuthError, IssuedToken, TokenStore, TOKEN_HEADER};
+use crate::cctweaked::{CCTweakedMonitorInputEvent, MonitorFrame, MonitorInputHandler, MonitorOutputHandler, MONITOR_FRAME_QUEUE};
+use crate::crafting::{CraftingPlan, RecipeBook};
+use crate::connections::{Connection, ConnectionInfo, ConnectionRegistry, ConnectionRole};
+use crate::config::{Config, LiveConfig, CONFIG_POLL_INTERVAL, USAGE};
+use crate::commands::{CommandError, ComputerHandle, ComputerHandles, ComputerRequest, RpcError, ServerCommand};
+use crate::events::{EventBus, ServerEvent};
+use crate::inventory_manager::{ComputerRates, InventoryItemCount, InventoryManager, InventoryManagerReport, InventoryReport, ItemLocation, ReportError, REPORT_QUEUE};
+use crate::metrics::{Direction, Metrics, Scrape};
+use crate::item_router::{ItemRouter, TransferError, TransferOutcome, TransferRequest};
+use crate::mining_planner::{MiningError, MiningJob, MiningPlanner, MiningProgress};
+use crate::stock_keeper::{StockJob, StockKeeper, StockRuleError, STOCK_CHECK_INTERVAL};
+use crate::turtle_manager::{BlockPosition, TurtleHistoryEntry, TurtleInputEvent, TurtleManager, TurtleStatus};
+use crate::turtle_tasks::{QueuedTask, TaskError, TaskStatus, TurtleBackendEvent, TurtleTask, TurtleTaskQueue};
+use crate::recording::Recorder;
+use crate::session::{SessionError, Supervisor, SHUTDOWN_TIMEOUT};
+use crate::views::MonitorView;
+use crate::world_map::{DigPolicy, WorldMap};
+
+/// Shared state handed to every route
+#[derive(Clone)]
+struct AppState {
+    manager: Arc<InventoryManager>,
+    computers: Arc<ComputerHandles>,
+    router: Arc<ItemRouter>,
+    recipes: Arc<RecipeBook>,
+    stock_keeper: Arc<StockKeeper>,
+    turtles: Arc<TurtleManager>,
+    tasks: Arc<TurtleTaskQueue>,
+    world: Arc<WorldMap>,
+    mining: Arc<MiningPlanner>,
+    connections: Arc<ConnectionRegistry>,
+    events: Arc<EventBus>,
+    tokens: Arc<TokenStore>,
+    config: Arc<LiveConfig>,
+    metrics: Arc<Metrics>,
+    /// cancelled on Ctrl-C so sessions can close their sockets
+    shutdown: CancellationToken,
+    /// every websocket session, so shutdown can wait for them
+    sessions: TaskTracker,
+}
+
+/// Browser page that acts as a monitor, for previewing views without being in game
+const MONITOR_EMULATOR_PAGE: &str = include_str!("../static/monitor_emulator.html");
+
+/// Where recipes for the crafting planner are loaded from
+const RECIPES_PATH: &str = "recipes.json";
+/// Where stock keeping rules are loaded from
+const STOCK_RULES_PATH: &str = "stock_rules.json";
+/// Where blocks seen by turtles are saved
+const WORLD_MAP_PATH: &str = "world_map.json";
+/// Where tokens issued to computers are saved
+const TOKENS_PATH: &str = "tokens.json";
+/// Where mining jobs are saved
+const MINING_JOBS_PATH: &str = "mining_jobs.json";
+/// How often the world map is written to disk
+const WORLD_MAP_SAVE_INTERVAL: Duration = Duration::from_secs(60);
 
 #[tokio::main]
 async fn main() {
     tracing_subscriber::fmt::init();
-    let (manager_sender, manager_receiver) = tokio::sync::mpsc::unbounded_channel::<InventoryReport>();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().is_some_and(|arg| arg == "replay") {
+        match recording::replay_command(&args[1..]) {
+            Ok(screen) => print!("{}", screen),
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    let config = LiveConfig::load(args).unwrap_or_else(|e| {
+        error!("{}\n{}", e, USAGE);
+        std::process::exit(1);
+    });
+    let settings = config.current();
+    info!("Reports every {}s, kept for {}s, rates averaged over {}s", settings.seconds_per_report, settings.retention_seconds, settings.report_window_seconds);
+    let (manager_sender, manager_receiver) = tokio::sync::mpsc::channel::<InventoryReport>(REPORT_QUEUE);
     
-    let manager = Arc::new(InventoryManager::new(manager_sender));
+    let events = Arc::new(EventBus::new());
+    let manager = Arc::new(InventoryManager::new(manager_sender, events.clone(), settings.report_settings()));
     let manager_clone = manager.clone();
     tokio::spawn(async move { 
         manager_clone.run(manager_receiver).await;
     });
-    
-    let app = Router::new()
-        .route("/", get(|| async {"hello world"}))
-        .route("/ws/monitor", any(terminal_handler))
-        .with_state(manager);
+    let recipes = RecipeBook::load(RECIPES_PATH).unwrap_or_else(|e| {
+        warn!("{}, crafting plans will only use items in stock", e);
+        RecipeBook::default()
+    });
+    info!("Loaded {} recipes", recipes.len());
+    let recipes = Arc::new(recipes);
+    let stock_rules = stock_keeper::load_rules(STOCK_RULES_PATH).unwrap_or_else(|e| {
+        warn!("{}, not keeping anything in stock", e);
+        Vec::new()
+    });
+    info!("Loaded {} stock rules", stock_rules.len());
+    let computers = Arc::new(ComputerHandles::new());
+    let router = Arc::new(ItemRouter::new(manager.clone(), computers.clone()));
+    let stock_keeper = Arc::new(StockKeeper::new(stock_rules, manager.clone(), router.clone(), recipes.clone(), events.clone()));
+    let stock_keeper_clone = stock_keeper.clone();
+    tokio::spawn(async move {
+        stock_keeper_clone.run(STOCK_CHECK_INTERVAL).await;
+    });
+    let world = Arc::new(WorldMap::load(WORLD_MAP_PATH).unwrap_or_else(|e| {
+        error!("{}", e);
+        std::process::exit(1);
+    }));
+    info!("Loaded {} known blocks", world.len().await);
+    let world_clone = world.clone();
+    tokio::spawn(async move {
+        let mut timer = tokio::time::interval(WORLD_MAP_SAVE_INTERVAL);
+        loop {
+            timer.tick().await;
+            if let Err(e) = world_clone.save(WORLD_MAP_PATH).await {
+                error!("{}", e);
+            }
+        }
+    });
+    let turtles = Arc::new(TurtleManager::new());
+    let tasks = Arc::new(TurtleTaskQueue::new());
+    let mining = MiningPlanner::load(MINING_JOBS_PATH, tasks.clone(), turtles.clone()).unwrap_or_else(|e| {
+        error!("{}", e);
+        std::process::exit(1);
+    });
+    let tokens = TokenStore::load(TOKENS_PATH).unwrap_or_else(|e| {
+        error!("{}", e);
+        std::process::exit(1);
+    });
+    if !tokens.is_enforced().await {
+        warn!("No tokens issued yet, any computer can connect");
+    }
+    let state = AppState {
+        router,
+        manager,
+        computers,
+        recipes,
+        stock_keeper,
+        turtles,
+        tasks,
+        world,
+        mining: Arc::new(mining),
+        connections: Arc::new(ConnectionRegistry::new(events.clone())),
+        events: events.clone(),
+        tokens: Arc::new(tokens),
+        config: Arc::new(config),
+        metrics: Arc::new(Metrics::default()),
+        shutdown: CancellationToken::new(),
+        sessions: TaskTracker::new(),
+    };
+    let manager_clone = state.manager.clone();
+    let config = state.config.clone();
+    tokio::spawn(async move {
+        loop {
+            // rates change once per report, so there is no point publishing them more often
+            let settings = config.current();
+            tokio::time::sleep(Duration::from_secs(settings.seconds_per_report)).await;
+            if events.has_subscribers() {
+                events.publish(ServerEvent::Rates(manager_clone.get_rates(settings.report_window()).await));
+            }
+        }
+    });
+    spawn_reloaders(state.clone());
+    let shutdown = state.shutdown.clone();
+    let sessions = state.sessions.clone();
+    let world = state.world.clone();
+    let bind = settings.bind;
+    let app = routes(state);
 
-    let listener =  tokio::net::TcpListener::bind("127.0.0.1:3000").await.unwrap_or_else(|e| {
+    let listener =  tokio::net::TcpListener::bind(bind).await.unwrap_or_else(|e| {
         error!("Failed to bind to address: {}", e);
         std::process::exit(1);
     });
     info!("Starting server on {}", listener.local_addr().expect("Failed to get local address we bound to"));
-    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await.unwrap_or_else(|e| {
-        error!("Failed to start server: {}", e);
+    let signal_shutdown = shutdown.clone();
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(async move {
+            if let Err(e) = tokio::signal::ctrl_c().await {
+                error!("Can't listen for Ctrl-C: {}", e);
+                std::future::pending::<()>().await;
+            }
+            info!("Shutting down");
+            signal_shutdown.cancel();
+        })
+        .await
+        .unwrap_or_else(|e| {
+            error!("Failed to start server: {}", e);
+        });
+    // websockets don't count as open requests, so the server doesn't wait for them on its own
+    shutdown.cancel();
+    sessions.close();
+    if tokio::time::timeout(SHUTDOWN_TIMEOUT, sessions.wait()).await.is_err() {
+        warn!("{} sessions didn't close within {:?}", sessions.len(), SHUTDOWN_TIMEOUT);
+    }
+    // mining jobs and tokens are saved as they change, only the world map is saved on a timer
+    if let Err(e) = world.save(WORLD_MAP_PATH).await {
+        error!("{}", e);
+    }
+    info!("Shut down");
+}
+
+/// Every route the server has
+fn routes(state: AppState) -> Router {
+    Router::new()
+        .route("/", get(|| async {"hello world"}))
+        .route("/metrics", get(metrics_handler))
+        .route("/emulator", get(emulator_handler))
+        .route("/ws/monitor", any(terminal_handler))
+        .route("/ws/turtle", any(turtle_handler))
+        .route("/api/computers", get(computers_handler))
+        .route("/api/computers/{computer_id}", get(computer_handler))
+        .route("/api/computers/{computer_id}/commands", post(command_handler))
+        .route("/api/computers/{computer_id}/call", post(call_handler))
+        .route("/api/inventory/{computer_id}", get(inventory_handler))
+        .route("/api/items/{name}", get(item_handler))
+        .route("/api/rates", get(rates_handler))
+        .route("/api/reports", post(report_handler))
+        .route("/api/events", get(events_handler))
+        .route("/api/transfers", post(transfer_handler))
+        .route("/api/crafting/plan", get(crafting_plan_handler))
+        .route("/api/stock/jobs", get(stock_jobs_handler))
+        .route("/api/turtles", get(turtles_handler))
+        .route("/api/turtles/{computer_id}/history", get(turtle_history_handler))
+        .route("/api/turtles/{computer_id}/tasks", get(turtle_tasks_handler).post(queue_turtle_task_handler))
+        .route("/api/turtles/{computer_id}/tasks/{task_id}", delete(cancel_turtle_task_handler))
+        .route("/api/mining/jobs", get(mining_jobs_handler).post(start_mining_job_handler))
+        .route("/api/mining/jobs/{job_id}", get(mining_job_handler).delete(stop_mining_job_handler))
+        .route("/api/admin/reload", post(reload_handler))
+        .route("/api/admin/tokens", get(tokens_handler).post(issue_token_handler))
+        .route("/api/admin/tokens/{token}", delete(revoke_token_handler))
+        .route("/api/world/policy", get(get_dig_policy_handler).put(set_dig_policy_handler))
+        .with_state(state)
+}
+
+/// Re-reads the config and stock rules and applies them to everything running, without dropping any
+/// connections. Nothing is applied if either fails to load. Returns whether the config changed.
+async fn reload(state: &AppState) -> Result<bool, String> {
+    let rules = match stock_keeper::load_rules(STOCK_RULES_PATH) {
+        Ok(rules) => rules,
+        Err(StockRuleError::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e.to_string()),
+    };
+    let changed = state.config.reload().map_err(|e| e.to_string())?;
+    state.manager.set_settings(state.config.current().report_settings()).await;
+    info!("Reloaded config and {} stock rules", rules.len());
+    state.stock_keeper.set_rules(rules).await;
+    Ok(changed)
+}
+
+/// Reloads when the config or stock rules change on disk, or on SIGHUP
+fn spawn_reloaders(state: AppState) {
+    fn modified(path: &std::path::Path) -> Option<SystemTime> {
+        std::fs::metadata(path).and_then(|m| m.modified()).ok()
+    }
+    #[cfg(unix)]
+    {
+        let state = state.clone();
+        tokio::spawn(async move {
+            let Ok(mut hangups) = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()).map_err(|e| {
+                warn!("Can't listen for SIGHUP: {}", e);
+            }) else {
+                return;
+            };
+            while hangups.recv().await.is_some() {
+                info!("SIGHUP received, reloading");
+                if let Err(e) = reload(&state).await {
+                    error!("Failed to reload, keeping the current config: {}", e);
+                }
+            }
+        });
+    }
+    tokio::spawn(async move {
+        let paths = [state.config.path(), PathBuf::from(STOCK_RULES_PATH)];
+        let mut last_modified: Vec<_> = paths.iter().map(|p| modified(p)).collect();
+        let mut timer = tokio::time::interval(CONFIG_POLL_INTERVAL);
+        loop {
+            timer.tick().await;
+            let now_modified: Vec<_> = paths.iter().map(|p| modified(p)).collect();
+            if now_modified == last_modified {
+                continue;
+            }
+            last_modified = now_modified;
+            if let Err(e) = reload(&state).await {
+                error!("Failed to reload, keeping the current config: {}", e);
+            }
+        }
     });
 }
 
+async fn reload_handler(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    State(state): State<AppState>,
+) -> Result<Json<Config>, (StatusCode, String)> {
+    require_local(addr)?;
+    reload(&state).await.map_err(|e| (StatusCode::UNPROCESSABLE_ENTITY, e))?;
+    Ok(Json(state.config.current().as_ref().clone()))
+}
+
+async fn emulator_handler() -> Html<&'static str> {
+    Html(emulator_page())
+}
+
+/// The emulator page with the code page filled in, so it draws the same characters as everything else
+fn emulator_page() -> &'static str {
+    static PAGE: OnceLock<String> = OnceLock::new();
+    PAGE.get_or_init(|| {
+        let code_page: String = (0..=u8::MAX).map(cctweaked_char).collect();
+        MONITOR_EMULATOR_PAGE.replace("CODE_PAGE_JSON", &serde_json::Value::from(code_page).to_string())
+    })
+}
 
 async fn terminal_handler(
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
     ws: WebSocketUpgrade,
     user_agent: Option<TypedHeader<headers::UserAgent>>,
-    State(manager): State<Arc<InventoryManager>>,
-) -> impl IntoResponse {
-    let user_agent = if let Some(TypedHeader(user_agent)) = user_agent {
-        user_agent.to_string()
-    } else {
-        String::from("Unknown browser")
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> Response {
+    let user_agent = user_agent.map(|TypedHeader(user_agent)| user_agent.to_string());
+    info!("`{}` at {addr} connected.", user_agent.as_deref().unwrap_or("Unknown browser"));
+    let token = match header_token(&headers, &state, addr).await {
+        Ok(token) => token,
+        Err(rejection) => return rejection.into_response(),
     };
-    info!("`{user_agent}` at {addr} connected.");
     // finalize the upgrade process by returning upgrade callback.
     // we can customize the callback by sending additional info such as address.
-    ws.on_upgrade(move |socket| handle_socket(socket, addr, manager))
+    ws.on_upgrade(move |socket| handle_socket(socket, addr, user_agent, token, state))
+}
+
+/// Reads the token header and turns away unknown tokens before the websocket is upgraded.
+/// Which computer the token is for is only checked once it registers.
+async fn header_token(headers: &HeaderMap, state: &AppState, addr: SocketAddr) -> Result<Option<String>, (StatusCode, String)> {
+    let Some(token) = headers.get(TOKEN_HEADER) else {
+        return Ok(None);
+    };
+    let token = token.to_str().map_err(|_| (StatusCode::BAD_REQUEST, format!("Invalid {} header", TOKEN_HEADER)))?;
+    state.tokens.check_token(token).await.map_err(|e| {
+        warn!("Rejected connection from {addr}: {e}");
+        (StatusCode::UNAUTHORIZED, e.to_string())
+    })?;
+    Ok(Some(token.to_string()))
+}
+
+/// Admin endpoints, and any that change state or act on computers, are only reachable from the
+/// machine the server runs on. `bind` may open the server up to the network.
+fn require_local(addr: SocketAddr) -> Result<(), (StatusCode, String)> {
+    if addr.ip().is_loopback() {
+        return Ok(());
+    }
+    warn!("Rejected request from {addr}, it is only allowed locally");
+    Err((StatusCode::FORBIDDEN, String::from("This endpoint is only available locally")))
+}
+
+async fn tokens_handler(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<IssuedToken>>, (StatusCode, String)> {
+    require_local(addr)?;
+    Ok(Json(state.tokens.list().await))
+}
+
+#[derive(Debug, Deserialize)]
+struct IssueTokenRequest {
+    computer_id: i64,
+    #[serde(default)]
+    label: Option<String>,
+}
+
+async fn issue_token_handler(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    State(state): State<AppState>,
+    Json(request): Json<IssueTokenRequest>,
+) -> Result<Json<IssuedToken>, (StatusCode, String)> {
+    require_local(addr)?;
+    let issued = state.tokens.issue(request.computer_id, request.label).await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    info!("Issued a token for computer {}", issued.computer_id);
+    Ok(Json(issued))
+}
+
+async fn revoke_token_handler(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(token): Path<String>,
+    State(state): State<AppState>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    require_local(addr)?;
+    match state.tokens.revoke(&token).await {
+        Ok(true) => {
+            info!("Revoked a token");
+            Ok(StatusCode::NO_CONTENT)
+        }
+        Ok(false) => Err((StatusCode::NOT_FOUND, AuthError::UnknownToken.to_string())),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+/// Sends a command to a connected computer and waits for it to be acknowledged
+async fn command_handler(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(computer_id): Path<i64>,
+    State(state): State<AppState>,
+    Json(command): Json<ServerCommand>,
+) -> impl IntoResponse {
+    if let Err(rejection) = require_local(addr) {
+        return rejection;
+    }
+    match state.computers.send_command(computer_id, command).await {
+        Ok(()) => (StatusCode::OK, String::from("ok")),
+        Err(e) => {
+            let status = match e {
+                CommandError::Invalid(_) => StatusCode::BAD_REQUEST,
+                CommandError::UnknownComputer(_) => StatusCode::NOT_FOUND,
+                CommandError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+                CommandError::Disconnected | CommandError::Rejected(_) => StatusCode::BAD_GATEWAY,
+            };
+            (status, e.to_string())
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct CallRequest {
+    peripheral: String,
+    method: String,
+    #[serde(default)]
+    args: Vec<serde_json::Value>,
+}
+
+/// Calls a peripheral method on a connected computer and returns whatever it returned
+async fn call_handler(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(computer_id): Path<i64>,
+    State(state): State<AppState>,
+    Json(call): Json<CallRequest>,
+) -> Result<Json<Vec<serde_json::Value>>, (StatusCode, String)> {
+    require_local(addr)?;
+    state.computers.call(computer_id, &call.peripheral, &call.method, call.args).await
+        .map(Json)
+        .map_err(|e| {
+            let status = match e {
+                RpcError::UnknownComputer(_) | RpcError::NoSuchPeripheral(_) => StatusCode::NOT_FOUND,
+                RpcError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+                RpcError::Disconnected | RpcError::CallFailed(_) | RpcError::InvalidResponse(_) => StatusCode::BAD_GATEWAY,
+            };
+            (status, e.to_string())
+        })
+}
+
+/// Moves items out of a storage, see [ItemRouter::transfer]
+async fn transfer_handler(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    State(state): State<AppState>,
+    Json(request): Json<TransferRequest>,
+) -> Result<Json<TransferOutcome>, (StatusCode, String)> {
+    require_local(addr)?;
+    state.router.transfer(&request).await
+        .map(Json)
+        .map_err(|e| {
+            let status = match e {
+                TransferError::InvalidCount(_) => StatusCode::BAD_REQUEST,
+                TransferError::UnknownStorage(_) => StatusCode::NOT_FOUND,
+                TransferError::NotEnoughItems { .. } => StatusCode::CONFLICT,
+                TransferError::Rpc(RpcError::Timeout) => StatusCode::GATEWAY_TIMEOUT,
+                TransferError::Rpc(_) => StatusCode::BAD_GATEWAY,
+            };
+            (status, e.to_string())
+        })
+}
+
+#[derive(Debug, Deserialize)]
+struct CraftingPlanQuery {
+    item: String,
+    #[serde(default = "default_crafting_count")]
+    count: i64,
 }
 
-async fn handle_socket(mut socket: WebSocket, addr: SocketAddr, manager: Arc<InventoryManager>) {
+fn default_crafting_count() -> i64 {
+    1
+}
+
+/// Prometheus text format, for scraping into Grafana
+async fn metrics_handler(State(state): State<AppState>) -> impl IntoResponse {
+    let scrape = Scrape {
+        connections: state.connections.list().await,
+        reports_ingested: state.manager.reports_ingested(),
+        manager_memory_bytes: state.manager.memory_usage().await,
+        stock: state.manager.get_storage_stock().await,
+        rates: state.manager.get_rates(state.config.current().report_window()).await,
+    };
+    ([(CONTENT_TYPE, "text/plain; version=0.0.4")], metrics::render(&state.metrics, &scrape))
+}
+
+async fn computers_handler(State(state): State<AppState>) -> Json<Vec<ConnectionInfo>> {
+    Json(state.connections.list().await)
+}
+
+/// Open connections of one computer
+async fn computer_handler(
+    Path(computer_id): Path<i64>,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<ConnectionInfo>>, StatusCode> {
+    let connections = state.connections.get(computer_id).await;
+    if connections.is_empty() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    Ok(Json(connections))
+}
+
+#[derive(Debug, Deserialize)]
+struct WindowQuery {
+    /// i.e. `300s`, `5m` or `1h`, plain numbers are seconds
+    window: Option<String>,
+}
+
+impl WindowQuery {
+    fn window(&self, default: Duration) -> Result<Duration, (StatusCode, String)> {
+        self.window.as_deref().map_or(Ok(default), parse_window)
+            .map_err(|e| (StatusCode::BAD_REQUEST, e))
+    }
+}
+
+fn parse_window(window: &str) -> Result<Duration, String> {
+    let (number, multiplier) = match window.char_indices().last() {
+        Some((i, 's')) => (&window[..i], 1),
+        Some((i, 'm')) => (&window[..i], 60),
+        Some((i, 'h')) => (&window[..i], 60 * 60),
+        _ => (window, 1),
+    };
+    number.parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("Invalid window `{}`, expected something like 300s, 5m or 1h", window))
+}
+
+/// Rates or stock of one computer's inventory over the window
+async fn inventory_handler(
+    Path(computer_id): Path<i64>,
+    Query(query): Query<WindowQuery>,
+    State(state): State<AppState>,
+) -> Result<Json<InventoryManagerReport>, (StatusCode, String)> {
+    let window = query.window(state.config.current().report_window())?;
+    state.manager.get_report(computer_id, window).await
+        .map(|report| Json(report.sorted()))
+        .ok_or((StatusCode::NOT_FOUND, format!("No reports from computer {} in the last {}s", computer_id, window.as_secs())))
+}
+
+#[derive(Debug, Serialize)]
+struct ItemStock {
+    #[serde(flatten)]
+    total: InventoryItemCount,
+    storages: Vec<ItemLocation>,
+}
+
+/// How many of an item there are across all storages, and where
+async fn item_handler(
+    Path(name): Path<String>,
+    State(state): State<AppState>,
+) -> Json<ItemStock> {
+    let mut storages = state.manager.find_item(&name).await;
+    storages.sort_by_key(|s| std::cmp::Reverse(s.count));
+    let count = storages.iter().map(|s| s.count).sum();
+    Json(ItemStock {
+        total: InventoryItemCount { name, count },
+        storages,
+    })
+}
+
+/// Rates of every input and output computer over the window
+async fn rates_handler(
+    Query(query): Query<WindowQuery>,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<ComputerRates>>, (StatusCode, String)> {
+    let window = query.window(state.config.current().report_window())?;
+    Ok(Json(state.manager.get_rates(window).await))
+}
+
+#[derive(Debug, Deserialize)]
+struct EventsQuery {
+    /// comma separated, i.e. `reports,alerts`, every topic if left out
+    topics: Option<String>,
+}
+
+/// Server sent events stream of everything happening on the server, filtered by topic
+async fn events_handler(
+    Query(query): Query<EventsQuery>,
+    State(state): State<AppState>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, (StatusCode, String)> {
+    let topics = events::parse_topics(query.topics.as_deref()).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    info!("Event subscriber for {:?}", topics);
+    let stream = futures::stream::unfold(state.events.subscribe(), move |mut receiver| {
+        let topics = topics.clone();
+        async move {
+            loop {
+                let event = match receiver.recv().await {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(missed)) => {
+                        return Some((Ok(Event::default().comment(format!("missed {} events", missed))), receiver));
+                    }
+                    Err(RecvError::Closed) => return None,
+                };
+                if !topics.contains(&event.topic()) {
+                    continue;
+                }
+                let Ok(sse_event) = Event::default().event(event.topic().name()).json_data(&event).map_err(|e| {
+                    error!("Failed to serialize event: {}", e);
+                }) else {
+                    continue;
+                };
+                return Some((Ok(sse_event), receiver));
+            }
+        }
+    });
+    // the stream would otherwise keep the server from shutting down
+    let stream = stream.take_until(state.shutdown.cancelled_owned());
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+#[derive(Debug, Serialize)]
+struct ReportErrorResponse {
+    #[serde(flatten)]
+    error: ReportError,
+    message: String,
+}
+
+/// Takes an inventory report from a computer that doesn't have a monitor
+async fn report_handler(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    report: Result<Json<InventoryReport>, JsonRejection>,
+) -> Result<StatusCode, (StatusCode, Json<ReportErrorResponse>)> {
+    let reject = |status, error: ReportError| {
+        warn!("Rejected inventory report: {}", error);
+        (status, Json(ReportErrorResponse { message: error.to_string(), error }))
+    };
+    let Json(report) = report.map_err(|e| {
+        reject(e.status(), ReportError::Malformed { reason: e.body_text() })
+    })?;
+    let token = headers.get(TOKEN_HEADER).and_then(|t| t.to_str().ok());
+    state.tokens.check(token, report.computer_id).await.map_err(|e| {
+        warn!("Rejected report from {addr}: {e}");
+        reject(StatusCode::UNAUTHORIZED, ReportError::Unauthorized { reason: e.to_string() })
+    })?;
+    report.validate().map_err(|e| reject(StatusCode::UNPROCESSABLE_ENTITY, e))?;
+    state.manager.get_sender().send(report).await
+        .map_err(|_| reject(StatusCode::SERVICE_UNAVAILABLE, ReportError::Unavailable))?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// Plans how to craft an item from what is currently in storage
+async fn crafting_plan_handler(
+    State(state): State<AppState>,
+    Query(query): Query<CraftingPlanQuery>,
+) -> Result<Json<CraftingPlan>, (StatusCode, String)> {
+    if query.count <= 0 {
+        return Err((StatusCode::BAD_REQUEST, format!("count must be positive, not {}", query.count)));
+    }
+    let stock = state.manager.get_storage_totals().await;
+    crafting::plan(&state.recipes, &stock, &query.item, query.count)
+        .map(Json)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))
+}
+
+async fn stock_jobs_handler(State(state): State<AppState>) -> Json<Vec<StockJob>> {
+    Json(state.stock_keeper.get_jobs().await)
+}
+
+async fn turtles_handler(State(state): State<AppState>) -> Json<Vec<TurtleStatus>> {
+    Json(state.turtles.get_fleet().await)
+}
+
+async fn turtle_history_handler(
+    Path(computer_id): Path<i64>,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<TurtleHistoryEntry>>, StatusCode> {
+    state.turtles.get_history(computer_id).await.map(Json).ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn turtle_tasks_handler(
+    Path(computer_id): Path<i64>,
+    State(state): State<AppState>,
+) -> Json<Vec<QueuedTask>> {
+    Json(state.tasks.get_tasks(computer_id).await)
+}
+
+#[derive(Debug, Deserialize)]
+struct QueueTaskRequest {
+    task: TurtleTask,
+    #[serde(default)]
+    max_retries: u32,
+}
+
+/// Queues a task for a turtle, returning the task's id
+async fn queue_turtle_task_handler(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(computer_id): Path<i64>,
+    State(state): State<AppState>,
+    Json(request): Json<QueueTaskRequest>,
+) -> Result<Json<u64>, (StatusCode, String)> {
+    require_local(addr)?;
+    Ok(Json(state.tasks.enqueue(computer_id, request.task, request.max_retries).await))
+}
+
+async fn cancel_turtle_task_handler(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path((computer_id, task_id)): Path<(i64, u64)>,
+    State(state): State<AppState>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    require_local(addr)?;
+    state.tasks.cancel(computer_id, task_id).await
+        .map_err(|e| {
+            let status = match e {
+                TaskError::UnknownTask(_) => StatusCode::NOT_FOUND,
+                TaskError::AlreadyFinished(_) => StatusCode::CONFLICT,
+            };
+            (status, e.to_string())
+        })?;
+    state.mining.task_finished(computer_id, task_id, &TaskStatus::Cancelled).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn mining_jobs_handler(State(state): State<AppState>) -> Json<Vec<MiningProgress>> {
+    Json(state.mining.get_jobs().await)
+}
+
+async fn mining_job_handler(
+    Path(job_id): Path<u64>,
+    State(state): State<AppState>,
+) -> Result<Json<MiningJob>, StatusCode> {
+    state.mining.get_job(job_id).await.map(Json).ok_or(StatusCode::NOT_FOUND)
+}
+
+#[derive(Debug, Deserialize)]
+struct MiningJobRequest {
+    from: BlockPosition,
+    to: BlockPosition,
+    turtles: Vec<i64>,
+}
+
+/// Starts mining out a region, returning the job's id
+async fn start_mining_job_handler(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    State(state): State<AppState>,
+    Json(request): Json<MiningJobRequest>,
+) -> Result<Json<u64>, (StatusCode, String)> {
+    require_local(addr)?;
+    state.mining.start(request.from, request.to, request.turtles).await
+        .map(Json)
+        .map_err(mining_error_response)
+}
+
+async fn stop_mining_job_handler(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(job_id): Path<u64>,
+    State(state): State<AppState>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    require_local(addr)?;
+    state.mining.stop(job_id).await
+        .map(|()| StatusCode::NO_CONTENT)
+        .map_err(mining_error_response)
+}
+
+fn mining_error_response(e: MiningError) -> (StatusCode, String) {
+    let status = match e {
+        MiningError::NoTurtles => StatusCode::BAD_REQUEST,
+        MiningError::UnknownJob(_) => StatusCode::NOT_FOUND,
+        MiningError::Io(_) | MiningError::Parse(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    (status, e.to_string())
+}
+
+async fn get_dig_policy_handler(State(state): State<AppState>) -> Json<DigPolicy> {
+    Json(state.world.get_policy().await)
+}
+
+async fn set_dig_policy_handler(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    State(state): State<AppState>,
+    Json(policy): Json<DigPolicy>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    require_local(addr)?;
+    info!("Setting dig policy to {:?}", policy);
+    state.world.set_policy(policy).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn turtle_handler(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    ws: WebSocketUpgrade,
+    user_agent: Option<TypedHeader<headers::UserAgent>>,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> Response {
+    info!("Turtle at {addr} connected.");
+    let user_agent = user_agent.map(|TypedHeader(user_agent)| user_agent.to_string());
+    let token = match header_token(&headers, &state, addr).await {
+        Ok(token) => token,
+        Err(rejection) => return rejection.into_response(),
+    };
+    ws.on_upgrade(move |socket| handle_turtle_socket(socket, addr, user_agent, token, state))
+}
+
+async fn handle_turtle_socket(socket: WebSocket, addr: SocketAddr, user_agent: Option<String>, header_token: Option<String>, state: AppState) {
+    let _session = state.sessions.token();
+    let (mut socket_sender, mut socket_receiver) = socket.split();
+    let (event_writer, mut event_receiver) = tokio::sync::mpsc::unbounded_channel::<TurtleBackendEvent>();
+    // the writer owns the socket, so it is the one to close it when the turtle is rejected
+    let (close_writer, mut close_receiver) = tokio::sync::oneshot::channel::<CloseFrame>();
+    let settings = state.config.current();
+    let heartbeat_interval = settings.heartbeat_interval();
+    let heartbeat_timeout = settings.heartbeat_timeout();
+    let mut supervisor = Supervisor::new(format!("turtle at {addr}"));
+    let shutdown = state.shutdown.clone();
+    let metrics = state.metrics.clone();
+    supervisor.spawn(async move {
+        let mut heartbeat = tokio::time::interval(heartbeat_interval);
+        heartbeat.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            let event = select! {
+                event = event_receiver.recv() => event,
+                _ = heartbeat.tick() => {
+                    socket_sender.send(Message::Ping(Default::default())).await.map_err(SessionError::Send)?;
+                    continue;
+                }
+                _ = shutdown.cancelled() => {
+                    let close = CloseFrame { code: close_code::AWAY, reason: "server shutting down".into() };
+                    socket_sender.send(Message::Close(Some(close))).await.ok();
+                    return Err(SessionError::Shutdown);
+                }
+                Ok(close) = &mut close_receiver => {
+                    socket_sender.send(Message::Close(Some(close))).await.ok();
+                    return Ok(());
+                }
+            };
+            let Some(event) = event else {
+                return Ok(());
+            };
+            metrics.message(ConnectionRole::Turtle, Direction::Out, event.kind());
+            let Ok(data) = serde_json::to_string(&event).map_err(|e| {
+                error!("Failed to serialize turtle event: {}", e);
+            }) else {
+                continue;
+            };
+            socket_sender.send(Message::Text(data.into())).await.map_err(SessionError::Send)?;
+        }
+    });
+
+    let mut computer_id = None;
+    let mut connection: Option<Connection> = None;
+    let result = loop {
+        let msg = select! {
+            msg = tokio::time::timeout(heartbeat_timeout, socket_receiver.next()) => msg,
+            _ = supervisor.cancelled() => break Ok(()),
+        };
+        let Ok(msg) = msg else {
+            break Err(SessionError::Timeout(heartbeat_timeout));
+        };
+        let msg = match msg {
+            Some(Ok(msg)) => msg,
+            Some(Err(e)) => break Err(SessionError::Read(e)),
+            None => break Err(SessionError::Closed),
+        };
+        if let Some(connection) = &connection {
+            connection.touch();
+        }
+        let text = match msg {
+            Message::Text(text) => text,
+            Message::Close(_) => break Err(SessionError::Closed),
+            _ => continue,
+        };
+        let Ok(event) = serde_json::from_str::<TurtleInputEvent>(&text).map_err(|e| {
+            error!("Failed to deserialize turtle message: {}| {}", e, text);
+        }) else {
+            continue;
+        };
+        state.metrics.message(ConnectionRole::Turtle, Direction::In, event.kind());
+        if let TurtleInputEvent::TurtleRegister { computer_id: id, label, protocol_version, token } = event {
+            if let Err(e) = state.tokens.check(header_token.as_deref().or(token.as_deref()), id).await {
+                warn!("Rejected turtle {id} at {addr}: {e}");
+                close_writer.send(CloseFrame { code: close_code::POLICY, reason: e.to_string().into() }).ok();
+                supervisor.cancelled().await;
+                break Ok(());
+            }
+            info!("Registering turtle {id} ({label:?}) at {addr}");
+            if let Some(connection) = connection.take() {
+                connection.unregister().await;
+            }
+            let registered = state.connections.register(ConnectionInfo {
+                connection_id: 0,
+                computer_id: id,
+                name: label.clone(),
+                role: ConnectionRole::Turtle,
+                peer_addr: addr,
+                user_agent: user_agent.clone(),
+                connected_at: 0,
+                last_message_at: 0,
+                protocol_version,
+                queue_depth: 0,
+                dropped_frames: 0,
+            }).await;
+            state.turtles.connected(id, label, registered.id()).await;
+            connection = Some(registered);
+            if let Some((task_id, status)) = state.tasks.attach(id, event_writer.clone()).await {
+                turtle_task_finished(&state, id, task_id, &status).await;
+            }
+            state.mining.turtle_online(id).await;
+            computer_id = Some(id);
+            continue;
+        }
+        let Some(id) = computer_id else {
+            error!("Turtle at {addr} sent {:?} before registering", event);
+            continue;
+        };
+        match event {
+            // registering is handled above, but don't take the session down if that ever changes
+            TurtleInputEvent::TurtleRegister { .. } => {
+                error!("Turtle {id} at {addr} sent a registration that wasn't handled");
+            }
+            TurtleInputEvent::TurtleTelemetry(telemetry) => {
+                if telemetry.computer_id != id {
+                    error!("Turtle {id} at {addr} sent telemetry for {}", telemetry.computer_id);
+                    continue;
+                }
+                state.turtles.record(telemetry).await;
+            }
+            TurtleInputEvent::TaskProgress { id: task_id, progress } => {
+                state.tasks.progress(id, task_id, progress).await;
+            }
+            TurtleInputEvent::TaskFinished { id: task_id, success, error } => {
+                let result = if success {
+                    Ok(())
+                } else {
+                    Err(error.unwrap_or_else(|| String::from("unknown error")))
+                };
+                if let Some(status) = state.tasks.finished(id, task_id, result).await {
+                    turtle_task_finished(&state, id, task_id, &status).await;
+                }
+            }
+            TurtleInputEvent::BlockObservations(observations) => {
+                state.world.observe(observations).await;
+            }
+            TurtleInputEvent::PathRequest { id: request_id, from, to, policy } => {
+                let world = state.world.clone();
+                let event_writer = event_writer.clone();
+                supervisor.spawn_background(async move {
+                    let path = world.find_path(from, to, policy).await;
+                    event_writer.send(TurtleBackendEvent::Path { id: request_id, path }).ok();
+                });
+            }
+        }
+    };
+    if let Some(connection) = connection {
+        let computer_id = connection.computer_id();
+        // a turtle that reconnected before this socket timed out is left to its new session
+        if state.turtles.disconnected(computer_id, connection.id()).await {
+            state.tasks.detach(computer_id, &event_writer).await;
+            state.mining.turtle_offline(computer_id).await;
+        }
+        connection.unregister().await;
+    }
+    supervisor.end(result).await;
+}
+
+/// Passes on the final status of a turtle's task
+async fn turtle_task_finished(state: &AppState, computer_id: i64, task_id: u64, status: &TaskStatus) {
+    if let TaskStatus::Failed(reason) = status {
+        state.events.publish(ServerEvent::alert("turtle_tasks", format!("Turtle {} gave up on task {}: {}", computer_id, task_id, reason)));
+    }
+    state.mining.task_finished(computer_id, task_id, status).await;
+}
+
+async fn handle_socket(mut socket: WebSocket, addr: SocketAddr, user_agent: Option<String>, header_token: Option<String>, state: AppState) {
+    // shutdown waits for this session to say goodbye
+    let _session = state.sessions.token();
     // Handle the WebSocket connection here
     info!("WebSocket connection established with {addr}");
     // You can send and receive messages using the `socket` object
     // For example, you can send a message to the client:
 
-    let (event_writer, event_receiver) = tokio::sync::mpsc::unbounded_channel::<CCTweakedMonitorBackendEvent>();
     let Some(initial_monitor_size) = socket.recv().await else {
         error!("Didnt receive initial monitor size");
         return;
@@ -105,10 +1047,18 @@ async fn handle_socket(mut socket: WebSocket, addr: SocketAddr, manager: Arc<Inv
     }) else {
         return;
     };
-    let (computer_id, common_name, size) = match initial_monitor_size {
-        CCTweakedMonitorInputEvent::InventoryRegister { size, computer_id, common_name} => {
-            info!("Registering computer id {computer_id} with common name {common_name}");
-            (computer_id, common_name, size)
+    state.metrics.message(ConnectionRole::Monitor, Direction::In, initial_monitor_size.kind());
+    let register = initial_monitor_size.clone();
+    let (computer_id, common_name, size, view, protocol_version) = match initial_monitor_size {
+        CCTweakedMonitorInputEvent::InventoryRegister { size, computer_id, common_name, view, protocol_version, token } => {
+            if let Err(e) = state.tokens.check(header_token.as_deref().or(token.as_deref()), computer_id).await {
+                warn!("Rejected computer {computer_id} ({common_name}) at {addr}: {e}");
+                let close = CloseFrame { code: close_code::POLICY, reason: e.to_string().into() };
+                socket.send(Message::Close(Some(close))).await.ok();
+                return;
+            }
+            info!("Registering computer id {computer_id} with common name {common_name} asking for {view:?}");
+            (computer_id, common_name, size, view, protocol_version)
         }
         _ => {
             error!("Expected monitor resize event, got: {:?}", initial_monitor_size);
@@ -116,96 +1066,112 @@ async fn handle_socket(mut socket: WebSocket, addr: SocketAddr, manager: Arc<Inv
         }
     };
         
-    let terminal_backend = CCTweakedMonitorBackend::new(event_writer, size);
+    let (socket_sender, socket_receiver) = socket.split();
+
+    let (command_writer, command_receiver) = tokio::sync::mpsc::unbounded_channel::<ComputerRequest>();
+    let computer = ComputerHandle::new(computer_id, command_writer);
+    state.computers.register(computer.clone()).await;
+
+    let connection = state.connections.register(ConnectionInfo {
+        connection_id: 0,
+        computer_id,
+        name: Some(common_name.clone()),
+        role: ConnectionRole::Monitor,
+        peer_addr: addr,
+        user_agent,
+        connected_at: 0,
+        last_message_at: 0,
+        protocol_version,
+        queue_depth: 0,
+        dropped_frames: 0,
+    }).await;
+
+    // a monitor that can't keep up gets fewer frames instead of the server buffering them all
+    let (frame_writer, frame_receiver) = tokio::sync::mpsc::channel::<MonitorFrame>(MONITOR_FRAME_QUEUE);
+    let terminal_backend = CCTweakedMonitorBackend::new(frame_writer, connection.queue(), state.metrics.clone(), size);
     let Ok(terminal) = Terminal::new(terminal_backend).map_err(|e| {
         error!("Failed to create terminal: {}", e);
     }) else {
+        state.computers.unregister(&computer).await;
+        connection.unregister().await;
         return;
     };
-
-    let (socket_sender, socket_receiver) = socket.split();
-
     let terminal = Arc::new(Mutex::new(terminal));
 
-    // input and output handlers can see the websocket is closed, but the terminal writer cant,
-    // so we need to send a hangup signal to the terminal writer when the websocket is closed to avoid
-    // leaking tasks
-    let (hangup_sender, hangup_receiver) = tokio::sync::oneshot::channel();
-
-    let input_handler = MonitorInputHandler::new(socket_receiver, terminal.clone());
-    let manager_sender = manager.get_sender();
-    tokio::spawn(async move {
-        input_handler.handle_inbound(manager_sender).await;
-    });
-
-    let output_handler = MonitorOutputHandler::new(event_receiver, socket_sender, hangup_sender);
-    tokio::spawn(async move {
-        output_handler.handle_outbound().await;
-    });
-
-    select! {
-        _ = write_inventory_manager_rate_report_to_terminal(terminal.clone(), manager, computer_id, common_name) => {},
-        _ = hangup_receiver => {
-            info!("Hangup received, closing terminal");
-        }
+    let settings = state.config.current();
+    let recorder = settings.record_dir.as_ref().and_then(|dir| {
+        Recorder::create(dir, computer_id).map_err(|e| {
+            error!("Failed to start recording computer {computer_id}: {}", e);
+        }).ok()
+    }).map(Arc::new);
+    if let Some(recorder) = &recorder {
+        info!("Recording computer {computer_id} to {}", recorder.path().display());
+        recorder.inbound(&register);
     }
-
-
+    let mut supervisor = Supervisor::new(format!("computer {computer_id} ({common_name})"));
+    let input_handler = MonitorInputHandler::new(socket_receiver, terminal.clone(), computer.clone(), connection.clone(), state.metrics.clone(), recorder.clone(), settings.heartbeat_timeout());
+    supervisor.spawn(input_handler.handle_inbound(state.manager.get_sender()));
+    let output_handler = MonitorOutputHandler::new(frame_receiver, command_receiver, socket_sender, connection.clone(), state.metrics.clone(), recorder.clone(), settings.heartbeat_interval());
+    supervisor.spawn(output_handler.handle_outbound(state.shutdown.clone()));
+    supervisor.spawn(run_view(state.clone(), terminal, computer_id, common_name, view));
+    supervisor.join().await;
+    // the handlers are gone with the session, so this is the last reference
+    if let Some(recorder) = recorder.and_then(Arc::into_inner) {
+        recorder.finish().await;
+    }
+    state.computers.unregister(&computer).await;
+    connection.unregister().await;
 }
 
-
-async fn write_inventory_manager_rate_report_to_terminal(terminal: Arc<Mutex<Terminal<CCTweakedMonitorBackend>>>, manager: Arc<InventoryManager>, computer_id: i64, common_name: String) {
-    let mut timer = tokio::time::interval(Duration::from_millis(1000));
+/// Draws the monitor's view until drawing fails. The view is restarted with the new settings
+/// whenever the config changes, the socket stays open.
+async fn run_view(
+    state: AppState,
+    terminal: Arc<Mutex<Terminal<CCTweakedMonitorBackend>>>,
+    computer_id: i64,
+    common_name: String,
+    requested_view: Option<MonitorView>,
+) -> Result<(), SessionError> {
+    let mut config = state.config.subscribe();
+    let mut current_view = None;
     loop {
-        timer.tick().await;
-        let Some(report) = manager.get_report(computer_id, Duration::from_secs(5 * 60)).await else {
-            // just havent received any reports yet
-            continue;
-        };
-        let display = match report {
-            InventoryManagerReport::Input(mut r) => {
-                r.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal).reverse());
-                List::new(r.iter().map(|item| {
-                    let text = Text::raw(format!("{}: {}", item.name, item.rate_per_second));
-                    text
-                })).block(Block::bordered().border_set(CCTWEAKED_BORDER).title(common_name.clone()))
-            }
-            InventoryManagerReport::Output(mut r)  => {
-                r.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal).reverse());
-                List::new(r.iter().map(|item| {
-                    let text = Text::raw(format!("{}: {}", item.name, item.rate_per_second));
-                    text
-                })).block(Block::bordered().border_set(CCTWEAKED_BORDER).title(common_name.clone()))
-            }
-            InventoryManagerReport::Storage(mut r) => {
-                r.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal).reverse());
-                List::new(r.iter().map(|item| {
-                    let text = Text::raw(format!("{}: {}", item.name, item.count));
-                    text
-                })).block(Block::bordered().border_set(CCTWEAKED_BORDER).title(common_name.clone()))
+        let settings = config.borrow_and_update().clone();
+        // a view assigned in the config wins over the one the computer asked for
+        let view = settings.view_for(computer_id).or(requested_view).unwrap_or_default();
+        if current_view.is_some_and(|current| current != view) {
+            info!("Computer {computer_id} now shows {view:?}");
+        }
+        current_view = Some(view);
+        let redraw_interval = settings.redraw_interval();
+        let view_task = async {
+            match view {
+                MonitorView::Inventory => views::write_inventory_manager_rate_report_to_terminal(terminal.clone(), state.manager.clone(), computer_id, common_name.clone(), settings.report_window(), redraw_interval, settings.stale_after()).await,
+                MonitorView::StockJobs => views::write_stock_jobs_to_terminal(terminal.clone(), state.stock_keeper.clone(), common_name.clone(), redraw_interval).await,
+                MonitorView::Fleet => views::write_fleet_to_terminal(terminal.clone(), state.turtles.clone(), common_name.clone(), redraw_interval).await,
             }
         };
-        let mut guard = terminal.lock().await;
-        let Ok(_frame) = guard.draw(|frame| {
-            frame.render_widget(display, frame.area());
-        }).map_err(|e| {
-            if e.to_string().contains("channel closed") {
-                return // normal disconnect
+        select! {
+            result = view_task => {
+                return result.map_err(|e| if cctweaked::is_disconnect(&e) { SessionError::Closed } else { SessionError::Draw(e) });
             }
-            error!("Failed to draw to terminal: {}", e);
-        }) else {
-            return;
-        };
+            changed = config.changed() => {
+                if changed.is_err() {
+                    return Ok(());
+                }
+            }
+        }
     }
 }
 
+
+#[allow(dead_code)]
 async fn write_hello_to_terminal(terminal: Arc<Mutex<Terminal<CCTweakedMonitorBackend>>>) {
     let mut i = 0;
     loop {
         tokio::time::sleep(tokio::time::Duration::from_millis(1000)).await;
         let mut guard = terminal.lock().await;
         let Ok(_frame) = guard.draw(|frame| render(frame, i) ).map_err(|e| {
-            if e.to_string().contains("channel closed") {
+            if cctweaked::is_disconnect(&e) {
                 return // normal disconnect
             }
             error!("Failed to draw to terminal: {}", e);
@@ -227,9 +1193,6 @@ fn render(frame: &mut Frame, i: i32) {
     frame.render_widget(table, frame.area());
 }
 
-/// MonitorInputHandler is responsible for receiving inbound events from minecraft entities
-
-
 pub const CCTWEAKED_BORDER: border::Set = border::Set {
     top_left: "🬕",
     top_right: "🬂",
@@ -262,4 +1225,22 @@ mod tests {
         terminal.draw(|f| render(f, 6)).unwrap();
         println!("Done")
     }
+
+    #[test]
+    fn test_emulator_page_has_code_page() {
+        let page = emulator_page();
+        assert!(!page.contains("CODE_PAGE_JSON"));
+        assert!(page.contains("const CODE_PAGE = Array.from(\"\\u0000☺"));
+    }
+
+    #[test]
+    fn test_parse_window() {
+        assert_eq!(parse_window("300s"), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_window("5m"), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_window("1h"), Ok(Duration::from_secs(3600)));
+        assert_eq!(parse_window("42"), Ok(Duration::from_secs(42)));
+        assert!(parse_window("m").is_err());
+        assert!(parse_window("-5s").is_err());
+        assert!(parse_window("5 minutes").is_err());
+    }
 }
\ No newline at end of file
@@ -0,0 +1,250 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Write};
+use std::sync::Mutex;
+use std::time::Duration;
+use crate::connections::{ConnectionInfo, ConnectionRole};
+use crate::inventory_manager::{ComputerRates, ComputerStock, InventoryManagerReport};
+
+/// Upper bounds of the draw duration histogram buckets, in seconds
+const DRAW_BUCKETS: [f64; 10] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0];
+
+/// Whether a message came from a computer or went to one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    In,
+    Out,
+}
+
+impl Direction {
+    fn name(self) -> &'static str {
+        match self {
+            Direction::In => "in",
+            Direction::Out => "out",
+        }
+    }
+}
+
+fn role_name(role: ConnectionRole) -> &'static str {
+    match role {
+        ConnectionRole::Monitor => "monitor",
+        ConnectionRole::Turtle => "turtle",
+    }
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    // not cumulative, one more than there are buckets for everything above the last one
+    counts: Vec<u64>,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        if self.counts.is_empty() {
+            self.counts = vec![0; DRAW_BUCKETS.len() + 1];
+        }
+        let bucket = DRAW_BUCKETS.iter().position(|bound| value <= *bound).unwrap_or(DRAW_BUCKETS.len());
+        self.counts[bucket] += 1;
+        self.sum += value;
+    }
+}
+
+/// Metrics collects what happens on the websockets, everything else is read from where it lives
+/// when `/metrics` is scraped
+#[derive(Debug, Default)]
+pub struct Metrics {
+    messages: Mutex<HashMap<(ConnectionRole, Direction, &'static str), u64>>,
+    bytes_sent: Mutex<HashMap<i64, u64>>,
+    draw_seconds: Mutex<Histogram>,
+}
+
+impl Metrics {
+    /// `kind` is the message type as it appears on the wire
+    pub fn message(&self, role: ConnectionRole, direction: Direction, kind: &'static str) {
+        *self.messages.lock().unwrap().entry((role, direction, kind)).or_insert(0) += 1;
+    }
+
+    pub fn bytes_sent(&self, computer_id: i64, bytes: usize) {
+        *self.bytes_sent.lock().unwrap().entry(computer_id).or_insert(0) += bytes as u64;
+    }
+
+    pub fn draw_took(&self, took: Duration) {
+        self.draw_seconds.lock().unwrap().observe(took.as_secs_f64());
+    }
+}
+
+/// Everything `/metrics` shows, gathered by the handler
+pub struct Scrape {
+    pub connections: Vec<ConnectionInfo>,
+    pub reports_ingested: u64,
+    pub manager_memory_bytes: usize,
+    pub stock: Vec<ComputerStock>,
+    pub rates: Vec<ComputerRates>,
+}
+
+/// Writes metrics in the Prometheus text format, one family at a time
+struct Exposition {
+    out: String,
+}
+
+impl Exposition {
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        writeln!(self.out, "# HELP {name} {help}").ok();
+        writeln!(self.out, "# TYPE {name} {kind}").ok();
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Display) {
+        self.out.push_str(name);
+        if !labels.is_empty() {
+            self.out.push('{');
+            for (i, (label, label_value)) in labels.iter().enumerate() {
+                if i > 0 {
+                    self.out.push(',');
+                }
+                write!(self.out, "{label}=\"{}\"", escape_label(label_value)).ok();
+            }
+            self.out.push('}');
+        }
+        writeln!(self.out, " {value}").ok();
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Renders the metrics collected so far along with `scrape`
+pub fn render(metrics: &Metrics, scrape: &Scrape) -> String {
+    let mut exposition = Exposition { out: String::new() };
+    let e = &mut exposition;
+
+    e.family("rustserver_connected_computers", "gauge", "Computers connected over a websocket.");
+    for role in [ConnectionRole::Monitor, ConnectionRole::Turtle] {
+        let connected = scrape.connections.iter().filter(|c| c.role == role).count();
+        e.sample("rustserver_connected_computers", &[("role", role_name(role))], connected);
+    }
+
+    e.family("rustserver_messages_total", "counter", "Websocket messages by type.");
+    let mut messages: Vec<_> = metrics.messages.lock().unwrap().iter().map(|(k, v)| (*k, *v)).collect();
+    messages.sort_by_key(|((role, direction, kind), _)| (role_name(*role), direction.name(), *kind));
+    for ((role, direction, kind), count) in messages {
+        e.sample("rustserver_messages_total", &[("role", role_name(role)), ("direction", direction.name()), ("type", kind)], count);
+    }
+
+    e.family("rustserver_monitor_bytes_sent_total", "counter", "Bytes of text and binary messages sent to a monitor.");
+    let mut bytes_sent: Vec<_> = metrics.bytes_sent.lock().unwrap().iter().map(|(k, v)| (*k, *v)).collect();
+    bytes_sent.sort();
+    for (computer_id, bytes) in bytes_sent {
+        e.sample("rustserver_monitor_bytes_sent_total", &[("computer_id", &computer_id.to_string())], bytes);
+    }
+
+    let monitors: Vec<_> = scrape.connections.iter().filter(|c| c.role == ConnectionRole::Monitor).collect();
+    // a computer can have more than one monitor connection, so these are per connection
+    e.family("rustserver_monitor_queue_depth", "gauge", "Frames drawn for a monitor but not sent yet, per connection.");
+    for monitor in &monitors {
+        let (computer_id, connection_id) = (monitor.computer_id.to_string(), monitor.connection_id.to_string());
+        e.sample("rustserver_monitor_queue_depth", &[("computer_id", &computer_id), ("connection_id", &connection_id)], monitor.queue_depth);
+    }
+    e.family("rustserver_monitor_dropped_frames_total", "counter", "Frames skipped because a monitor couldn't keep up, per connection.");
+    for monitor in &monitors {
+        let (computer_id, connection_id) = (monitor.computer_id.to_string(), monitor.connection_id.to_string());
+        e.sample("rustserver_monitor_dropped_frames_total", &[("computer_id", &computer_id), ("connection_id", &connection_id)], monitor.dropped_frames);
+    }
+
+    e.family("rustserver_draw_duration_seconds", "histogram", "Time taken to draw a frame to a monitor.");
+    let draws = metrics.draw_seconds.lock().unwrap();
+    let mut cumulative = 0;
+    for (i, bound) in DRAW_BUCKETS.iter().enumerate() {
+        cumulative += draws.counts.get(i).copied().unwrap_or(0);
+        e.sample("rustserver_draw_duration_seconds_bucket", &[("le", &bound.to_string())], cumulative);
+    }
+    cumulative += draws.counts.last().copied().unwrap_or(0);
+    e.sample("rustserver_draw_duration_seconds_bucket", &[("le", "+Inf")], cumulative);
+    e.sample("rustserver_draw_duration_seconds_sum", &[], draws.sum);
+    e.sample("rustserver_draw_duration_seconds_count", &[], cumulative);
+    drop(draws);
+
+    e.family("rustserver_reports_ingested_total", "counter", "Inventory reports taken in by the inventory manager.");
+    e.sample("rustserver_reports_ingested_total", &[], scrape.reports_ingested);
+    e.family("rustserver_inventory_manager_memory_bytes", "gauge", "Rough size of the reports kept by the inventory manager.");
+    e.sample("rustserver_inventory_manager_memory_bytes", &[], scrape.manager_memory_bytes);
+
+    e.family("rustserver_storage_items", "gauge", "Items in the latest report of a storage computer.");
+    for stock in &scrape.stock {
+        let computer_id = stock.computer_id.to_string();
+        for item in &stock.items {
+            e.sample("rustserver_storage_items", &[("computer_id", &computer_id), ("computer", &stock.common_name), ("item", &item.name)], item.count);
+        }
+    }
+
+    e.family("rustserver_item_rate_per_second", "gauge", "Items per second going through an input or output computer.");
+    for rates in &scrape.rates {
+        let computer_id = rates.computer_id.to_string();
+        let (direction, items) = match &rates.report {
+            InventoryManagerReport::Input(items) => ("input", items),
+            InventoryManagerReport::Output(items) => ("output", items),
+            InventoryManagerReport::Storage(_) => continue,
+        };
+        for item in items {
+            e.sample(
+                "rustserver_item_rate_per_second",
+                &[("computer_id", &computer_id), ("computer", &rates.common_name), ("item", &item.name), ("direction", direction)],
+                item.rate_per_second,
+            );
+        }
+    }
+    exposition.out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inventory_manager::InventoryItemCount;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        metrics.message(ConnectionRole::Monitor, Direction::In, "inventory_report");
+        metrics.message(ConnectionRole::Monitor, Direction::In, "inventory_report");
+        metrics.draw_took(Duration::from_millis(3));
+        metrics.draw_took(Duration::from_secs(2));
+        let monitor = |connection_id: u64, dropped_frames: u64| ConnectionInfo {
+            connection_id,
+            computer_id: 4,
+            name: None,
+            role: ConnectionRole::Monitor,
+            peer_addr: "127.0.0.1:1234".parse().unwrap(),
+            user_agent: None,
+            connected_at: 0,
+            last_message_at: 0,
+            protocol_version: None,
+            queue_depth: 0,
+            dropped_frames,
+        };
+        let scrape = Scrape {
+            // the same computer reconnected before its old connection went away
+            connections: vec![monitor(1, 3), monitor(2, 0)],
+            reports_ingested: 7,
+            manager_memory_bytes: 1024,
+            stock: vec![ComputerStock {
+                computer_id: 4,
+                common_name: "Main \"north\" chest".to_string(),
+                items: vec![InventoryItemCount { name: "minecraft:torch".to_string(), count: 12 }],
+            }],
+            rates: Vec::new(),
+        };
+        let text = render(&metrics, &scrape);
+        let lines: Vec<_> = text.lines().collect();
+        assert!(lines.contains(&"rustserver_connected_computers{role=\"turtle\"} 0"));
+        assert!(lines.contains(&"rustserver_monitor_dropped_frames_total{computer_id=\"4\",connection_id=\"1\"} 3"));
+        assert!(lines.contains(&"rustserver_monitor_dropped_frames_total{computer_id=\"4\",connection_id=\"2\"} 0"));
+        assert!(lines.contains(&"rustserver_messages_total{role=\"monitor\",direction=\"in\",type=\"inventory_report\"} 2"));
+        assert!(lines.contains(&"rustserver_draw_duration_seconds_bucket{le=\"0.0025\"} 0"));
+        assert!(lines.contains(&"rustserver_draw_duration_seconds_bucket{le=\"0.005\"} 1"));
+        assert!(lines.contains(&"rustserver_draw_duration_seconds_bucket{le=\"+Inf\"} 2"));
+        assert!(lines.contains(&"rustserver_draw_duration_seconds_count 2"));
+        assert!(lines.contains(&"rustserver_reports_ingested_total 7"));
+        assert!(lines.contains(&"rustserver_storage_items{computer_id=\"4\",computer=\"Main \\\"north\\\" chest\",item=\"minecraft:torch\"} 12"));
+        assert!(lines.contains(&"# TYPE rustserver_item_rate_per_second gauge"));
+    }
+}
@@ -0,0 +1,403 @@
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+use crate::turtle_manager::{now_seconds, BlockPosition, TurtleManager};
+use crate::turtle_tasks::{TaskStatus, TurtleTask, TurtleTaskQueue};
+
+/// Width of a minecraft chunk, work is handed out one chunk column at a time
+pub const CHUNK_SIZE: i64 = 16;
+/// How many times a turtle retries a chunk before it goes back to the pool
+const MINING_TASK_RETRIES: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ChunkPosition {
+    pub x: i64,
+    pub z: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChunkStatus {
+    Pending,
+    Assigned {
+        computer_id: i64,
+        task_id: u64,
+        /// seconds since the unix epoch
+        started: u64,
+    },
+    Done,
+}
+
+/// The part of a mining region that lies in one chunk
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkWork {
+    pub chunk: ChunkPosition,
+    pub from: BlockPosition,
+    pub to: BlockPosition,
+    pub status: ChunkStatus,
+}
+
+impl ChunkWork {
+    pub fn blocks(&self) -> i64 {
+        (self.to.x - self.from.x + 1) * (self.to.y - self.from.y + 1) * (self.to.z - self.from.z + 1)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MiningJob {
+    pub id: u64,
+    pub turtles: Vec<i64>,
+    pub chunks: Vec<ChunkWork>,
+    pub blocks_done: i64,
+    /// time turtles spent on the chunks that are done, used to work out the dig rate
+    pub busy_seconds: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MiningProgress {
+    pub id: u64,
+    pub turtles: Vec<i64>,
+    pub chunks: usize,
+    pub chunks_done: usize,
+    pub blocks: i64,
+    pub blocks_done: i64,
+    /// how fast a single turtle digs, None until a chunk is done
+    pub blocks_per_second: Option<f64>,
+    /// None when nothing is being worked on or there is no dig rate yet
+    pub seconds_left: Option<u64>,
+}
+
+impl MiningJob {
+    pub fn progress(&self) -> MiningProgress {
+        let blocks: i64 = self.chunks.iter().map(|c| c.blocks()).sum();
+        let active = self.chunks.iter().filter(|c| matches!(c.status, ChunkStatus::Assigned { .. })).count();
+        let blocks_per_second = (self.busy_seconds > 0).then(|| self.blocks_done as f64 / self.busy_seconds as f64);
+        let seconds_left = blocks_per_second
+            .filter(|rate| *rate > 0.0 && active > 0)
+            .map(|rate| ((blocks - self.blocks_done) as f64 / (rate * active as f64)).ceil() as u64);
+        MiningProgress {
+            id: self.id,
+            turtles: self.turtles.clone(),
+            chunks: self.chunks.len(),
+            chunks_done: self.chunks.iter().filter(|c| c.status == ChunkStatus::Done).count(),
+            blocks,
+            blocks_done: self.blocks_done,
+            blocks_per_second,
+            seconds_left,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum MiningError {
+    NoTurtles,
+    UnknownJob(u64),
+    Io(#[from] std::io::Error),
+    Parse(#[from] serde_json::Error),
+}
+
+impl Display for MiningError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MiningError::NoTurtles => write!(f, "A mining job needs at least one turtle"),
+            MiningError::UnknownJob(id) => write!(f, "No mining job with id {}", id),
+            MiningError::Io(e) => write!(f, "Failed to access mining jobs file: {}", e),
+            MiningError::Parse(e) => write!(f, "Failed to parse mining jobs: {}", e),
+        }
+    }
+}
+
+/// Splits the box between two corners along chunk borders, every piece is pending
+pub fn partition(from: BlockPosition, to: BlockPosition) -> Vec<ChunkWork> {
+    let (min_x, max_x) = (from.x.min(to.x), from.x.max(to.x));
+    let (min_y, max_y) = (from.y.min(to.y), from.y.max(to.y));
+    let (min_z, max_z) = (from.z.min(to.z), from.z.max(to.z));
+    let mut chunks = Vec::new();
+    for chunk_x in min_x.div_euclid(CHUNK_SIZE)..=max_x.div_euclid(CHUNK_SIZE) {
+        for chunk_z in min_z.div_euclid(CHUNK_SIZE)..=max_z.div_euclid(CHUNK_SIZE) {
+            chunks.push(ChunkWork {
+                chunk: ChunkPosition { x: chunk_x, z: chunk_z },
+                from: BlockPosition {
+                    x: min_x.max(chunk_x * CHUNK_SIZE),
+                    y: min_y,
+                    z: min_z.max(chunk_z * CHUNK_SIZE),
+                },
+                to: BlockPosition {
+                    x: max_x.min(chunk_x * CHUNK_SIZE + CHUNK_SIZE - 1),
+                    y: max_y,
+                    z: max_z.min(chunk_z * CHUNK_SIZE + CHUNK_SIZE - 1),
+                },
+                status: ChunkStatus::Pending,
+            });
+        }
+    }
+    chunks
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct MiningState {
+    next_id: u64,
+    jobs: Vec<MiningJob>,
+}
+
+/// MiningPlanner splits mining regions into chunks and hands them out to turtles as dig tasks,
+/// one chunk per turtle at a time. Chunks of turtles that fail or go offline go back to the
+/// pool for the other turtles. Jobs are saved after every change.
+pub struct MiningPlanner {
+    state: Mutex<MiningState>,
+    path: PathBuf,
+    tasks: Arc<TurtleTaskQueue>,
+    turtles: Arc<TurtleManager>,
+}
+
+impl MiningPlanner {
+    /// Loads the jobs saved at `path`. Turtles lose their task queue on restart, so chunks that
+    /// were being worked on are pending again.
+    pub fn load(path: impl AsRef<Path>, tasks: Arc<TurtleTaskQueue>, turtles: Arc<TurtleManager>) -> Result<Self, MiningError> {
+        let path = path.as_ref().to_path_buf();
+        let mut state = match std::fs::read_to_string(&path) {
+            Ok(data) => serde_json::from_str::<MiningState>(&data)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => MiningState::default(),
+            Err(e) => return Err(e.into()),
+        };
+        for chunk in state.jobs.iter_mut().flat_map(|j| j.chunks.iter_mut()) {
+            if matches!(chunk.status, ChunkStatus::Assigned { .. }) {
+                chunk.status = ChunkStatus::Pending;
+            }
+        }
+        Ok(MiningPlanner { state: Mutex::new(state), path, tasks, turtles })
+    }
+
+    pub async fn get_jobs(&self) -> Vec<MiningProgress> {
+        self.state.lock().await.jobs.iter().map(MiningJob::progress).collect()
+    }
+
+    pub async fn get_job(&self, id: u64) -> Option<MiningJob> {
+        self.state.lock().await.jobs.iter().find(|j| j.id == id).cloned()
+    }
+
+    /// Starts mining out the box between two corners with the given turtles, returns the job id
+    pub async fn start(&self, from: BlockPosition, to: BlockPosition, turtles: Vec<i64>) -> Result<u64, MiningError> {
+        if turtles.is_empty() {
+            return Err(MiningError::NoTurtles);
+        }
+        let mut state = self.state.lock().await;
+        state.next_id += 1;
+        let id = state.next_id;
+        let chunks = partition(from, to);
+        info!("Starting mining job {id}: {} chunks for turtles {:?}", chunks.len(), turtles);
+        state.jobs.push(MiningJob {
+            id,
+            turtles: turtles.clone(),
+            chunks,
+            blocks_done: 0,
+            busy_seconds: 0,
+        });
+        self.assign_idle(&mut state, &turtles, None).await;
+        self.save(&state).await?;
+        Ok(id)
+    }
+
+    /// Stops a job, cancelling the dig tasks of its turtles
+    pub async fn stop(&self, id: u64) -> Result<(), MiningError> {
+        let mut state = self.state.lock().await;
+        let index = state.jobs.iter().position(|j| j.id == id).ok_or(MiningError::UnknownJob(id))?;
+        let job = state.jobs.remove(index);
+        for chunk in &job.chunks {
+            if let ChunkStatus::Assigned { computer_id, task_id, .. } = chunk.status {
+                self.tasks.cancel(computer_id, task_id).await.ok();
+            }
+        }
+        info!("Stopped mining job {id}");
+        self.save(&state).await
+    }
+
+    /// Gives a turtle that just connected something to do
+    pub async fn turtle_online(&self, computer_id: i64) {
+        let mut state = self.state.lock().await;
+        if self.assign_idle(&mut state, &[computer_id], None).await {
+            self.save_or_warn(&state).await;
+        }
+    }
+
+    /// Takes the chunk away from a turtle that disconnected and hands it to someone else. A
+    /// turtle that already reconnected keeps its chunk.
+    pub async fn turtle_offline(&self, computer_id: i64) {
+        if self.turtles.is_connected(computer_id).await {
+            return;
+        }
+        let mut state = self.state.lock().await;
+        let mut changed = false;
+        let mut others = Vec::new();
+        for job in &mut state.jobs {
+            for chunk in &mut job.chunks {
+                let ChunkStatus::Assigned { computer_id: assigned, task_id, .. } = chunk.status else {
+                    continue;
+                };
+                if assigned != computer_id {
+                    continue;
+                }
+                warn!("Turtle {computer_id} went offline, chunk {:?} of mining job {} is up for grabs", chunk.chunk, job.id);
+                self.tasks.cancel(computer_id, task_id).await.ok();
+                chunk.status = ChunkStatus::Pending;
+                others.extend(job.turtles.iter().copied());
+                changed = true;
+            }
+        }
+        self.assign_idle(&mut state, &others, Some(computer_id)).await;
+        if changed {
+            self.save_or_warn(&state).await;
+        }
+    }
+
+    /// Called with the final status of every turtle task, anything but a mining task is ignored
+    pub async fn task_finished(&self, computer_id: i64, task_id: u64, status: &TaskStatus) {
+        let mut state = self.state.lock().await;
+        let now = now_seconds();
+        let mut found = None;
+        for job in &mut state.jobs {
+            let Some(chunk) = job.chunks.iter_mut().find(|c| matches!(
+                c.status,
+                ChunkStatus::Assigned { computer_id: c_id, task_id: t_id, .. } if c_id == computer_id && t_id == task_id
+            )) else {
+                continue;
+            };
+            let ChunkStatus::Assigned { started, .. } = chunk.status else {
+                continue;
+            };
+            if *status == TaskStatus::Done {
+                chunk.status = ChunkStatus::Done;
+                job.blocks_done += chunk.blocks();
+                job.busy_seconds += now.saturating_sub(started);
+                info!("Turtle {computer_id} finished chunk {:?} of mining job {}", chunk.chunk, job.id);
+            } else {
+                warn!("Turtle {computer_id} gave up on chunk {:?} of mining job {}: {:?}", chunk.chunk, job.id, status);
+                chunk.status = ChunkStatus::Pending;
+            }
+            found = Some(job.turtles.clone());
+            break;
+        }
+        let Some(turtles) = found else {
+            return;
+        };
+        // a turtle that failed is probably stuck, so its chunk goes to one of the others
+        let exclude = (*status != TaskStatus::Done).then_some(computer_id);
+        self.assign_idle(&mut state, &turtles, exclude).await;
+        self.save_or_warn(&state).await;
+    }
+
+    /// Hands a pending chunk to every connected turtle in `candidates` that isn't mining yet.
+    /// Returns whether anything was assigned.
+    async fn assign_idle(&self, state: &mut MiningState, candidates: &[i64], exclude: Option<i64>) -> bool {
+        let connected: HashSet<i64> = self.turtles.get_fleet().await.into_iter()
+            .filter(|t| t.connected)
+            .map(|t| t.computer_id)
+            .collect();
+        let mut busy: HashSet<i64> = state.jobs.iter()
+            .flat_map(|j| j.chunks.iter())
+            .filter_map(|c| match c.status {
+                ChunkStatus::Assigned { computer_id, .. } => Some(computer_id),
+                _ => None,
+            })
+            .collect();
+        let mut assigned = false;
+        for &computer_id in candidates {
+            if Some(computer_id) == exclude || !connected.contains(&computer_id) || busy.contains(&computer_id) {
+                continue;
+            }
+            let chunk = state.jobs.iter_mut()
+                .filter(|j| j.turtles.contains(&computer_id))
+                .flat_map(|j| j.chunks.iter_mut())
+                .find(|c| c.status == ChunkStatus::Pending);
+            let Some(chunk) = chunk else {
+                continue;
+            };
+            let task = TurtleTask::Dig { from: chunk.from, to: chunk.to };
+            let task_id = self.tasks.enqueue(computer_id, task, MINING_TASK_RETRIES).await;
+            chunk.status = ChunkStatus::Assigned { computer_id, task_id, started: now_seconds() };
+            busy.insert(computer_id);
+            assigned = true;
+        }
+        assigned
+    }
+
+    async fn save(&self, state: &MiningState) -> Result<(), MiningError> {
+        let data = serde_json::to_string(state)?;
+        let temp = self.path.with_extension("tmp");
+        tokio::fs::write(&temp, data).await?;
+        tokio::fs::rename(&temp, &self.path).await?;
+        Ok(())
+    }
+
+    async fn save_or_warn(&self, state: &MiningState) {
+        if let Err(e) = self.save(state).await {
+            warn!("{}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pos(x: i64, y: i64, z: i64) -> BlockPosition {
+        BlockPosition { x, y, z }
+    }
+
+    #[test]
+    fn test_partition() {
+        let chunks = partition(pos(20, 10, -3), pos(-5, 0, 4));
+        assert_eq!(chunks.iter().map(|c| (c.chunk.x, c.chunk.z)).collect::<Vec<_>>(), vec![(-1, -1), (-1, 0), (0, -1), (0, 0), (1, -1), (1, 0)]);
+        assert_eq!((chunks[0].from, chunks[0].to), (pos(-5, 0, -3), pos(-1, 10, -1)));
+        assert_eq!((chunks[5].from, chunks[5].to), (pos(16, 0, 0), pos(20, 10, 4)));
+        assert_eq!(chunks.iter().map(ChunkWork::blocks).sum::<i64>(), 26 * 11 * 8);
+    }
+
+    #[tokio::test]
+    async fn test_assign_and_reassign() {
+        let path = std::env::temp_dir().join(format!("mining_jobs_test_{}.json", std::process::id()));
+        let tasks = Arc::new(TurtleTaskQueue::new());
+        let turtles = Arc::new(TurtleManager::new());
+        turtles.connected(1, None, 1).await;
+        turtles.connected(2, None, 2).await;
+        let planner = MiningPlanner::load(&path, tasks.clone(), turtles.clone()).unwrap();
+
+        // 3 chunks for three turtles, only two of them are connected
+        let id = planner.start(pos(0, 0, 0), pos(47, 1, 0), vec![1, 2, 3]).await.unwrap();
+        let first = tasks.get_tasks(1).await[0].id;
+        assert_eq!(tasks.get_tasks(2).await.len(), 1);
+        assert!(tasks.get_tasks(3).await.is_empty());
+
+        planner.task_finished(1, first, &TaskStatus::Done).await;
+        let job = planner.get_job(id).await.unwrap();
+        assert_eq!(job.chunks[0].status, ChunkStatus::Done);
+        assert!(matches!(job.chunks[2].status, ChunkStatus::Assigned { computer_id: 1, .. }));
+
+        // turtle 2 drops off, its chunk waits for the next free turtle
+        turtles.disconnected(2, 2).await;
+        planner.turtle_offline(2).await;
+        assert_eq!(planner.get_job(id).await.unwrap().chunks[1].status, ChunkStatus::Pending);
+        turtles.connected(3, None, 3).await;
+        planner.turtle_online(3).await;
+        assert!(matches!(planner.get_job(id).await.unwrap().chunks[1].status, ChunkStatus::Assigned { computer_id: 3, .. }));
+
+        // turtle 3 reconnects before its old socket times out, then the old session ends
+        turtles.connected(3, None, 4).await;
+        turtles.disconnected(3, 3).await;
+        planner.turtle_offline(3).await;
+        assert!(matches!(planner.get_job(id).await.unwrap().chunks[1].status, ChunkStatus::Assigned { computer_id: 3, .. }));
+        assert_eq!(tasks.get_tasks(3).await[0].status, TaskStatus::Queued);
+
+        // progress survives a restart, running chunks start over
+        let reloaded = MiningPlanner::load(&path, tasks, turtles).unwrap();
+        std::fs::remove_file(&path).ok();
+        let progress = &reloaded.get_jobs().await[0];
+        assert_eq!((progress.chunks, progress.chunks_done, progress.blocks, progress.blocks_done), (3, 1, 96, 32));
+        assert_eq!(progress.seconds_left, None);
+        assert!(reloaded.get_job(id).await.unwrap().chunks[1..].iter().all(|c| c.status == ChunkStatus::Pending));
+    }
+}
@@ -0,0 +1,358 @@
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{BufRead, BufReader, LineWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use ratatui::layout::{Position, Size};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::task::JoinHandle;
+use tracing::warn;
+use crate::cctweaked::{CCTweakedColor, CCTweakedMonitorBackendEvent, CCTweakedMonitorInputEvent};
+
+pub const REPLAY_USAGE: &str = "usage: rustserver replay <recording.jsonl> [--colors]";
+/// How many lines a recording can fall behind the session by before they are dropped
+const RECORDING_QUEUE: usize = 1024;
+
+/// One line of a recording
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "direction", rename_all = "lowercase")]
+pub enum RecordedEvent {
+    /// sent by the computer, `at` is milliseconds since the unix epoch
+    In {
+        at: u64,
+        event: CCTweakedMonitorInputEvent,
+    },
+    /// sent to the computer
+    Out {
+        at: u64,
+        event: CCTweakedMonitorBackendEvent,
+    },
+}
+
+#[derive(Debug, Error)]
+pub enum RecordingError {
+    Io(#[from] std::io::Error),
+    Parse {
+        line: usize,
+        source: serde_json::Error,
+    },
+    /// the recording never says how big the monitor is
+    NoSize,
+    BadArgs(String),
+}
+
+impl Display for RecordingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RecordingError::Io(e) => write!(f, "Failed to read recording: {}", e),
+            RecordingError::Parse { line, source } => write!(f, "Failed to parse line {} of recording: {}", line, source),
+            RecordingError::NoSize => write!(f, "Recording has no register or resize event to size the monitor"),
+            RecordingError::BadArgs(reason) => write!(f, "{}\n{}", reason, REPLAY_USAGE),
+        }
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+/// Recorder writes every message of one monitor session to a JSONL file, a line at a time so
+/// nothing is lost if the server dies mid session. Writing happens on a blocking thread of its own
+/// so a slow disk doesn't hold up the session, which drops lines instead of waiting for it.
+pub struct Recorder {
+    path: PathBuf,
+    lines: tokio::sync::mpsc::Sender<String>,
+    writer: JoinHandle<()>,
+    dropped: AtomicU64,
+}
+
+impl Recorder {
+    /// Starts a recording in `dir`, named after the computer and when the session started
+    pub fn create(dir: &Path, computer_id: i64) -> std::io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let path = dir.join(format!("computer_{}_{}.jsonl", computer_id, now_millis()));
+        let mut file = LineWriter::new(File::create(&path)?);
+        let (lines, mut receiver) = tokio::sync::mpsc::channel::<String>(RECORDING_QUEUE);
+        let writer_path = path.clone();
+        let writer = tokio::task::spawn_blocking(move || {
+            while let Some(line) = receiver.blocking_recv() {
+                if let Err(e) = writeln!(file, "{}", line) {
+                    warn!("Failed to write to recording {}: {}", writer_path.display(), e);
+                }
+            }
+        });
+        Ok(Recorder { path, lines, writer, dropped: AtomicU64::new(0) })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn inbound(&self, event: &CCTweakedMonitorInputEvent) {
+        let mut event = event.clone();
+        // recordings get passed around, tokens shouldn't be
+        if let CCTweakedMonitorInputEvent::InventoryRegister { token, .. } = &mut event {
+            *token = None;
+        }
+        self.write(RecordedEvent::In { at: now_millis(), event });
+    }
+
+    pub fn outbound(&self, event: &CCTweakedMonitorBackendEvent) {
+        self.write(RecordedEvent::Out { at: now_millis(), event: event.clone() });
+    }
+
+    /// Waits for everything recorded so far to be written out
+    pub async fn finish(self) {
+        drop(self.lines);
+        if let Err(e) = self.writer.await {
+            warn!("Failed to finish recording {}: {}", self.path.display(), e);
+        }
+        let dropped = self.dropped.into_inner();
+        if dropped > 0 {
+            warn!("Recording {} is missing {} events the disk couldn't keep up with", self.path.display(), dropped);
+        }
+    }
+
+    fn write(&self, event: RecordedEvent) {
+        let line = match serde_json::to_string(&event) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("Failed to write to recording {}: {}", self.path.display(), e);
+                return;
+            }
+        };
+        match self.lines.try_send(line) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(TrySendError::Closed(_)) => warn!("Recording {} stopped", self.path.display()),
+        }
+    }
+}
+
+/// Reads a recording made by [Recorder]
+pub fn load(path: impl AsRef<Path>) -> Result<Vec<RecordedEvent>, RecordingError> {
+    let file = BufReader::new(File::open(path)?);
+    let mut events = Vec::new();
+    for (i, line) in file.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        events.push(serde_json::from_str(&line).map_err(|source| RecordingError::Parse { line: i + 1, source })?);
+    }
+    Ok(events)
+}
+
+/// One character on a monitor and its colors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScreenCell {
+    pub symbol: char,
+    pub fg: CCTweakedColor,
+    pub bg: CCTweakedColor,
+}
+
+/// What a CC monitor shows, rebuilt from the events sent to it the way CC would draw them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Screen {
+    size: Size,
+    cells: Vec<ScreenCell>,
+    cursor: Position,
+    fg: CCTweakedColor,
+    bg: CCTweakedColor,
+}
+
+impl Screen {
+    pub fn new(size: Size) -> Self {
+        let mut screen = Screen {
+            size: Size::new(0, 0),
+            cells: Vec::new(),
+            cursor: Position::ORIGIN,
+            fg: CCTweakedColor::White,
+            bg: CCTweakedColor::Black,
+        };
+        screen.resize(size);
+        screen
+    }
+
+    /// Keeps what fits, like a monitor that had blocks added or removed
+    pub fn resize(&mut self, size: Size) {
+        let blank = self.blank();
+        let mut cells = vec![blank; size.width as usize * size.height as usize];
+        for y in 0..size.height.min(self.size.height) {
+            for x in 0..size.width.min(self.size.width) {
+                cells[y as usize * size.width as usize + x as usize] = self.cells[self.index(x, y)];
+            }
+        }
+        self.size = size;
+        self.cells = cells;
+    }
+
+    pub fn apply(&mut self, event: &CCTweakedMonitorBackendEvent) {
+        match event {
+            CCTweakedMonitorBackendEvent::ClearScreen => {
+                let blank = self.blank();
+                self.cells.fill(blank);
+            }
+            CCTweakedMonitorBackendEvent::ClearLine => {
+                if self.cursor.y < self.size.height {
+                    let start = self.index(0, self.cursor.y);
+                    let blank = self.blank();
+                    self.cells[start..start + self.size.width as usize].fill(blank);
+                }
+            }
+            CCTweakedMonitorBackendEvent::SetCursorPosition(position) => self.cursor = *position,
+            CCTweakedMonitorBackendEvent::SetTextColor(color) => self.fg = *color,
+            CCTweakedMonitorBackendEvent::SetBackgroundColor(color) => self.bg = *color,
+            CCTweakedMonitorBackendEvent::WriteText(text) => self.write(text.chars()),
+            CCTweakedMonitorBackendEvent::HideCursor
+            | CCTweakedMonitorBackendEvent::ShowCursor
+            | CCTweakedMonitorBackendEvent::ServerCommand(_)
+            | CCTweakedMonitorBackendEvent::PeripheralCall(_) => {}
+        }
+    }
+
+    /// Writes at the cursor and moves it along, anything past the edge is cut off
+    pub fn write(&mut self, text: impl Iterator<Item = char>) {
+        for symbol in text {
+            if self.cursor.x < self.size.width && self.cursor.y < self.size.height {
+                let index = self.index(self.cursor.x, self.cursor.y);
+                self.cells[index] = ScreenCell { symbol, fg: self.fg, bg: self.bg };
+            }
+            self.cursor.x = self.cursor.x.saturating_add(1);
+        }
+    }
+
+    /// Every row as text
+    pub fn text(&self) -> Vec<String> {
+        self.rows().map(|row| row.iter().map(|c| c.symbol).collect()).collect()
+    }
+
+    /// Every row's text and background colors in the format of `term.blit`
+    pub fn blit(&self) -> Vec<(String, String)> {
+        self.rows().map(|row| (
+            row.iter().map(|c| c.fg.blit()).collect(),
+            row.iter().map(|c| c.bg.blit()).collect(),
+        )).collect()
+    }
+
+    fn rows(&self) -> impl Iterator<Item = &[ScreenCell]> {
+        self.cells.chunks(self.size.width.max(1) as usize)
+    }
+
+    fn blank(&self) -> ScreenCell {
+        ScreenCell { symbol: ' ', fg: self.fg, bg: self.bg }
+    }
+
+    fn index(&self, x: u16, y: u16) -> usize {
+        y as usize * self.size.width as usize + x as usize
+    }
+}
+
+/// Plays the outbound events of a recording onto a [Screen], resizing it as the monitor did
+pub fn replay(events: &[RecordedEvent]) -> Result<Screen, RecordingError> {
+    let mut screen: Option<Screen> = None;
+    for event in events {
+        match event {
+            RecordedEvent::In { event: CCTweakedMonitorInputEvent::InventoryRegister { size, .. }, .. }
+            | RecordedEvent::In { event: CCTweakedMonitorInputEvent::MonitorResize(size), .. } => match &mut screen {
+                Some(screen) => screen.resize(*size),
+                None => screen = Some(Screen::new(*size)),
+            },
+            RecordedEvent::In { .. } => {}
+            RecordedEvent::Out { event, .. } => screen.as_mut().ok_or(RecordingError::NoSize)?.apply(event),
+        }
+    }
+    screen.ok_or(RecordingError::NoSize)
+}
+
+/// `rustserver replay`, prints what the monitor showed at the end of a recording
+pub fn replay_command(args: &[String]) -> Result<String, RecordingError> {
+    let mut path = None;
+    let mut colors = false;
+    for arg in args {
+        match arg.as_str() {
+            "--colors" => colors = true,
+            flag if flag.starts_with("--") => return Err(RecordingError::BadArgs(format!("unknown flag {}", flag))),
+            _ if path.is_none() => path = Some(arg),
+            _ => return Err(RecordingError::BadArgs(String::from("only one recording can be replayed at a time"))),
+        }
+    }
+    let path = path.ok_or_else(|| RecordingError::BadArgs(String::from("missing recording")))?;
+    let events = load(path)?;
+    let screen = replay(&events)?;
+
+    let inbound = events.iter().filter(|e| matches!(e, RecordedEvent::In { .. })).count();
+    let at = |e: &RecordedEvent| match e {
+        RecordedEvent::In { at, .. } | RecordedEvent::Out { at, .. } => *at,
+    };
+    let took = events.last().map(at).unwrap_or(0).saturating_sub(events.first().map(at).unwrap_or(0));
+    let mut out = format!("{} messages in, {} out over {:.1}s\n", inbound, events.len() - inbound, took as f64 / 1000.0);
+    let border = "-".repeat(screen.size.width as usize);
+    out.push_str(&format!("+{}+\n", border));
+    for row in screen.text() {
+        out.push_str(&format!("|{}|\n", row));
+    }
+    out.push_str(&format!("+{}+\n", border));
+    if colors {
+        out.push_str("text colors:\n");
+        for (fg, _) in screen.blit() {
+            out.push_str(&format!(" {}\n", fg));
+        }
+        out.push_str("background colors:\n");
+        for (_, bg) in screen.blit() {
+            out.push_str(&format!(" {}\n", bg));
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_record_and_replay() {
+        let dir = std::env::temp_dir().join(format!("recording_test_{}", std::process::id()));
+        let recorder = Recorder::create(&dir, 7).unwrap();
+        recorder.inbound(&CCTweakedMonitorInputEvent::InventoryRegister {
+            size: Size::new(6, 2),
+            computer_id: 7,
+            common_name: String::from("Furnaces"),
+            view: None,
+            protocol_version: Some(1),
+            token: Some(String::from("secret")),
+        });
+        for event in [
+            CCTweakedMonitorBackendEvent::SetCursorPosition(Position::new(1, 0)),
+            CCTweakedMonitorBackendEvent::SetTextColor(CCTweakedColor::Red),
+            CCTweakedMonitorBackendEvent::WriteText(String::from("coal")),
+            CCTweakedMonitorBackendEvent::SetTextColor(CCTweakedColor::White),
+            CCTweakedMonitorBackendEvent::SetBackgroundColor(CCTweakedColor::Blue),
+            CCTweakedMonitorBackendEvent::SetCursorPosition(Position::new(4, 1)),
+            // cut off at the edge
+            CCTweakedMonitorBackendEvent::WriteText(String::from("64x")),
+        ] {
+            recorder.outbound(&event);
+        }
+        let path = recorder.path().to_path_buf();
+        recorder.finish().await;
+
+        let events = load(&path).unwrap();
+        std::fs::remove_dir_all(&dir).ok();
+        assert_eq!(events.len(), 8);
+        assert!(matches!(&events[0], RecordedEvent::In { event: CCTweakedMonitorInputEvent::InventoryRegister { token: None, .. }, .. }));
+
+        let screen = replay(&events).unwrap();
+        assert_eq!(screen.text(), vec![" coal ", "    64"]);
+        assert_eq!(screen.blit(), vec![
+            (String::from("0eeee0"), String::from("ffffff")),
+            (String::from("000000"), String::from("ffffbb")),
+        ]);
+        assert!(matches!(replay(&events[1..]), Err(RecordingError::NoSize)));
+    }
+}
@@ -0,0 +1,181 @@
+use std::fmt::Display;
+use std::future::Future;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::task::{JoinError, JoinSet};
+use tokio_util::sync::CancellationToken;
+use tokio_tungstenite::tungstenite;
+use tracing::{error, info, warn};
+
+/// How long shutdown waits for sessions to say goodbye to their computers
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Why a websocket session ended
+#[derive(Debug, Error)]
+pub enum SessionError {
+    /// the computer closed the websocket
+    Closed,
+    /// nothing, not even a pong, came in within the heartbeat timeout
+    Timeout(Duration),
+    /// reading failed, CC resets the stream instead of closing it so this is usually just a disconnect
+    Read(axum::Error),
+    Send(axum::Error),
+    /// drawing failed for a reason other than the monitor going away
+    Draw(std::io::Error),
+    /// the server is shutting down
+    Shutdown,
+    Panicked(JoinError),
+}
+
+impl Display for SessionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SessionError::Closed => write!(f, "Connection closed"),
+            SessionError::Timeout(timeout) => write!(f, "Nothing received for {:?}", timeout),
+            SessionError::Read(e) => write!(f, "Failed to read from websocket: {}", e),
+            SessionError::Send(e) => write!(f, "Failed to send to websocket: {}", e),
+            SessionError::Draw(e) => write!(f, "Failed to draw to terminal: {}", e),
+            SessionError::Shutdown => write!(f, "Server shutting down"),
+            SessionError::Panicked(e) => write!(f, "Session task panicked: {}", e),
+        }
+    }
+}
+
+impl SessionError {
+    /// Whether this is just the computer going away, which happens every time a chunk unloads
+    pub fn is_disconnect(&self) -> bool {
+        match self {
+            SessionError::Closed | SessionError::Shutdown | SessionError::Read(_) => true,
+            SessionError::Send(e) => matches!(
+                std::error::Error::source(e).and_then(|e| e.downcast_ref::<tungstenite::Error>()),
+                Some(tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed)
+            ),
+            _ => false,
+        }
+    }
+}
+
+/// Supervisor owns every task of one websocket session. The first task to finish ends the
+/// session: the others are cancelled and waited for, so nothing outlives the connection.
+pub struct Supervisor {
+    name: String,
+    tasks: JoinSet<Result<(), SessionError>>,
+    /// work done on the session's behalf that doesn't end it when done
+    background: JoinSet<()>,
+    cancel: CancellationToken,
+}
+
+impl Supervisor {
+    /// `name` says which computer the session is for in logs
+    pub fn new(name: impl Into<String>) -> Self {
+        Supervisor {
+            name: name.into(),
+            tasks: JoinSet::new(),
+            background: JoinSet::new(),
+            cancel: CancellationToken::new(),
+        }
+    }
+
+    pub fn spawn(&mut self, task: impl Future<Output = Result<(), SessionError>> + Send + 'static) {
+        let cancel = self.cancel.clone();
+        self.tasks.spawn(async move {
+            let result = tokio::select! {
+                result = task => result,
+                _ = cancel.cancelled() => return Ok(()),
+            };
+            cancel.cancel();
+            result
+        });
+    }
+
+    /// Spawns work like answering a request, which is cancelled with the session but doesn't end
+    /// it when done
+    pub fn spawn_background(&mut self, task: impl Future<Output = ()> + Send + 'static) {
+        // forget the ones that are done so a long session doesn't pile them up
+        while self.background.try_join_next().is_some() {}
+        let cancel = self.cancel.clone();
+        self.background.spawn(async move {
+            tokio::select! {
+                () = task => {}
+                _ = cancel.cancelled() => {}
+            }
+        });
+    }
+
+    /// Resolves once any task has finished, for work the session does outside of its tasks
+    pub async fn cancelled(&self) {
+        self.cancel.cancelled().await
+    }
+
+    /// Waits for the first task to finish, then for the rest to stop
+    pub async fn join(self) {
+        self.finish(None).await
+    }
+
+    /// Stops every task, `result` being how the work done outside of them ended
+    pub async fn end(self, result: Result<(), SessionError>) {
+        self.cancel.cancel();
+        self.finish(Some(result)).await
+    }
+
+    async fn finish(mut self, mut reason: Option<Result<(), SessionError>>) {
+        while let Some(joined) = self.tasks.join_next().await {
+            let result = joined.unwrap_or_else(|e| Err(SessionError::Panicked(e)));
+            self.cancel.cancel();
+            // cancelled tasks return Ok, so the first error says best why the session ended
+            reason = match reason {
+                Some(Err(e)) => Some(Err(e)),
+                Some(Ok(())) | None if result.is_err() => Some(result),
+                reason => reason.or(Some(result)),
+            };
+        }
+        while let Some(joined) = self.background.join_next().await {
+            if let Err(e) = joined {
+                error!("Background task of {} panicked: {}", self.name, e);
+            }
+        }
+        match reason {
+            None | Some(Ok(())) => info!("Session of {} ended", self.name),
+            Some(Err(e)) if e.is_disconnect() => info!("Session of {} ended: {}", self.name, e),
+            Some(Err(e @ SessionError::Timeout(_))) => warn!("Session of {} ended: {}", self.name, e),
+            Some(Err(e)) => error!("Session of {} ended: {}", self.name, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[tokio::test]
+    async fn test_first_task_ends_session() {
+        let stopped = Arc::new(AtomicBool::new(true));
+        let mut supervisor = Supervisor::new("test");
+        let flag = stopped.clone();
+        supervisor.spawn(async move {
+            // only reaches the end if it isn't cancelled
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            flag.store(false, Ordering::Relaxed);
+            Ok(())
+        });
+        supervisor.spawn(async { Err(SessionError::Closed) });
+        tokio::time::timeout(Duration::from_secs(1), supervisor.join()).await.expect("session should end with its first task");
+        assert!(stopped.load(Ordering::Relaxed));
+
+        let mut supervisor = Supervisor::new("test");
+        supervisor.spawn(std::future::pending());
+        tokio::time::timeout(Duration::from_secs(1), supervisor.end(Ok(()))).await.expect("end should cancel pending tasks");
+        assert!(SessionError::Shutdown.is_disconnect());
+
+        // background work ends with the session, but doesn't end it
+        let mut supervisor = Supervisor::new("test");
+        supervisor.spawn_background(async {});
+        supervisor.spawn_background(std::future::pending());
+        tokio::task::yield_now().await;
+        assert!(!supervisor.cancel.is_cancelled());
+        tokio::time::timeout(Duration::from_secs(1), supervisor.end(Ok(()))).await.expect("end should cancel background tasks");
+        assert!(!SessionError::Timeout(Duration::from_secs(1)).is_disconnect());
+    }
+}
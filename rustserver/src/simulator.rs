@@ -0,0 +1,284 @@
+//! A fake CC computer for testing the server end to end. It talks to the real router over a
+//! loopback websocket and keeps a [Screen] of what its monitor would show.
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use futures::{SinkExt, StreamExt};
+use ratatui::layout::Size;
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use crate::auth::TokenStore;
+use cc_code_page::cctweaked_char;
+use crate::cctweaked::{CCTweakedMonitorBackendEvent, CCTweakedMonitorInputEvent};
+use crate::commands::ComputerHandles;
+use crate::config::LiveConfig;
+use crate::connections::ConnectionRegistry;
+use crate::crafting::RecipeBook;
+use crate::events::EventBus;
+use crate::inventory_manager::{InventoryManager, REPORT_QUEUE};
+use crate::item_router::ItemRouter;
+use crate::metrics::Metrics;
+use crate::mining_planner::MiningPlanner;
+use crate::recording::Screen;
+use crate::stock_keeper::StockKeeper;
+use crate::turtle_manager::TurtleManager;
+use crate::turtle_tasks::TurtleTaskQueue;
+use crate::world_map::WorldMap;
+use crate::{routes, AppState};
+
+/// How long to wait for a redraw before giving up on the server
+const TICK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Settings that keep tests quick: redraws every 50ms and reports are expected every second
+const TEST_CONFIG: &str = "\
+seconds_per_report = 1
+report_window_seconds = 60
+retention_seconds = 60
+redraw_interval_ms = 50
+";
+
+/// A server on a loopback port with nothing in it, its files kept in a directory of its own
+pub struct TestServer {
+    pub addr: SocketAddr,
+    pub manager: Arc<InventoryManager>,
+    pub tokens: Arc<TokenStore>,
+    dir: PathBuf,
+}
+
+impl TestServer {
+    /// Reports stay fresh for a minute, longer than any test runs
+    pub async fn start(name: &str) -> Self {
+        Self::start_stale_after(name, 60).await
+    }
+
+    /// A server that grays out reports older than `stale_after_seconds`
+    pub async fn start_stale_after(name: &str, stale_after_seconds: u64) -> Self {
+        let dir = std::env::temp_dir().join(format!("rustserver_simulator_{}_{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config_path = dir.join("rustserver.toml");
+        std::fs::write(&config_path, format!("{}stale_after_seconds = {}\n", TEST_CONFIG, stale_after_seconds)).unwrap();
+        let config = LiveConfig::load(vec![String::from("--config"), config_path.to_string_lossy().into_owned()]).unwrap();
+
+        let (manager_sender, manager_receiver) = tokio::sync::mpsc::channel(REPORT_QUEUE);
+        let events = Arc::new(EventBus::new());
+        let manager = Arc::new(InventoryManager::new(manager_sender, events.clone(), config.current().report_settings()));
+        let manager_clone = manager.clone();
+        tokio::spawn(async move {
+            manager_clone.run(manager_receiver).await;
+        });
+        let computers = Arc::new(ComputerHandles::new());
+        let item_router = Arc::new(ItemRouter::new(manager.clone(), computers.clone()));
+        let recipes = Arc::new(RecipeBook::default());
+        let stock_keeper = Arc::new(StockKeeper::new(Vec::new(), manager.clone(), item_router.clone(), recipes.clone(), events.clone()));
+        let turtles = Arc::new(TurtleManager::new());
+        let tasks = Arc::new(TurtleTaskQueue::new());
+        let mining = MiningPlanner::load(dir.join("mining_jobs.json"), tasks.clone(), turtles.clone()).unwrap();
+        let tokens = Arc::new(TokenStore::load(dir.join("tokens.json")).unwrap());
+        let state = AppState {
+            manager: manager.clone(),
+            computers,
+            router: item_router,
+            recipes,
+            stock_keeper,
+            turtles,
+            tasks,
+            world: Arc::new(WorldMap::load(dir.join("world_map.json")).unwrap()),
+            mining: Arc::new(mining),
+            connections: Arc::new(ConnectionRegistry::new(events.clone())),
+            events,
+            tokens: tokens.clone(),
+            config: Arc::new(config),
+            metrics: Arc::new(Metrics::default()),
+            shutdown: CancellationToken::new(),
+            sessions: TaskTracker::new(),
+        };
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            axum::serve(listener, routes(state).into_make_service_with_connect_info::<SocketAddr>()).await.unwrap();
+        });
+        TestServer { addr, manager, tokens, dir }
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        std::fs::remove_dir_all(&self.dir).ok();
+    }
+}
+
+/// A computer with a monitor attached, as the Lua client behaves
+pub struct FakeComputer {
+    socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    pub screen: Screen,
+}
+
+impl FakeComputer {
+    /// Connects a monitor of `size` showing the inventory view
+    pub async fn connect(server: &TestServer, computer_id: i64, common_name: &str, size: Size) -> Self {
+        let (socket, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws/monitor", server.addr)).await.unwrap();
+        let mut computer = FakeComputer { socket, screen: Screen::new(size) };
+        computer.send(&CCTweakedMonitorInputEvent::InventoryRegister {
+            size,
+            computer_id,
+            common_name: common_name.to_string(),
+            view: None,
+            protocol_version: None,
+            token: None,
+        }).await;
+        computer
+    }
+
+    pub async fn send(&mut self, event: &CCTweakedMonitorInputEvent) {
+        if let CCTweakedMonitorInputEvent::MonitorResize(size) = event {
+            self.screen.resize(*size);
+        }
+        let text = serde_json::to_string(event).unwrap();
+        self.socket.send(Message::text(text)).await.unwrap();
+    }
+
+    /// Draws everything the server sends until the end of its next redraw. Every redraw ends with
+    /// the cursor being hidden, since no view shows one.
+    pub async fn tick(&mut self) {
+        loop {
+            let message = tokio::time::timeout(TICK_TIMEOUT, self.socket.next()).await
+                .expect("no redraw from the server")
+                .expect("server closed the socket")
+                .unwrap();
+            match message {
+                Message::Text(text) => {
+                    let event: CCTweakedMonitorBackendEvent = serde_json::from_str(&text).unwrap();
+                    self.screen.apply(&event);
+                    if matches!(event, CCTweakedMonitorBackendEvent::HideCursor) {
+                        return;
+                    }
+                }
+                Message::Binary(bytes) => {
+                    self.screen.write(bytes.iter().copied().map(cctweaked_char));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    pub async fn ticks(&mut self, n: usize) {
+        for _ in 0..n {
+            self.tick().await;
+        }
+    }
+
+    /// Ticks until `done` holds for the screen, panicking with what is shown if it never does
+    pub async fn wait_until(&mut self, done: impl Fn(&Screen) -> bool) {
+        let deadline = tokio::time::Instant::now() + TICK_TIMEOUT;
+        while !done(&self.screen) {
+            assert!(tokio::time::Instant::now() < deadline, "gave up waiting, the monitor shows:\n{}", self.screen.text().join("\n"));
+            self.tick().await;
+        }
+    }
+}
+
+mod tests {
+    use super::*;
+    use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+    use crate::inventory_manager::{InventoryItem, InventoryReport, InventoryType};
+    use crate::turtle_manager::TurtleInputEvent;
+
+    fn storage_report(computer_id: i64, items: &[(&str, i64)]) -> CCTweakedMonitorInputEvent {
+        CCTweakedMonitorInputEvent::InventoryReport(InventoryReport {
+            common_name: String::from("Main"),
+            computer_id,
+            inventory: items.iter().enumerate().map(|(slot, (name, count))| InventoryItem {
+                slot: slot as i64 + 1,
+                name: name.to_string(),
+                count: *count,
+            }).collect(),
+            peripheral_name: String::from("minecraft:chest_0"),
+            inventory_type: InventoryType::Storage,
+        })
+    }
+
+    fn row(text: &str, width: usize) -> String {
+        format!("{:<width$}", text)
+    }
+
+    #[tokio::test]
+    async fn test_storage_report_on_monitor() {
+        let server = TestServer::start("storage").await;
+        let mut computer = FakeComputer::connect(&server, 7, "Main", Size::new(30, 4)).await;
+        computer.ticks(2).await;
+        assert_eq!(computer.screen.text()[0], format!("🬕Main (no reports){}", "🬂".repeat(12)));
+
+        computer.send(&storage_report(7, &[("iron", 5), ("coal", 10)])).await;
+        computer.wait_until(|screen| screen.text()[1].starts_with("▌coal")).await;
+        assert_eq!(computer.screen.text(), vec![
+            format!("🬕Main{}", "🬂".repeat(25)),
+            row("▌coal: 10", 30),
+            row("▌iron: 5", 30),
+            row("▌", 30),
+        ]);
+        assert!(computer.screen.blit().iter().all(|(fg, bg)| fg == &"0".repeat(30) && bg == &"f".repeat(30)));
+    }
+
+    #[tokio::test]
+    async fn test_stale_report_grays_out() {
+        let server = TestServer::start_stale_after("stale", 1).await;
+        let mut computer = FakeComputer::connect(&server, 7, "Main", Size::new(30, 4)).await;
+        computer.send(&storage_report(7, &[("iron", 5), ("coal", 10)])).await;
+
+        // no more reports, so the monitor says when it last heard from the computer and grays out
+        computer.wait_until(|screen| screen.text()[0].contains("last seen")).await;
+        assert_eq!(computer.screen.text()[1], row("▌coal: 10", 30));
+        assert!(computer.screen.blit().iter().all(|(fg, bg)| fg == &"7".repeat(30) && bg == &"f".repeat(30)));
+    }
+
+    #[tokio::test]
+    async fn test_report_for_another_computer_is_dropped() {
+        let server = TestServer::start("foreign_report").await;
+        let mut computer = FakeComputer::connect(&server, 7, "Main", Size::new(30, 4)).await;
+        computer.send(&storage_report(9, &[("diamond", 64)])).await;
+        // reports are handled in order, so once this one shows the other was dealt with
+        computer.send(&storage_report(7, &[("coal", 10)])).await;
+        computer.wait_until(|screen| screen.text()[1].starts_with("▌coal")).await;
+        assert_eq!(server.manager.last_report_age(9).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_rejected_turtle_is_told_why() {
+        let server = TestServer::start("rejected_turtle").await;
+        server.tokens.issue(3, None).await.unwrap();
+        let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws/turtle", server.addr)).await.unwrap();
+        let register = TurtleInputEvent::TurtleRegister { computer_id: 3, label: None, protocol_version: None, token: None };
+        socket.send(Message::text(serde_json::to_string(&register).unwrap())).await.unwrap();
+
+        let message = tokio::time::timeout(TICK_TIMEOUT, socket.next()).await.unwrap().unwrap().unwrap();
+        let Message::Close(Some(close)) = message else { panic!("Expected a close frame, got {:?}", message) };
+        assert_eq!(close.code, CloseCode::Policy);
+    }
+
+    #[tokio::test]
+    async fn test_resize_redraws_monitor() {
+        let server = TestServer::start("resize").await;
+        let mut computer = FakeComputer::connect(&server, 8, "A", Size::new(24, 3)).await;
+        computer.ticks(2).await;
+        assert_eq!(computer.screen.text(), vec![
+            format!("🬕A (no reports){}", "🬂".repeat(9)),
+            row("▌", 24),
+            row("▌", 24),
+        ]);
+
+        computer.send(&CCTweakedMonitorInputEvent::MonitorResize(Size::new(30, 4))).await;
+        computer.wait_until(|screen| screen.text()[0].ends_with(&"🬂".repeat(15))).await;
+        assert_eq!(computer.screen.text(), vec![
+            format!("🬕A (no reports){}", "🬂".repeat(15)),
+            row("▌", 30),
+            row("▌", 30),
+            row("▌", 30),
+        ]);
+    }
+}
@@ -0,0 +1,296 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+use crate::crafting::{self, CraftingPlan, RecipeBook};
+use crate::events::{EventBus, ServerEvent};
+use crate::inventory_manager::{InventoryManager, InventoryReport};
+use crate::item_router::{ItemRouter, TransferRequest};
+
+/// How often rules are checked against the latest storage reports
+pub const STOCK_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Keep at least `count` of `item` in the storage registered as `storage`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StockRule {
+    pub item: String,
+    pub count: i64,
+    pub storage: String,
+    /// storages that items may be moved out of before falling back to crafting
+    #[serde(default)]
+    pub overflow: Vec<String>,
+}
+
+/// What should be done about a rule that isn't met
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StockAction {
+    Transfer {
+        from: String,
+        count: i64,
+    },
+    Craft(CraftingPlan),
+    Alert(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum JobStatus {
+    Running,
+    /// waiting on something outside the server, like a crafting turtle or a player
+    Waiting,
+    Done,
+    Failed(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StockJob {
+    pub rule: StockRule,
+    pub have: i64,
+    pub action: StockAction,
+    pub status: JobStatus,
+}
+
+#[derive(Debug, Error)]
+pub enum StockRuleError {
+    Io(#[from] std::io::Error),
+    Parse(#[from] serde_json::Error),
+}
+
+impl Display for StockRuleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StockRuleError::Io(e) => write!(f, "Failed to read stock rules: {}", e),
+            StockRuleError::Parse(e) => write!(f, "Failed to parse stock rules: {}", e),
+        }
+    }
+}
+
+/// Loads a json list of [StockRule]s
+pub fn load_rules(path: impl AsRef<Path>) -> Result<Vec<StockRule>, StockRuleError> {
+    let data = std::fs::read_to_string(path)?;
+    Ok(serde_json::from_str(&data)?)
+}
+
+fn count_item(report: &InventoryReport, item: &str) -> i64 {
+    report.inventory.iter().filter(|i| i.name == item).map(|i| i.count).sum()
+}
+
+/// Decides what to do about a rule given the latest report of every storage it mentions and the
+/// total stock across all storages. Returns how many of the item the storage has, and the action
+/// to take if the rule isn't met.
+pub fn evaluate_rule(
+    rule: &StockRule,
+    reports: &HashMap<String, InventoryReport>,
+    recipes: &RecipeBook,
+    stock: &HashMap<String, i64>,
+) -> (i64, Option<StockAction>) {
+    let Some(storage) = reports.get(&rule.storage) else {
+        return (0, Some(StockAction::Alert(format!("no report from {}", rule.storage))));
+    };
+    let have = count_item(storage, &rule.item);
+    let shortfall = rule.count - have;
+    if shortfall <= 0 {
+        return (have, None);
+    }
+
+    for overflow in &rule.overflow {
+        let available = reports.get(overflow).map(|r| count_item(r, &rule.item)).unwrap_or(0);
+        if available > 0 {
+            return (have, Some(StockAction::Transfer {
+                from: overflow.clone(),
+                count: shortfall.min(available),
+            }));
+        }
+    }
+
+    let action = match crafting::plan(recipes, stock, &rule.item, shortfall) {
+        Ok(plan) if plan.is_craftable() => StockAction::Craft(plan),
+        Ok(plan) => StockAction::Alert(format!(
+            "missing {}",
+            plan.missing.iter().map(|m| format!("{} {}", m.count, m.name)).collect::<Vec<_>>().join(", ")
+        )),
+        Err(e) => StockAction::Alert(e.to_string()),
+    };
+    (have, Some(action))
+}
+
+/// StockKeeper periodically checks every [StockRule] and starts jobs to fill any shortfall
+pub struct StockKeeper {
+    rules: RwLock<Vec<StockRule>>,
+    manager: Arc<InventoryManager>,
+    router: Arc<ItemRouter>,
+    recipes: Arc<RecipeBook>,
+    jobs: RwLock<Vec<StockJob>>,
+    events: Arc<EventBus>,
+}
+
+impl StockKeeper {
+    pub fn new(rules: Vec<StockRule>, manager: Arc<InventoryManager>, router: Arc<ItemRouter>, recipes: Arc<RecipeBook>, events: Arc<EventBus>) -> Self {
+        StockKeeper {
+            rules: RwLock::new(rules),
+            manager,
+            router,
+            recipes,
+            jobs: RwLock::new(Vec::new()),
+            events,
+        }
+    }
+
+    /// The job started for each unmet rule the last time the rules were checked
+    pub async fn get_jobs(&self) -> Vec<StockJob> {
+        self.jobs.read().await.clone()
+    }
+
+    /// Swaps in new rules, jobs of removed rules disappear at the next check
+    pub async fn set_rules(&self, rules: Vec<StockRule>) {
+        *self.rules.write().await = rules;
+    }
+
+    pub async fn run(&self, interval: Duration) {
+        let mut timer = tokio::time::interval(interval);
+        loop {
+            timer.tick().await;
+            self.check_rules().await;
+        }
+    }
+
+    async fn check_rules(&self) {
+        let rules = self.rules.read().await.clone();
+        if rules.is_empty() {
+            self.jobs.write().await.clear();
+            return;
+        }
+        let mut reports = HashMap::new();
+        for rule in &rules {
+            for name in std::iter::once(&rule.storage).chain(rule.overflow.iter()) {
+                if reports.contains_key(name) {
+                    continue;
+                }
+                if let Some(report) = self.manager.get_latest_storage_report(name).await {
+                    reports.insert(name.clone(), report);
+                }
+            }
+        }
+        let stock = self.manager.get_storage_totals().await;
+
+        let mut jobs = Vec::new();
+        for rule in &rules {
+            let (have, Some(action)) = evaluate_rule(rule, &reports, &self.recipes, &stock) else {
+                continue;
+            };
+            let status = match &action {
+                StockAction::Transfer { .. } => JobStatus::Running,
+                StockAction::Craft(_) => {
+                    info!("Need to craft {} {} for {}", rule.count - have, rule.item, rule.storage);
+                    JobStatus::Waiting
+                }
+                StockAction::Alert(reason) => {
+                    warn!("Can't keep {} {} in {}: {}", rule.count, rule.item, rule.storage, reason);
+                    self.events.publish(ServerEvent::alert(
+                        "stock_keeper",
+                        format!("Can't keep {} {} in {}: {}", rule.count, rule.item, rule.storage, reason),
+                    ));
+                    JobStatus::Waiting
+                }
+            };
+            jobs.push(StockJob { rule: rule.clone(), have, action, status });
+        }
+        *self.jobs.write().await = jobs.clone();
+
+        for (index, job) in jobs.iter().enumerate() {
+            let StockAction::Transfer { from, count } = &job.action else {
+                continue;
+            };
+            let Some(to) = reports.get(&job.rule.storage).map(|r| r.peripheral_name.clone()) else {
+                continue;
+            };
+            let request = TransferRequest {
+                item: job.rule.item.clone(),
+                count: *count,
+                from: from.clone(),
+                to,
+            };
+            let status = match self.router.transfer(&request).await {
+                Ok(outcome) if outcome.moved == *count => JobStatus::Done,
+                Ok(outcome) => JobStatus::Failed(format!("only moved {} of {}", outcome.moved, count)),
+                Err(e) => JobStatus::Failed(e.to_string()),
+            };
+            if let JobStatus::Failed(reason) = &status {
+                self.events.publish(ServerEvent::alert(
+                    "stock_keeper",
+                    format!("Moving {} {} to {} failed: {}", count, job.rule.item, job.rule.storage, reason),
+                ));
+            }
+            if let Some(job) = self.jobs.write().await.get_mut(index) {
+                job.status = status;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crafting::Recipe;
+    use crate::inventory_manager::{InventoryItem, InventoryType};
+
+    fn storage(name: &str, items: &[(&str, i64)]) -> (String, InventoryReport) {
+        (name.to_string(), InventoryReport {
+            common_name: name.to_string(),
+            computer_id: 1,
+            inventory: items.iter().enumerate().map(|(slot, (name, count))| InventoryItem {
+                slot: slot as i64 + 1,
+                name: name.to_string(),
+                count: *count,
+            }).collect(),
+            peripheral_name: format!("{name}_peripheral"),
+            inventory_type: InventoryType::Storage,
+        })
+    }
+
+    fn rule(item: &str, count: i64, overflow: &[&str]) -> StockRule {
+        StockRule {
+            item: item.to_string(),
+            count,
+            storage: "Main".to_string(),
+            overflow: overflow.iter().map(|o| o.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_evaluate_rule() {
+        let reports: HashMap<_, _> = [
+            storage("Main", &[("torch", 10), ("torch", 20), ("coal", 3)]),
+            storage("Overflow", &[("torch", 5)]),
+        ].into_iter().collect();
+        let recipes = RecipeBook::new(vec![Recipe {
+            output: "torch".to_string(),
+            count: 4,
+            ingredients: [("coal".to_string(), 1), ("stick".to_string(), 1)].into_iter().collect(),
+            machine: "crafting_table".to_string(),
+        }]).unwrap();
+        let stock = HashMap::from([("torch".to_string(), 35), ("coal".to_string(), 3)]);
+
+        assert_eq!(evaluate_rule(&rule("torch", 30, &["Overflow"]), &reports, &recipes, &stock), (30, None));
+        assert_eq!(
+            evaluate_rule(&rule("torch", 64, &["Empty", "Overflow"]), &reports, &recipes, &stock),
+            (30, Some(StockAction::Transfer { from: "Overflow".to_string(), count: 5 }))
+        );
+        assert_eq!(
+            evaluate_rule(&rule("torch", 40, &[]), &reports, &recipes, &stock),
+            (30, Some(StockAction::Alert("missing 3 stick".to_string())))
+        );
+
+        let stock = HashMap::from([("coal".to_string(), 3), ("stick".to_string(), 3)]);
+        let (_, action) = evaluate_rule(&rule("torch", 40, &[]), &reports, &recipes, &stock);
+        assert!(matches!(action, Some(StockAction::Craft(plan)) if plan.steps[0].crafts == 3));
+
+        let mut missing_storage = rule("torch", 1, &[]);
+        missing_storage.storage = "Nowhere".to_string();
+        assert!(matches!(evaluate_rule(&missing_storage, &reports, &recipes, &stock), (0, Some(StockAction::Alert(_)))));
+    }
+}
@@ -0,0 +1,290 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+use crate::inventory_manager::InventoryItem;
+use crate::world_map::{BlockObservation, DigPolicy};
+
+/// How many telemetry entries are kept per turtle
+pub const TURTLE_HISTORY_LENGTH: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct BlockPosition {
+    pub x: i64,
+    pub y: i64,
+    pub z: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Facing {
+    North,
+    East,
+    South,
+    West,
+}
+
+/// What the turtle says it is doing
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TurtleState {
+    #[default]
+    Idle,
+    Moving,
+    Mining,
+    Building,
+    Refueling,
+    Unloading,
+    Stuck,
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TurtleTelemetry {
+    pub computer_id: i64,
+    pub position: BlockPosition,
+    pub facing: Facing,
+    /// None when fuel is disabled on the server ("unlimited")
+    #[serde(default)]
+    pub fuel: Option<i64>,
+    #[serde(default)]
+    pub inventory: Vec<InventoryItem>,
+    #[serde(default)]
+    pub state: TurtleState,
+}
+
+/// Messages sent from a turtle to the server
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum TurtleInputEvent {
+    #[serde(rename = "turtle_register")]
+    TurtleRegister {
+        computer_id: i64,
+        #[serde(default)]
+        label: Option<String>,
+        #[serde(default)]
+        protocol_version: Option<u32>,
+        /// for clients that can't set the token header
+        #[serde(default)]
+        token: Option<String>,
+    },
+    #[serde(rename = "turtle_telemetry")]
+    TurtleTelemetry(TurtleTelemetry),
+    #[serde(rename = "task_progress")]
+    TaskProgress {
+        id: u64,
+        progress: f64,
+    },
+    #[serde(rename = "task_finished")]
+    TaskFinished {
+        id: u64,
+        success: bool,
+        #[serde(default)]
+        error: Option<String>,
+    },
+    /// results of `turtle.inspect` calls
+    #[serde(rename = "block_observations")]
+    BlockObservations(Vec<BlockObservation>),
+    /// asks the server to plan a path, answered with [crate::turtle_tasks::TurtleBackendEvent::Path]
+    #[serde(rename = "path_request")]
+    PathRequest {
+        id: u64,
+        from: BlockPosition,
+        to: BlockPosition,
+        /// the map's policy if left out
+        #[serde(default)]
+        policy: Option<DigPolicy>,
+    },
+}
+
+impl TurtleInputEvent {
+    /// Name of the message type on the wire
+    pub fn kind(&self) -> &'static str {
+        match self {
+            TurtleInputEvent::TurtleRegister { .. } => "turtle_register",
+            TurtleInputEvent::TurtleTelemetry(_) => "turtle_telemetry",
+            TurtleInputEvent::TaskProgress { .. } => "task_progress",
+            TurtleInputEvent::TaskFinished { .. } => "task_finished",
+            TurtleInputEvent::BlockObservations(_) => "block_observations",
+            TurtleInputEvent::PathRequest { .. } => "path_request",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TurtleHistoryEntry {
+    /// seconds since the unix epoch
+    pub time: u64,
+    pub position: BlockPosition,
+    pub fuel: Option<i64>,
+    pub state: TurtleState,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TurtleStatus {
+    pub computer_id: i64,
+    pub label: Option<String>,
+    pub connected: bool,
+    /// seconds since the unix epoch
+    pub last_seen: u64,
+    pub telemetry: Option<TurtleTelemetry>,
+}
+
+struct TurtleRecord {
+    status: TurtleStatus,
+    /// the [crate::connections::ConnectionRegistry] id of the turtle's current session
+    connection_id: u64,
+    history: VecDeque<TurtleHistoryEntry>,
+}
+
+pub fn now_seconds() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// TurtleManager keeps the last known state and a short history of every turtle that has
+/// connected since the server started
+#[derive(Default)]
+pub struct TurtleManager {
+    turtles: RwLock<HashMap<i64, TurtleRecord>>,
+}
+
+impl TurtleManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Marks a turtle as connected over the session with `connection_id`, taking over from any
+    /// session it had before
+    pub async fn connected(&self, computer_id: i64, label: Option<String>, connection_id: u64) {
+        let mut guard = self.turtles.write().await;
+        let record = guard.entry(computer_id).or_insert_with(|| TurtleRecord {
+            status: TurtleStatus {
+                computer_id,
+                label: None,
+                connected: true,
+                last_seen: 0,
+                telemetry: None,
+            },
+            connection_id,
+            history: VecDeque::new(),
+        });
+        record.connection_id = connection_id;
+        record.status.label = label;
+        record.status.connected = true;
+        record.status.last_seen = now_seconds();
+    }
+
+    /// Marks a turtle as disconnected when its session with `connection_id` ends. Returns false,
+    /// changing nothing, if the turtle already reconnected over another session.
+    pub async fn disconnected(&self, computer_id: i64, connection_id: u64) -> bool {
+        let mut guard = self.turtles.write().await;
+        let Some(record) = guard.get_mut(&computer_id) else {
+            return false;
+        };
+        if record.connection_id != connection_id {
+            return false;
+        }
+        record.status.connected = false;
+        true
+    }
+
+    pub async fn is_connected(&self, computer_id: i64) -> bool {
+        self.turtles.read().await.get(&computer_id).is_some_and(|r| r.status.connected)
+    }
+
+    pub async fn record(&self, telemetry: TurtleTelemetry) {
+        let now = now_seconds();
+        let mut guard = self.turtles.write().await;
+        let Some(record) = guard.get_mut(&telemetry.computer_id) else {
+            // telemetry is only accepted from registered turtles
+            return;
+        };
+        if record.history.len() >= TURTLE_HISTORY_LENGTH {
+            record.history.pop_front();
+        }
+        record.history.push_back(TurtleHistoryEntry {
+            time: now,
+            position: telemetry.position,
+            fuel: telemetry.fuel,
+            state: telemetry.state,
+        });
+        record.status.last_seen = now;
+        record.status.telemetry = Some(telemetry);
+    }
+
+    /// Status of every known turtle, sorted by computer id
+    pub async fn get_fleet(&self) -> Vec<TurtleStatus> {
+        let mut fleet: Vec<_> = self.turtles.read().await.values().map(|r| r.status.clone()).collect();
+        fleet.sort_by_key(|t| t.computer_id);
+        fleet
+    }
+
+    pub async fn get_history(&self, computer_id: i64) -> Option<Vec<TurtleHistoryEntry>> {
+        self.turtles.read().await.get(&computer_id).map(|r| r.history.iter().cloned().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn telemetry(computer_id: i64, x: i64) -> TurtleTelemetry {
+        TurtleTelemetry {
+            computer_id,
+            position: BlockPosition { x, y: 64, z: 0 },
+            facing: Facing::East,
+            fuel: Some(100),
+            inventory: vec![],
+            state: TurtleState::Mining,
+        }
+    }
+
+    #[test]
+    fn test_deserialize() {
+        let event = serde_json::from_str::<TurtleInputEvent>(
+            r#"{"turtle_telemetry":{"computer_id":4,"position":{"x":1,"y":2,"z":3},"facing":"north","state":"refueling"}}"#
+        ).unwrap();
+        let TurtleInputEvent::TurtleTelemetry(telemetry) = event else { panic!("Expected telemetry") };
+        assert_eq!(telemetry.facing, Facing::North);
+        assert_eq!(telemetry.fuel, None);
+        assert_eq!(telemetry.state, TurtleState::Refueling);
+
+        let state = serde_json::from_str::<TurtleState>(r#""dancing""#).unwrap();
+        assert_eq!(state, TurtleState::Unknown);
+    }
+
+    #[tokio::test]
+    async fn test_record() {
+        let manager = TurtleManager::new();
+        // not registered yet
+        manager.record(telemetry(2, 0)).await;
+        assert!(manager.get_fleet().await.is_empty());
+
+        manager.connected(2, Some("miner".to_string()), 1).await;
+        manager.connected(1, None, 2).await;
+        for x in 0..TURTLE_HISTORY_LENGTH as i64 + 5 {
+            manager.record(telemetry(2, x)).await;
+        }
+        assert!(manager.disconnected(2, 1).await);
+
+        let fleet = manager.get_fleet().await;
+        assert_eq!(fleet.iter().map(|t| t.computer_id).collect::<Vec<_>>(), vec![1, 2]);
+        assert!(!fleet[1].connected);
+        assert_eq!(fleet[1].telemetry.as_ref().unwrap().position.x, TURTLE_HISTORY_LENGTH as i64 + 4);
+        let history = manager.get_history(2).await.unwrap();
+        assert_eq!(history.len(), TURTLE_HISTORY_LENGTH);
+        assert_eq!(history[0].position.x, 5);
+    }
+
+    #[tokio::test]
+    async fn test_old_session_disconnecting_after_reconnect() {
+        let manager = TurtleManager::new();
+        manager.connected(2, None, 1).await;
+        manager.connected(2, None, 5).await;
+        // the first socket only times out now
+        assert!(!manager.disconnected(2, 1).await);
+        assert!(manager.get_fleet().await[0].connected);
+        assert!(manager.disconnected(2, 5).await);
+        assert!(!manager.get_fleet().await[0].connected);
+    }
+}
@@ -0,0 +1,373 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Display;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::Mutex;
+use tracing::{debug, info, warn};
+use crate::turtle_manager::BlockPosition;
+
+/// How many finished tasks are remembered per turtle
+pub const TASK_HISTORY_LENGTH: usize = 50;
+
+/// Work a turtle can be asked to do
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TurtleTask {
+    /// move to the position, digging through anything in the way
+    #[serde(rename = "go_to")]
+    GoTo {
+        position: BlockPosition,
+    },
+    /// dig out every block of the box between the two corners
+    #[serde(rename = "dig")]
+    Dig {
+        from: BlockPosition,
+        to: BlockPosition,
+    },
+    #[serde(rename = "refuel")]
+    Refuel,
+    /// move to the position and drop the whole inventory into whatever is below it
+    #[serde(rename = "unload")]
+    Unload {
+        at: BlockPosition,
+    },
+    /// move above the position and place the item down into it
+    #[serde(rename = "place")]
+    Place {
+        position: BlockPosition,
+        item: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum TaskStatus {
+    Queued,
+    Running {
+        /// 0 to 1
+        progress: f64,
+    },
+    Done,
+    Failed(String),
+    Cancelled,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QueuedTask {
+    pub id: u64,
+    pub task: TurtleTask,
+    pub status: TaskStatus,
+    pub attempts: u32,
+    pub max_retries: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TaskRequest {
+    pub id: u64,
+    pub task: TurtleTask,
+}
+
+/// Messages sent from the server to a turtle
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TurtleBackendEvent {
+    RunTask(TaskRequest),
+    CancelTask(u64),
+    /// answer to a path request, None if there is no way there
+    Path {
+        id: u64,
+        path: Option<Vec<BlockPosition>>,
+    },
+}
+
+impl TurtleBackendEvent {
+    /// Name of the message type on the wire
+    pub fn kind(&self) -> &'static str {
+        match self {
+            TurtleBackendEvent::RunTask(_) => "RunTask",
+            TurtleBackendEvent::CancelTask(_) => "CancelTask",
+            TurtleBackendEvent::Path { .. } => "Path",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Error, PartialEq, Eq)]
+pub enum TaskError {
+    UnknownTask(u64),
+    AlreadyFinished(u64),
+}
+
+impl Display for TaskError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TaskError::UnknownTask(id) => write!(f, "No task with id {}", id),
+            TaskError::AlreadyFinished(id) => write!(f, "Task {} already finished", id),
+        }
+    }
+}
+
+#[derive(Default)]
+struct TurtleQueue {
+    /// the running task, if any, is always at the front
+    tasks: VecDeque<QueuedTask>,
+    history: VecDeque<QueuedTask>,
+    sender: Option<UnboundedSender<TurtleBackendEvent>>,
+}
+
+impl TurtleQueue {
+    fn finish(&mut self, mut task: QueuedTask, status: TaskStatus) {
+        task.status = status;
+        if self.history.len() >= TASK_HISTORY_LENGTH {
+            self.history.pop_front();
+        }
+        self.history.push_back(task);
+    }
+
+    /// Sends the next queued task to the turtle if it is connected and not already busy
+    fn dispatch(&mut self) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        let Some(task) = self.tasks.front_mut() else {
+            return;
+        };
+        if task.status != TaskStatus::Queued {
+            return;
+        }
+        task.attempts += 1;
+        task.status = TaskStatus::Running { progress: 0.0 };
+        debug!("Dispatching task {} (attempt {}): {:?}", task.id, task.attempts, task.task);
+        let request = TaskRequest { id: task.id, task: task.task.clone() };
+        if sender.send(TurtleBackendEvent::RunTask(request)).is_err() {
+            // turtle went away, it gets sent again when it reconnects
+            task.attempts -= 1;
+            task.status = TaskStatus::Queued;
+            self.sender = None;
+        }
+    }
+
+    /// Retries the running task if it has retries left, otherwise finishes it as failed and
+    /// returns the final status
+    fn fail_running(&mut self, reason: String) -> Option<TaskStatus> {
+        let task = self.tasks.front_mut()?;
+        if task.attempts <= task.max_retries {
+            warn!("Task {} failed ({}), retrying", task.id, reason);
+            task.status = TaskStatus::Queued;
+            return None;
+        }
+        warn!("Task {} failed ({}), giving up after {} attempts", task.id, reason, task.attempts);
+        let task = self.tasks.pop_front()?;
+        let status = TaskStatus::Failed(reason);
+        self.finish(task, status.clone());
+        Some(status)
+    }
+}
+
+/// TurtleTaskQueue holds the queue of tasks for every turtle and hands them out one at a time
+/// over the turtle's websocket
+#[derive(Default)]
+pub struct TurtleTaskQueue {
+    queues: Mutex<HashMap<i64, TurtleQueue>>,
+    next_id: Mutex<u64>,
+}
+
+impl TurtleTaskQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub async fn enqueue(&self, computer_id: i64, task: TurtleTask, max_retries: u32) -> u64 {
+        let id = {
+            let mut next_id = self.next_id.lock().await;
+            *next_id += 1;
+            *next_id
+        };
+        info!("Queueing task {id} for turtle {computer_id}: {task:?}");
+        let mut guard = self.queues.lock().await;
+        let queue = guard.entry(computer_id).or_default();
+        queue.tasks.push_back(QueuedTask {
+            id,
+            task,
+            status: TaskStatus::Queued,
+            attempts: 0,
+            max_retries,
+        });
+        queue.dispatch();
+        id
+    }
+
+    /// Queued and running tasks followed by recently finished ones
+    pub async fn get_tasks(&self, computer_id: i64) -> Vec<QueuedTask> {
+        let guard = self.queues.lock().await;
+        let Some(queue) = guard.get(&computer_id) else {
+            return Vec::new();
+        };
+        queue.tasks.iter().chain(queue.history.iter().rev()).cloned().collect()
+    }
+
+    pub async fn cancel(&self, computer_id: i64, id: u64) -> Result<(), TaskError> {
+        let mut guard = self.queues.lock().await;
+        let Some(queue) = guard.get_mut(&computer_id) else {
+            return Err(TaskError::UnknownTask(id));
+        };
+        let Some(index) = queue.tasks.iter().position(|t| t.id == id) else {
+            if queue.history.iter().any(|t| t.id == id) {
+                return Err(TaskError::AlreadyFinished(id));
+            }
+            return Err(TaskError::UnknownTask(id));
+        };
+        if let Some(task) = queue.tasks.remove(index) {
+            if let (TaskStatus::Running { .. }, Some(sender)) = (&task.status, &queue.sender) {
+                sender.send(TurtleBackendEvent::CancelTask(id)).ok();
+            }
+            queue.finish(task, TaskStatus::Cancelled);
+        }
+        queue.dispatch();
+        Ok(())
+    }
+
+    /// Called when a turtle connects, whatever it was running before is retried. Returns the id and
+    /// final status of that task if it had no retries left.
+    pub async fn attach(&self, computer_id: i64, sender: UnboundedSender<TurtleBackendEvent>) -> Option<(u64, TaskStatus)> {
+        let mut guard = self.queues.lock().await;
+        let queue = guard.entry(computer_id).or_default();
+        queue.sender = Some(sender);
+        let failed = match queue.tasks.front() {
+            Some(QueuedTask { id, status: TaskStatus::Running { .. }, .. }) => {
+                let id = *id;
+                queue.fail_running(String::from("turtle reconnected while running the task")).map(|status| (id, status))
+            }
+            _ => None,
+        };
+        queue.dispatch();
+        failed
+    }
+
+    /// Called when a turtle's socket closes. A turtle that already reconnected keeps its new sender.
+    pub async fn detach(&self, computer_id: i64, sender: &UnboundedSender<TurtleBackendEvent>) {
+        if let Some(queue) = self.queues.lock().await.get_mut(&computer_id) {
+            if queue.sender.as_ref().is_some_and(|s| s.same_channel(sender)) {
+                queue.sender = None;
+            }
+        }
+    }
+
+    pub async fn progress(&self, computer_id: i64, id: u64, progress: f64) {
+        let mut guard = self.queues.lock().await;
+        let Some(task) = guard.get_mut(&computer_id).and_then(|q| q.tasks.front_mut()) else {
+            return;
+        };
+        if task.id == id && matches!(task.status, TaskStatus::Running { .. }) {
+            task.status = TaskStatus::Running { progress: progress.clamp(0.0, 1.0) };
+        }
+    }
+
+    /// Records the outcome of the running task. Returns the task's final status, or None if it
+    /// is being retried or wasn't running.
+    pub async fn finished(&self, computer_id: i64, id: u64, result: Result<(), String>) -> Option<TaskStatus> {
+        let mut guard = self.queues.lock().await;
+        let queue = guard.get_mut(&computer_id)?;
+        if queue.tasks.front().map(|t| t.id) != Some(id) {
+            // most likely cancelled while the turtle was finishing it
+            debug!("Turtle {computer_id} finished task {id} which isn't running");
+            return None;
+        }
+        let status = match result {
+            Ok(()) => {
+                info!("Turtle {computer_id} finished task {id}");
+                queue.tasks.pop_front().map(|task| {
+                    queue.finish(task, TaskStatus::Done);
+                    TaskStatus::Done
+                })
+            }
+            Err(reason) => queue.fail_running(reason),
+        };
+        queue.dispatch();
+        status
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn go_to(x: i64) -> TurtleTask {
+        TurtleTask::GoTo { position: BlockPosition { x, y: 0, z: 0 } }
+    }
+
+    #[test]
+    fn test_serialize() {
+        let event = TurtleBackendEvent::RunTask(TaskRequest { id: 1, task: go_to(3) });
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"RunTask":{"id":1,"task":{"go_to":{"position":{"x":3,"y":0,"z":0}}}}}"#
+        );
+        assert_eq!(serde_json::to_string(&TurtleTask::Refuel).unwrap(), r#""refuel""#);
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_and_retry() {
+        let queue = TurtleTaskQueue::new();
+        let first = queue.enqueue(1, go_to(1), 1).await;
+        let second = queue.enqueue(1, go_to(2), 0).await;
+
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        queue.attach(1, sender).await;
+        assert_eq!(receiver.try_recv().unwrap(), TurtleBackendEvent::RunTask(TaskRequest { id: first, task: go_to(1) }));
+        // only one task at a time
+        assert!(receiver.try_recv().is_err());
+
+        queue.progress(1, first, 0.5).await;
+        assert_eq!(queue.get_tasks(1).await[0].status, TaskStatus::Running { progress: 0.5 });
+
+        // first failure is retried, the second isn't
+        queue.finished(1, first, Err("blocked".to_string())).await;
+        assert!(matches!(receiver.try_recv().unwrap(), TurtleBackendEvent::RunTask(TaskRequest { id, .. }) if id == first));
+        queue.finished(1, first, Err("blocked".to_string())).await;
+        assert!(matches!(receiver.try_recv().unwrap(), TurtleBackendEvent::RunTask(TaskRequest { id, .. }) if id == second));
+        assert_eq!(queue.finished(1, second, Ok(())).await, Some(TaskStatus::Done));
+
+        let tasks = queue.get_tasks(1).await;
+        assert_eq!(tasks.iter().map(|t| (t.id, t.status.clone())).collect::<Vec<_>>(), vec![
+            (second, TaskStatus::Done),
+            (first, TaskStatus::Failed("blocked".to_string())),
+        ]);
+        assert_eq!(tasks[1].attempts, 2);
+    }
+
+    #[tokio::test]
+    async fn test_cancel_and_reconnect() {
+        let queue = TurtleTaskQueue::new();
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        queue.attach(1, sender.clone()).await;
+        let first = queue.enqueue(1, go_to(1), 0).await;
+        let second = queue.enqueue(1, go_to(2), 0).await;
+        let third = queue.enqueue(1, go_to(3), 0).await;
+        receiver.try_recv().unwrap();
+
+        queue.cancel(1, second).await.unwrap();
+        queue.cancel(1, first).await.unwrap();
+        assert_eq!(receiver.try_recv().unwrap(), TurtleBackendEvent::CancelTask(first));
+        assert!(matches!(receiver.try_recv().unwrap(), TurtleBackendEvent::RunTask(TaskRequest { id, .. }) if id == third));
+        assert_eq!(queue.cancel(1, first).await, Err(TaskError::AlreadyFinished(first)));
+        assert_eq!(queue.cancel(1, 99).await, Err(TaskError::UnknownTask(99)));
+
+        // the turtle dropped off while running the third task without any retries left
+        queue.detach(1, &sender).await;
+        let (sender, _receiver) = tokio::sync::mpsc::unbounded_channel();
+        assert!(matches!(queue.attach(1, sender).await, Some((id, TaskStatus::Failed(_))) if id == third));
+        assert!(matches!(queue.get_tasks(1).await[0].status, TaskStatus::Failed(_)));
+    }
+
+    #[tokio::test]
+    async fn test_old_session_detaching_after_reconnect() {
+        let queue = TurtleTaskQueue::new();
+        let (old_sender, _old_receiver) = tokio::sync::mpsc::unbounded_channel();
+        queue.attach(1, old_sender.clone()).await;
+        let (new_sender, mut new_receiver) = tokio::sync::mpsc::unbounded_channel();
+        queue.attach(1, new_sender).await;
+        // the old socket is only torn down now
+        queue.detach(1, &old_sender).await;
+
+        let id = queue.enqueue(1, go_to(1), 0).await;
+        assert!(matches!(new_receiver.try_recv().unwrap(), TurtleBackendEvent::RunTask(TaskRequest { id: running, .. }) if running == id));
+    }
+}
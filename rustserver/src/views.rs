@@ -0,0 +1,154 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use ratatui::Terminal;
+use ratatui::style::{Color, Style};
+use ratatui::text::Text;
+use ratatui::widgets::{Block, List, Widget};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use crate::cctweaked::CCTweakedMonitorBackend;
+use crate::inventory_manager::{InventoryManager, InventoryManagerReport};
+use crate::stock_keeper::{JobStatus, StockAction, StockKeeper};
+use crate::turtle_manager::TurtleManager;
+use crate::CCTWEAKED_BORDER;
+
+/// How often views redraw the monitor unless configured otherwise
+pub const REDRAW_INTERVAL: Duration = Duration::from_millis(1000);
+
+/// What a monitor shows, picked by the computer when it registers
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MonitorView {
+    /// rates or counts of the computer's own inventory
+    #[default]
+    #[serde(rename = "inventory")]
+    Inventory,
+    /// status of the jobs started by stock keeping rules
+    #[serde(rename = "stock_jobs")]
+    StockJobs,
+    /// every turtle's position, fuel and state
+    #[serde(rename = "fleet")]
+    Fleet,
+}
+
+/// Draws a widget, failing with a [crate::cctweaked::MonitorDisconnected] error once the monitor has gone away
+async fn draw(terminal: &Mutex<Terminal<CCTweakedMonitorBackend>>, widget: impl Widget) -> std::io::Result<()> {
+    let mut guard = terminal.lock().await;
+    let started = Instant::now();
+    guard.draw(|frame| {
+        frame.render_widget(widget, frame.area());
+    })?;
+    guard.backend().draw_took(started.elapsed());
+    Ok(())
+}
+
+/// Short human form of how long ago something happened, i.e. `3m ago`
+fn format_age(age: Duration) -> String {
+    let seconds = age.as_secs();
+    match seconds {
+        0..60 => format!("{}s ago", seconds),
+        60..3600 => format!("{}m ago", seconds / 60),
+        _ => format!("{}h ago", seconds / 3600),
+    }
+}
+
+pub async fn write_inventory_manager_rate_report_to_terminal(terminal: Arc<Mutex<Terminal<CCTweakedMonitorBackend>>>, manager: Arc<InventoryManager>, computer_id: i64, common_name: String, report_window: Duration, redraw_interval: Duration, stale_after: Duration) -> std::io::Result<()> {
+    let mut timer = tokio::time::interval(redraw_interval);
+    loop {
+        timer.tick().await;
+        // rather than leaving old numbers up as if nothing happened, say when the computer was last heard from
+        let (title, stale) = match manager.last_report_age(computer_id).await {
+            Some(age) if age >= stale_after => (format!("{} (last seen {})", common_name, format_age(age)), true),
+            Some(_) => (common_name.clone(), false),
+            None => (format!("{} (no reports)", common_name), true),
+        };
+        let Some(report) = manager.get_report(computer_id, report_window).await else {
+            draw(&terminal, Block::bordered().border_set(CCTWEAKED_BORDER).title(title)).await?;
+            continue;
+        };
+        let style = if stale { Style::default().fg(Color::Gray) } else { Style::default() };
+        let display = match report {
+            InventoryManagerReport::Input(mut r) => {
+                r.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal).reverse());
+                List::new(r.iter().map(|item| {
+                    let text = Text::raw(format!("{}: {}", item.name, item.rate_per_second));
+                    text
+                })).block(Block::bordered().border_set(CCTWEAKED_BORDER).title(title.clone())).style(style)
+            }
+            InventoryManagerReport::Output(mut r)  => {
+                r.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal).reverse());
+                List::new(r.iter().map(|item| {
+                    let text = Text::raw(format!("{}: {}", item.name, item.rate_per_second));
+                    text
+                })).block(Block::bordered().border_set(CCTWEAKED_BORDER).title(title.clone())).style(style)
+            }
+            InventoryManagerReport::Storage(mut r) => {
+                r.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal).reverse());
+                List::new(r.iter().map(|item| {
+                    let text = Text::raw(format!("{}: {}", item.name, item.count));
+                    text
+                })).block(Block::bordered().border_set(CCTWEAKED_BORDER).title(title)).style(style)
+            }
+        };
+        draw(&terminal, display).await?;
+    }
+}
+
+pub async fn write_stock_jobs_to_terminal(terminal: Arc<Mutex<Terminal<CCTweakedMonitorBackend>>>, keeper: Arc<StockKeeper>, common_name: String, redraw_interval: Duration) -> std::io::Result<()> {
+    let mut timer = tokio::time::interval(redraw_interval);
+    loop {
+        timer.tick().await;
+        let jobs = keeper.get_jobs().await;
+        let display = List::new(jobs.iter().map(|job| {
+            let action = match &job.action {
+                StockAction::Transfer { from, count } => format!("move {} from {}", count, from),
+                StockAction::Craft(plan) => format!("craft {}", plan.count),
+                StockAction::Alert(reason) => reason.clone(),
+            };
+            let (status, color) = match &job.status {
+                JobStatus::Running => (String::from("running"), Color::Yellow),
+                JobStatus::Waiting => (String::from("waiting"), Color::LightBlue),
+                JobStatus::Done => (String::from("done"), Color::Green),
+                JobStatus::Failed(reason) => (format!("failed: {}", reason), Color::Red),
+            };
+            Text::styled(
+                format!("{} {}/{}: {} ({})", job.rule.item, job.have, job.rule.count, action, status),
+                Style::default().fg(color),
+            )
+        })).block(Block::bordered().border_set(CCTWEAKED_BORDER).title(common_name.clone()));
+        draw(&terminal, display).await?;
+    }
+}
+
+pub async fn write_fleet_to_terminal(terminal: Arc<Mutex<Terminal<CCTweakedMonitorBackend>>>, turtles: Arc<TurtleManager>, common_name: String, redraw_interval: Duration) -> std::io::Result<()> {
+    let mut timer = tokio::time::interval(redraw_interval);
+    loop {
+        timer.tick().await;
+        let fleet = turtles.get_fleet().await;
+        let display = List::new(fleet.iter().map(|turtle| {
+            let name = turtle.label.clone().unwrap_or_else(|| format!("#{}", turtle.computer_id));
+            let color = if turtle.connected { Color::White } else { Color::Gray };
+            let Some(telemetry) = &turtle.telemetry else {
+                return Text::styled(format!("{}: no telemetry", name), Style::default().fg(color));
+            };
+            let fuel = telemetry.fuel.map(|f| f.to_string()).unwrap_or_else(|| String::from("inf"));
+            let p = telemetry.position;
+            Text::styled(
+                format!("{} {},{},{} {:?} fuel {} {:?}", name, p.x, p.y, p.z, telemetry.facing, fuel, telemetry.state),
+                Style::default().fg(color),
+            )
+        })).block(Block::bordered().border_set(CCTWEAKED_BORDER).title(common_name.clone()));
+        draw(&terminal, display).await?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_age() {
+        assert_eq!(format_age(Duration::from_secs(42)), "42s ago");
+        assert_eq!(format_age(Duration::from_secs(3 * 60 + 20)), "3m ago");
+        assert_eq!(format_age(Duration::from_secs(2 * 3600)), "2h ago");
+    }
+}
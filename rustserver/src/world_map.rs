@@ -0,0 +1,343 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::fmt::Display;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::RwLock;
+use crate::turtle_manager::BlockPosition;
+
+/// Stops a search from wandering off forever when there is no way to the goal
+pub const MAX_PATH_NODES: usize = 200_000;
+/// Extra cost of going through a block that has to be dug, so paths prefer open space
+const DIG_COST: u32 = 4;
+/// Blocks no turtle can dig, paths go around them whatever the policy
+const UNBREAKABLE: [&str; 1] = ["minecraft:bedrock"];
+
+/// What a turtle saw when it inspected a block, `block` is None for air
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockObservation {
+    pub position: BlockPosition,
+    #[serde(default)]
+    pub block: Option<String>,
+}
+
+/// What pathfinding may do about solid blocks in the way
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DigPolicy {
+    /// only go through air and blocks nobody has seen yet
+    #[serde(rename = "avoid")]
+    Avoid,
+    /// dig through anything
+    #[default]
+    #[serde(rename = "dig")]
+    Dig,
+    /// dig through anything except the listed blocks
+    #[serde(rename = "dig_except")]
+    DigExcept(Vec<String>),
+}
+
+impl DigPolicy {
+    fn may_dig(&self, block: &str) -> bool {
+        match self {
+            DigPolicy::Avoid => false,
+            DigPolicy::Dig => true,
+            DigPolicy::DigExcept(protected) => !protected.iter().any(|p| p == block),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum WorldMapError {
+    Io(#[from] std::io::Error),
+    Parse(#[from] serde_json::Error),
+}
+
+impl Display for WorldMapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WorldMapError::Io(e) => write!(f, "Failed to access world map file: {}", e),
+            WorldMapError::Parse(e) => write!(f, "Failed to parse world map: {}", e),
+        }
+    }
+}
+
+/// Sparse store of every block turtles have seen. Anything not in it is unknown.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Voxels {
+    blocks: HashMap<BlockPosition, Option<String>>,
+}
+
+impl Voxels {
+    pub fn observe(&mut self, observation: BlockObservation) {
+        self.blocks.insert(observation.position, observation.block);
+    }
+
+    /// None if the block was never seen, Some(None) if it is air
+    pub fn get(&self, position: &BlockPosition) -> Option<&Option<String>> {
+        self.blocks.get(position)
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Cost of moving into the block, None if the policy doesn't allow it
+    fn cost(&self, position: &BlockPosition, policy: &DigPolicy) -> Option<u32> {
+        match self.get(position) {
+            None | Some(None) => Some(1),
+            Some(Some(block)) if UNBREAKABLE.contains(&block.as_str()) => None,
+            Some(Some(block)) if policy.may_dig(block) => Some(1 + DIG_COST),
+            Some(Some(_)) => None,
+        }
+    }
+
+    /// A* over the 6 neighbours of each block. Returns every position from `from` to `to`
+    /// inclusive, or None if there is no way there.
+    pub fn find_path(&self, from: BlockPosition, to: BlockPosition, policy: &DigPolicy) -> Option<Vec<BlockPosition>> {
+        let heuristic = |p: &BlockPosition| (p.x.abs_diff(to.x) + p.y.abs_diff(to.y) + p.z.abs_diff(to.z)) as u32;
+        // the goal has to be reachable itself
+        self.cost(&to, policy)?;
+        let mut open = BinaryHeap::new();
+        let mut came_from: HashMap<BlockPosition, BlockPosition> = HashMap::new();
+        let mut best_cost: HashMap<BlockPosition, u32> = HashMap::new();
+        best_cost.insert(from, 0);
+        // ties are broken on the cost so far, preferring nodes closer to the goal
+        open.push(Reverse((heuristic(&from), Reverse(0u32), Position3(from))));
+
+        while let Some(Reverse((_, Reverse(cost), Position3(current)))) = open.pop() {
+            if current == to {
+                let mut path = vec![current];
+                let mut node = current;
+                while let Some(previous) = came_from.get(&node) {
+                    path.push(*previous);
+                    node = *previous;
+                }
+                path.reverse();
+                return Some(path);
+            }
+            if best_cost.get(&current).is_some_and(|best| *best < cost) {
+                continue; // already found a cheaper way here
+            }
+            if best_cost.len() > MAX_PATH_NODES {
+                return None;
+            }
+            for (dx, dy, dz) in [(1, 0, 0), (-1, 0, 0), (0, 1, 0), (0, -1, 0), (0, 0, 1), (0, 0, -1)] {
+                let next = BlockPosition { x: current.x + dx, y: current.y + dy, z: current.z + dz };
+                let Some(step) = self.cost(&next, policy) else {
+                    continue;
+                };
+                let next_cost = cost + step;
+                if best_cost.get(&next).is_some_and(|best| *best <= next_cost) {
+                    continue;
+                }
+                best_cost.insert(next, next_cost);
+                came_from.insert(next, current);
+                open.push(Reverse((next_cost + heuristic(&next), Reverse(next_cost), Position3(next))));
+            }
+        }
+        None
+    }
+}
+
+/// BlockPosition ordered by coordinates so it can live in the heap
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Position3(BlockPosition);
+
+impl Ord for Position3 {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (self.0.x, self.0.y, self.0.z).cmp(&(other.0.x, other.0.y, other.0.z))
+    }
+}
+
+impl PartialOrd for Position3 {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// What [WorldMap::save] writes out
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum SavedWorldMap {
+    Map {
+        policy: DigPolicy,
+        blocks: Vec<BlockObservation>,
+    },
+    /// maps saved before the policy was, which only had the blocks
+    Blocks(Vec<BlockObservation>),
+}
+
+/// WorldMap is the shared, persisted [Voxels] store along with the default dig policy
+#[derive(Default)]
+pub struct WorldMap {
+    // shared with path searches, which hold a read lock while they run
+    voxels: Arc<RwLock<Voxels>>,
+    policy: RwLock<DigPolicy>,
+    /// set when blocks were observed or the policy changed since the last save
+    dirty: AtomicBool,
+}
+
+impl WorldMap {
+    /// Loads the map saved by [WorldMap::save], or an empty map if there is no file yet
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, WorldMapError> {
+        let data = match std::fs::read_to_string(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e.into()),
+        };
+        let (policy, observations) = match serde_json::from_str(&data)? {
+            SavedWorldMap::Map { policy, blocks } => (policy, blocks),
+            SavedWorldMap::Blocks(blocks) => (DigPolicy::default(), blocks),
+        };
+        let mut voxels = Voxels::default();
+        for observation in observations {
+            voxels.observe(observation);
+        }
+        Ok(WorldMap {
+            voxels: Arc::new(RwLock::new(voxels)),
+            policy: RwLock::new(policy),
+            ..Default::default()
+        })
+    }
+
+    /// Writes the map out if anything changed since the last save
+    pub async fn save(&self, path: impl AsRef<Path>) -> Result<(), WorldMapError> {
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+        let blocks = self.voxels.read().await.blocks.iter()
+            .map(|(position, block)| BlockObservation { position: *position, block: block.clone() })
+            .collect();
+        let data = serde_json::to_string(&SavedWorldMap::Map { policy: self.get_policy().await, blocks })?;
+        // write then rename so a crash mid-save doesn't lose the whole map
+        let path = path.as_ref();
+        let temp = path.with_extension("tmp");
+        let result = async {
+            tokio::fs::write(&temp, data).await?;
+            tokio::fs::rename(&temp, path).await
+        }.await;
+        if result.is_err() {
+            // try again next time
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+        Ok(result?)
+    }
+
+    pub async fn observe(&self, observations: Vec<BlockObservation>) {
+        let mut voxels = self.voxels.write().await;
+        for observation in observations {
+            voxels.observe(observation);
+        }
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    pub async fn len(&self) -> usize {
+        self.voxels.read().await.len()
+    }
+
+    pub async fn get_policy(&self) -> DigPolicy {
+        self.policy.read().await.clone()
+    }
+
+    /// Sets the default policy, which is saved along with the map
+    pub async fn set_policy(&self, policy: DigPolicy) {
+        *self.policy.write().await = policy;
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    /// Plans a path with the given policy, or the map's default one
+    pub async fn find_path(&self, from: BlockPosition, to: BlockPosition, policy: Option<DigPolicy>) -> Option<Vec<BlockPosition>> {
+        let policy = match policy {
+            Some(policy) => policy,
+            None => self.get_policy().await,
+        };
+        let voxels = self.voxels.clone().read_owned().await;
+        // searching can take a while, don't hold up the runtime
+        tokio::task::spawn_blocking(move || voxels.find_path(from, to, &policy)).await.ok().flatten()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pos(x: i64, y: i64, z: i64) -> BlockPosition {
+        BlockPosition { x, y, z }
+    }
+
+    /// a wall of stone at x = 1 spanning y 0..=4 and z -2..=2, framed by bedrock and with
+    /// bedrock at (1, 1, 0)
+    fn walled() -> Voxels {
+        let mut voxels = Voxels::default();
+        for y in -1..=5 {
+            for z in -3i64..=3 {
+                let block = if y == -1 || y == 5 || z.abs() == 3 || (y, z) == (1, 0) {
+                    "minecraft:bedrock"
+                } else {
+                    "minecraft:stone"
+                };
+                voxels.observe(BlockObservation { position: pos(1, y, z), block: Some(block.to_string()) });
+            }
+        }
+        voxels
+    }
+
+    #[test]
+    fn test_find_path_open_space() {
+        let path = Voxels::default().find_path(pos(0, 0, 0), pos(3, 1, 0), &DigPolicy::Avoid).unwrap();
+        assert_eq!(path.len(), 5);
+        assert_eq!(path.first(), Some(&pos(0, 0, 0)));
+        assert_eq!(path.last(), Some(&pos(3, 1, 0)));
+    }
+
+    #[test]
+    fn test_find_path_policies() {
+        let voxels = walled();
+        // avoiding means going around the wall, underneath is the shortest way
+        let path = voxels.find_path(pos(0, 1, 0), pos(2, 1, 0), &DigPolicy::Avoid).unwrap();
+        assert_eq!(path.len(), 9);
+        assert!(path.iter().all(|p| voxels.get(p).is_none()));
+
+        // with stone protected too nothing in the wall may be dug, so the path goes around it
+        let protected = DigPolicy::DigExcept(vec!["minecraft:stone".to_string()]);
+        let path = voxels.find_path(pos(0, 1, 0), pos(2, 1, 0), &protected).unwrap();
+        assert_eq!(path.len(), 9);
+        assert!(path.iter().all(|p| voxels.get(p).is_none()));
+
+        // bedrock can't be dug whatever the policy says
+        let path = voxels.find_path(pos(0, 1, 0), pos(2, 1, 0), &DigPolicy::Dig).unwrap();
+        assert!(!path.contains(&pos(1, 1, 0)));
+        assert_eq!(path.len(), 5);
+        assert_eq!(voxels.find_path(pos(0, 1, 0), pos(1, 1, 0), &DigPolicy::Dig), None);
+
+        // the goal itself is protected
+        assert!(voxels.find_path(pos(0, 1, 0), pos(1, 2, 0), &DigPolicy::Dig).is_some());
+        assert_eq!(voxels.find_path(pos(0, 1, 0), pos(1, 2, 0), &protected), None);
+    }
+
+    #[tokio::test]
+    async fn test_save_and_load() {
+        let path = std::env::temp_dir().join(format!("world_map_test_{}.json", std::process::id()));
+        let map = WorldMap::default();
+        map.observe(vec![
+            BlockObservation { position: pos(1, 2, 3), block: Some("minecraft:stone".to_string()) },
+            BlockObservation { position: pos(1, 3, 3), block: None },
+        ]).await;
+        map.set_policy(DigPolicy::Avoid).await;
+        map.save(&path).await.unwrap();
+        let loaded = WorldMap::load(&path).unwrap();
+        assert_eq!(*loaded.voxels.read().await, *map.voxels.read().await);
+        assert_eq!(loaded.len().await, 2);
+        assert_eq!(loaded.get_policy().await, DigPolicy::Avoid);
+
+        // maps from before the policy was saved only have the blocks
+        std::fs::write(&path, r#"[{"position":{"x":1,"y":2,"z":3},"block":"minecraft:stone"}]"#).unwrap();
+        let loaded = WorldMap::load(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+        assert_eq!(loaded.len().await, 1);
+        assert_eq!(loaded.get_policy().await, DigPolicy::Dig);
+    }
+}